@@ -0,0 +1,180 @@
+// A Motorola 68B50 ACIA in front of a serial line like the console's, as on the RC2014's serial
+//     board: a control/status register and a data register.
+//
+// Characters move at the line's pace, so the clock divider and word select are kept but not acted
+// on, and framing, parity and overrun errors never happen. CTS is always on; DCD follows the
+// line's carrier. The core gives devices no way to interrupt the CPU, so IRQ only shows in the
+// status register, for guests that poll.
+
+use super::ConcurrentDevice;
+use stdio_dev::StdioDevice;
+
+use z80e_core_rust::IoDevice;
+
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::Duration;
+
+// How often the line is looked at for changes the guest should hear about.
+const POLL_MILLIS: u64 = 1;
+
+// Control register.
+const DIVIDE: u8 = 0x03;
+const MASTER_RESET: u8 = 0x03;
+const TX_CONTROL: u8 = 0x60;
+const TX_INT_ENABLE: u8 = 0x20;
+const RX_INT_ENABLE: u8 = 1 << 7;
+// Status register.
+const RX_FULL: u8 = 1 << 0;
+const TX_EMPTY: u8 = 1 << 1;
+// Set while there's no carrier.
+const DCD: u8 = 1 << 2;
+const IRQ: u8 = 1 << 7;
+
+struct State {
+    control: u8,
+    line: StdioDevice,
+    // Read again when there's nothing new.
+    last: u8,
+    // Written while the line was busy.
+    held: Option<u8>,
+    // Carrier went away; reading the status and then the data clears it.
+    carrier_lost: bool,
+    lost_seen: bool,
+    had_carrier: bool,
+}
+
+impl State {
+    fn reset(&mut self) {
+        self.control = MASTER_RESET;
+        self.held = None;
+        self.carrier_lost = false;
+        self.lost_seen = false;
+        self.had_carrier = self.line.carrier_detected();
+    }
+    fn in_reset(&self) -> bool {
+        self.control & DIVIDE == MASTER_RESET
+    }
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.in_reset() {
+            return status;
+        }
+        if self.line.ready_read() { status |= RX_FULL; }
+        if self.held.is_none() && self.line.ready_write() { status |= TX_EMPTY; }
+        if self.carrier_lost || !self.line.carrier_detected() { status |= DCD; }
+        if self.requesting(status) { status |= IRQ; }
+        status
+    }
+    fn requesting(&self, status: u8) -> bool {
+        let rx = self.control & RX_INT_ENABLE != 0 && (status & RX_FULL != 0 || self.carrier_lost);
+        let tx = self.control & TX_CONTROL == TX_INT_ENABLE && status & TX_EMPTY != 0;
+        rx || tx
+    }
+    // Moves a held character along and notices the carrier going.
+    fn poll(&mut self) {
+        if let Some(byte) = self.held {
+            if self.line.ready_write() {
+                self.held = None;
+                self.line.get_data_port().write_out(byte);
+            }
+        }
+        let carrier = self.line.carrier_detected();
+        if self.had_carrier && !carrier && !self.in_reset() {
+            self.carrier_lost = true;
+            self.lost_seen = false;
+        }
+        self.had_carrier = carrier;
+    }
+}
+
+#[derive(Clone)]
+pub struct Acia {
+    state: Arc<Mutex<State>>,
+}
+
+impl Acia {
+    pub fn new(line: StdioDevice) -> Acia {
+        let mut state = State {
+            control: 0,
+            line: line,
+            last: 0,
+            held: None,
+            carrier_lost: false,
+            lost_seen: false,
+            had_carrier: false,
+        };
+        state.reset();
+        Acia {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+    pub fn control_port(&self) -> AciaControl {
+        AciaControl { acia: self.clone() }
+    }
+    pub fn data_port(&self) -> AciaData {
+        AciaData { acia: self.clone() }
+    }
+}
+
+impl ConcurrentDevice for Acia {
+    fn run(&mut self, die: Arc<AtomicBool>, _timeout: Duration) {
+        while !die.load(Ordering::Acquire) {
+            self.state.lock().unwrap().poll();
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+    }
+}
+
+// Status when read, control when written.
+pub struct AciaControl {
+    acia: Acia,
+}
+
+impl IoDevice for AciaControl {
+    fn read_in(&self) -> u8 {
+        let mut state = self.acia.state.lock().unwrap();
+        if state.carrier_lost {
+            state.lost_seen = true;
+        }
+        state.status()
+    }
+    fn write_out(&mut self, value: u8) {
+        let mut state = self.acia.state.lock().unwrap();
+        if value & DIVIDE == MASTER_RESET {
+            state.reset();
+        } else {
+            state.control = value;
+        }
+    }
+}
+
+pub struct AciaData {
+    acia: Acia,
+}
+
+impl IoDevice for AciaData {
+    fn read_in(&self) -> u8 {
+        let mut state = self.acia.state.lock().unwrap();
+        if state.lost_seen {
+            state.carrier_lost = false;
+            state.lost_seen = false;
+        }
+        if !state.in_reset() && state.line.ready_read() {
+            state.last = state.line.get_data_port().read_in();
+        }
+        state.last
+    }
+    fn write_out(&mut self, value: u8) {
+        let mut state = self.acia.state.lock().unwrap();
+        if state.in_reset() {
+            return;
+        }
+        if state.held.is_none() && state.line.ready_write() {
+            state.line.get_data_port().write_out(value);
+        } else {
+            state.held = Some(value);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+// Console sessions as asciicast v2 files, the format asciinema plays: a JSON header line, then
+//     one [seconds, "o" or "i", "text"] line per burst of output or input.
+//
+// Recording happens at the host end of the console, so output is what a host terminal would be
+// shown and input is what was typed. A replay types the recorded input again at the times it was
+// first typed.
+//
+// Bytes that aren't UTF-8 are written as \u0080 to \u00ff escapes, which are otherwise never used
+// since those characters go in as themselves, so a replay types exactly the bytes recorded.
+
+use console::{ self, ConsoleBackend, ConsoleInput };
+use screen;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader, ErrorKind, Write };
+use std::path::{ Path, PathBuf };
+use std::str::{ self, FromStr };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use std::{ char, cmp, thread };
+
+#[derive(Clone)]
+pub struct RecordConfig {
+    path: PathBuf,
+    // Record what was typed as well, so the session can be replayed.
+    input: bool,
+}
+
+impl RecordConfig {
+    pub fn describe(&self) -> String {
+        format!("{}{}", self.path.display(), if self.input { " (with input)" } else { "" })
+    }
+}
+
+impl FromStr for RecordConfig {
+    type Err = String;
+    // path[,input]
+    fn from_str(s: &str) -> Result<RecordConfig, String> {
+        let mut fields = s.splitn(2, ',');
+        let path = match fields.next() {
+            Some(x) if !x.is_empty() => PathBuf::from(x),
+            _ => return Err(format!("Expected path[,input]: {}", s)),
+        };
+        let input = match fields.next() {
+            None => false,
+            Some("input") => true,
+            Some(x) => return Err(format!("Expected input: {}", x)),
+        };
+        Ok(RecordConfig {
+            path: path,
+            input: input,
+        })
+    }
+}
+
+// Puts bytes in a JSON string.
+fn quote(bytes: &[u8], out: &mut String) {
+    out.push('"');
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (valid, invalid) = match str::from_utf8(rest) {
+            Ok(_) => (rest.len(), 0),
+            Err(err) => (err.valid_up_to(), err.error_len().unwrap_or(rest.len() - err.valid_up_to())),
+        };
+        for c in str::from_utf8(&rest[..valid]).unwrap().chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                '\u{0}'..='\u{1f}' | '\u{7f}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+                _ => out.push(c),
+            }
+        }
+        for &byte in &rest[valid..valid + invalid] {
+            out.push_str(&format!("\\u{:04x}", byte));
+        }
+        rest = &rest[valid + invalid..];
+    }
+    out.push('"');
+}
+
+// Reads a JSON string off the front of s, returning its bytes and what follows.
+fn unquote(s: &str) -> Result<(Vec<u8>, &str), String> {
+    if !s.starts_with('"') {
+        return Err(format!("Expected a string: {}", s));
+    }
+    let mut text = Vec::new();
+    let push = |text: &mut Vec<u8>, c: char| {
+        let mut utf8 = [0; 4];
+        text.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+    };
+    let mut chars = s[1..].char_indices();
+    let hex = |chars: &mut str::CharIndices| -> Result<u32, String> {
+        let digits: String = chars.take(4).map(|(_, c)| c).collect();
+        u32::from_str_radix(&digits, 16).map_err(|err| format!("\\u{} ← {}", digits, err))
+    };
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, &s[i + 2..])),
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some((_, x)) => x,
+                    None => break,
+                };
+                match escaped {
+                    'n' => text.push(b'\n'),
+                    'r' => text.push(b'\r'),
+                    't' => text.push(b'\t'),
+                    'b' => text.push(0x08),
+                    'f' => text.push(0x0c),
+                    'u' => {
+                        let mut code = try!(hex(&mut chars));
+                        // Outside the BMP comes as a surrogate pair.
+                        if (0xd800..0xdc00).contains(&code) && chars.as_str().starts_with("\\u") {
+                            chars.next();
+                            chars.next();
+                            let low = try!(hex(&mut chars));
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                        }
+                        // A byte that wasn't UTF-8.
+                        if (0x80..0x100).contains(&code) {
+                            text.push(code as u8);
+                        } else {
+                            push(&mut text, char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                    },
+                    x => push(&mut text, x),
+                }
+            },
+            x => push(&mut text, x),
+        }
+    }
+    Err(format!("Unterminated string: {}", s))
+}
+
+// [seconds, "kind", "text"]
+fn parse_event(line: &str) -> Result<(f64, Vec<u8>, Vec<u8>), String> {
+    let line = line.trim();
+    if !line.starts_with('[') || !line.ends_with(']') {
+        return Err(format!("Expected [time, kind, text]: {}", line));
+    }
+    let inner = &line[1..line.len() - 1];
+    let comma = match inner.find(',') {
+        Some(i) => i,
+        None => return Err(format!("Expected [time, kind, text]: {}", line)),
+    };
+    let time = try!(f64::from_str(inner[..comma].trim()).map_err(|err| format!("{} ← {}", &inner[..comma], err)));
+    let (kind, rest) = try!(unquote(inner[comma + 1..].trim_start()));
+    let rest = rest.trim_start();
+    if !rest.starts_with(',') {
+        return Err(format!("Expected [time, kind, text]: {}", line));
+    }
+    let (text, _) = try!(unquote(rest[1..].trim_start()));
+    Ok((time, kind, text))
+}
+
+// Takes bytes out to record, leaving an unfinished UTF-8 sequence at the end for next time.
+fn take_text(pending: &mut Vec<u8>) -> Vec<u8> {
+    let keep = match str::from_utf8(pending) {
+        Err(ref err) if err.error_len().is_none() => pending.len() - err.valid_up_to(),
+        _ => 0,
+    };
+    let split = pending.len() - keep;
+    pending.drain(..split).collect()
+}
+
+struct Recording {
+    file: Mutex<File>,
+    start: Instant,
+    // So a full disk is only complained about once.
+    failed: AtomicBool,
+}
+
+impl Recording {
+    fn event(&self, kind: &str, text: &[u8]) {
+        if text.is_empty() { return; }
+        let elapsed = self.start.elapsed();
+        let mut line = format!("[{}.{:06}, ", elapsed.as_secs(), elapsed.subsec_micros());
+        quote(kind.as_bytes(), &mut line);
+        line.push_str(", ");
+        quote(text, &mut line);
+        line.push_str("]\n");
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                let _ = writeln!(io::stderr(), "record: {}", err);
+            }
+        }
+    }
+}
+
+// Starts a recording and wraps the host end of the console so it feeds it.
+pub fn record(config: &RecordConfig, input: Box<dyn ConsoleInput>, output: Box<dyn Write + Send>) -> io::Result<(Box<dyn ConsoleInput>, Box<dyn Write + Send>)> {
+    let mut file = try!(File::create(&config.path));
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let mut header = format!("{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}", screen::COLUMNS, screen::ROWS, timestamp);
+    if let Ok(term) = env::var("TERM") {
+        header.push_str(", \"env\": {\"TERM\": ");
+        quote(term.as_bytes(), &mut header);
+        header.push('}');
+    }
+    header.push_str("}\n");
+    try!(file.write_all(header.as_bytes()));
+    let recording = Arc::new(Recording {
+        file: Mutex::new(file),
+        start: Instant::now(),
+        failed: AtomicBool::new(false),
+    });
+    let input: Box<dyn ConsoleInput> = if config.input {
+        Box::new(RecordInput {
+            inner: input,
+            recording: recording.clone(),
+            pending: Vec::new(),
+        })
+    } else {
+        input
+    };
+    let output = Box::new(RecordOutput {
+        inner: output,
+        recording: recording,
+        pending: Vec::new(),
+    });
+    Ok((input, output))
+}
+
+struct RecordInput {
+    inner: Box<dyn ConsoleInput>,
+    recording: Arc<Recording>,
+    pending: Vec<u8>,
+}
+
+impl ConsoleInput for RecordInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let count = try!(self.inner.read(buf, timeout));
+        self.pending.extend_from_slice(&buf[..count]);
+        self.recording.event("i", &take_text(&mut self.pending));
+        Ok(count)
+    }
+}
+
+struct RecordOutput {
+    inner: Box<dyn Write + Send>,
+    recording: Arc<Recording>,
+    pending: Vec<u8>,
+}
+
+impl Write for RecordOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        self.recording.event("o", &take_text(&mut self.pending));
+        try!(self.inner.write_all(buf));
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Types a recording's input again, showing the guest's output on stdout.
+pub struct ReplayBackend {
+    path: PathBuf,
+    events: Vec<(Duration, Vec<u8>)>,
+}
+
+impl ReplayBackend {
+    pub fn load(path: &Path) -> io::Result<ReplayBackend> {
+        let file = BufReader::new(try!(File::open(path)));
+        let mut events = Vec::new();
+        for (number, line) in file.lines().enumerate() {
+            let line = try!(line);
+            // The header, and blank lines.
+            if number == 0 || line.trim().is_empty() { continue; }
+            let (time, kind, text) = match parse_event(&line) {
+                Ok(x) => x,
+                Err(err) => {
+                    let message = format!("{}:{}: {}", path.display(), number + 1, err);
+                    return Err(io::Error::new(ErrorKind::InvalidData, message));
+                },
+            };
+            if kind == b"i" && time >= 0.0 && !text.is_empty() {
+                let micros = (time * 1e6) as u64;
+                events.push((Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000), text));
+            }
+        }
+        if events.is_empty() {
+            let _ = writeln!(io::stderr(), "replay: {} has no recorded input.", path.display());
+        }
+        Ok(ReplayBackend {
+            path: path.to_path_buf(),
+            events: events,
+        })
+    }
+}
+
+impl ConsoleBackend for ReplayBackend {
+    fn describe(&self) -> Option<String> {
+        Some(format!("replaying {} ({} inputs)", self.path.display(), self.events.len()))
+    }
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let input = ReplayInput {
+            events: self.events.into_iter().collect(),
+            start: Instant::now(),
+        };
+        (Box::new(input), Box::new(io::stdout()))
+    }
+}
+
+struct ReplayInput {
+    events: VecDeque<(Duration, Vec<u8>)>,
+    start: Instant,
+}
+
+impl ConsoleInput for ReplayInput {
+    // Input ends with the recording.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let due = match self.events.front() {
+            Some(&(time, _)) => self.start + time,
+            None => return Ok(0),
+        };
+        let now = Instant::now();
+        if due > now {
+            if due - now > timeout {
+                thread::sleep(timeout);
+                return Err(console::would_block());
+            }
+            thread::sleep(due - now);
+        }
+        let bytes = &mut self.events.front_mut().unwrap().1;
+        let count = cmp::min(buf.len(), bytes.len());
+        buf[..count].copy_from_slice(&bytes[..count]);
+        bytes.drain(..count);
+        if bytes.is_empty() {
+            self.events.pop_front();
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Vec<u8> {
+        let mut line = String::from("[1.5, \"i\", ");
+        quote(bytes, &mut line);
+        line.push(']');
+        let (time, kind, text) = parse_event(&line).unwrap();
+        assert!(time == 1.5);
+        assert_eq!(kind, b"i");
+        text
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let text = "A\"\\\r\n\t\u{1b}[H\u{7f} caf\u{e9} \u{20ac} \u{1f600}".as_bytes();
+        assert_eq!(round_trip(text), text);
+    }
+
+    #[test]
+    fn bytes_that_arent_utf8_round_trip() {
+        let mut quoted = String::new();
+        quote(&[b'a', 0xe9, b'b', 0x80, 0xff], &mut quoted);
+        assert_eq!(quoted, "\"a\\u00e9b\\u0080\\u00ff\"");
+        for bytes in [&[0xe9, b'b'][..], &[0xc1, 0x81, 0x8d], &[0xe2, 0x82], &[0xf0, b'x', 0xc3, 0xa9]].iter() {
+            assert_eq!(round_trip(bytes), *bytes);
+        }
+    }
+
+    #[test]
+    fn unfinished_sequences_wait_for_the_rest() {
+        let euro = "\u{20ac}".as_bytes();
+        let mut pending = vec![b'a', euro[0], euro[1]];
+        assert_eq!(take_text(&mut pending), b"a");
+        pending.push(euro[2]);
+        assert_eq!(take_text(&mut pending), euro);
+        assert!(pending.is_empty());
+        // Not the start of something longer, so it goes now.
+        let mut pending = vec![b'a', 0xff];
+        assert_eq!(take_text(&mut pending), &[b'a', 0xff]);
+    }
+}
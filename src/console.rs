@@ -0,0 +1,641 @@
+use asciicast::ReplayBackend;
+use host::{ self, Host };
+use modem::ModemBackend;
+use pty::PtyBackend;
+use script::ScriptBackend;
+use telnet::{ TelnetDecoder, TelnetEncoder };
+use tty;
+
+use libc;
+
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::AtomicBool;
+use std::time::{ Duration, Instant };
+use std::io::{ self, ErrorKind, Read, Write };
+use std::fs::{ File, OpenOptions };
+use std::net::{ Shutdown, TcpListener, TcpStream };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::os::unix::fs::FileTypeExt;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::{ cmp, fs, thread };
+
+const ACCEPT_POLL_MILLIS: u64 = 100;
+const BUF_LENGTH: usize = 0x100;
+// Guest output held back while the host menu is open; past this, the rest is dropped.
+const HELD_OUTPUT_LIMIT: usize = 0x10000;
+
+// The host end of a console's input.
+pub trait ConsoleInput: Send {
+    // Reads whatever input is available, waiting up to timeout where the transport allows.
+    //     Ok(0) means the input has ended; nothing arriving in time is ErrorKind::WouldBlock.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+}
+
+// A host transport for a console, split into halves driven by separate threads.
+pub trait ConsoleBackend: Send {
+    // Where an operator should look for the console, if it isn't obvious.
+    fn describe(&self) -> Option<String> {
+        None
+    }
+    // Set while there's someone at the other end, for transports that can tell.
+    fn carrier(&self) -> Option<Arc<AtomicBool>> {
+        None
+    }
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>);
+}
+
+pub fn would_block() -> io::Error {
+    io::Error::new(ErrorKind::WouldBlock, "No console input.")
+}
+
+// Binds a Unix socket, replacing a stale one nobody is listening on any more.
+pub fn bind_unix<T: AsRef<Path>>(path: &T) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    match UnixListener::bind(path) {
+        Err(ref err) if err.kind() == ErrorKind::AddrInUse => {
+            let is_socket = fs::symlink_metadata(path).map(|x| x.file_type().is_socket()).unwrap_or(false);
+            if !is_socket || UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse, format!("{} is in use.", path.display())));
+            }
+            try!(fs::remove_file(path));
+            UnixListener::bind(path)
+        },
+        result => result,
+    }
+}
+
+pub enum BackendSpec {
+    Stdio,
+    Telnet(String),
+    Tcp(String),
+    Unix(PathBuf),
+    File(Option<PathBuf>, Option<PathBuf>),
+    Pty(Option<PathBuf>),
+    Script(PathBuf),
+    Replay(PathBuf),
+    Modem(Option<String>),
+}
+
+// Bare ports are bound on the loopback interface only.
+fn socket_address(s: &str) -> Result<String, String> {
+    if s.contains(':') {
+        Ok(s.to_string())
+    } else {
+        match u16::from_str(s) {
+            Ok(port) => Ok(format!("127.0.0.1:{}", port)),
+            Err(err) => Err(format!("{} ← {}", s, err)),
+        }
+    }
+}
+
+impl FromStr for BackendSpec {
+    type Err = String;
+    // stdio, telnet:[address:]port, tcp:[address:]port, unix:path, file:[input][,output], pty[:link], script:path, replay:path or modem[:[address:]port]
+    fn from_str(s: &str) -> Result<BackendSpec, String> {
+        let (kind, rest) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        match kind {
+            "stdio" => Ok(BackendSpec::Stdio),
+            "telnet" => Ok(BackendSpec::Telnet(try!(socket_address(rest)))),
+            "tcp" => Ok(BackendSpec::Tcp(try!(socket_address(rest)))),
+            "unix" if !rest.is_empty() => Ok(BackendSpec::Unix(PathBuf::from(rest))),
+            "file" => {
+                let mut files = rest.splitn(2, ',').map(|name| {
+                    if name.is_empty() { None } else { Some(PathBuf::from(name)) }
+                });
+                Ok(BackendSpec::File(files.next().unwrap_or(None), files.next().unwrap_or(None)))
+            },
+            "pty" => Ok(BackendSpec::Pty(if rest.is_empty() { None } else { Some(PathBuf::from(rest)) })),
+            "script" if !rest.is_empty() => Ok(BackendSpec::Script(PathBuf::from(rest))),
+            "replay" if !rest.is_empty() => Ok(BackendSpec::Replay(PathBuf::from(rest))),
+            "modem" if rest.is_empty() => Ok(BackendSpec::Modem(None)),
+            "modem" => Ok(BackendSpec::Modem(Some(try!(socket_address(rest))))),
+            _ => Err(format!("Expected stdio, telnet:[address:]port, tcp:[address:]port, unix:path, file:[input][,output], pty[:link], script:path, replay:path or modem[:[address:]port]: {}", s)),
+        }
+    }
+}
+
+impl BackendSpec {
+    // The host menu is only offered on a local terminal.
+    pub fn open(&self, host: &Host) -> io::Result<Box<dyn ConsoleBackend>> {
+        Ok(match *self {
+            BackendSpec::Stdio => Box::new(StdioBackend { host: host.clone() }),
+            BackendSpec::Telnet(ref address) => {
+                let listener = try!(TcpListener::bind(&address[..]));
+                Box::new(try!(SocketBackend::listen(Box::new(listener), true, format!("telnet {}", address), None)))
+            },
+            BackendSpec::Tcp(ref address) => {
+                let listener = try!(TcpListener::bind(&address[..]));
+                Box::new(try!(SocketBackend::listen(Box::new(listener), false, format!("TCP {}", address), None)))
+            },
+            BackendSpec::Unix(ref path) => {
+                let listener = try!(bind_unix(path));
+                let description = format!("Unix socket {}", path.display());
+                Box::new(try!(SocketBackend::listen(Box::new(listener), false, description, Some(path.clone()))))
+            },
+            BackendSpec::File(ref input, ref output) => {
+                let input: Box<dyn Read + Send> = match *input {
+                    Some(ref path) => Box::new(try!(File::open(path))),
+                    None => Box::new(io::empty()),
+                };
+                let output: Box<dyn Write + Send> = match *output {
+                    Some(ref path) => Box::new(try!(OpenOptions::new().create(true).append(true).open(path))),
+                    None => Box::new(io::sink()),
+                };
+                Box::new(FileBackend {
+                    input: input,
+                    output: output,
+                })
+            },
+            BackendSpec::Pty(ref link) => Box::new(try!(PtyBackend::open(link.as_ref().map(|x| x.as_path())))),
+            BackendSpec::Script(ref path) => Box::new(try!(ScriptBackend::load(path, host))),
+            BackendSpec::Replay(ref path) => Box::new(try!(ReplayBackend::load(path))),
+            BackendSpec::Modem(ref address) => Box::new(try!(ModemBackend::open(address.as_ref().map(|x| &x[..])))),
+        })
+    }
+}
+
+pub struct StdioBackend {
+    host: Host,
+}
+
+impl ConsoleBackend for StdioBackend {
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let input = Box::new(StdinInput);
+        if !tty::is_tty(libc::STDIN_FILENO) {
+            return (input, Box::new(io::stdout()));
+        }
+        if let Err(err) = tty::enter_raw(libc::STDIN_FILENO) {
+            let _ = writeln!(io::stderr(), "console: Unable to enter raw mode: {}", err);
+        }
+        let held = Arc::new(Mutex::new(None));
+        let input = EscapeInput {
+            inner: input,
+            host: self.host,
+            held: held.clone(),
+        };
+        let output = PausableOutput {
+            inner: io::stdout(),
+            held: held,
+        };
+        (Box::new(input), Box::new(output))
+    }
+}
+
+// Watches for the escape key, handing the terminal to the host menu when it's pressed.
+struct EscapeInput {
+    inner: Box<dyn ConsoleInput>,
+    host: Host,
+    // Guest output that came while the menu was open, or None while it isn't.
+    held: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl ConsoleInput for EscapeInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let count = try!(self.inner.read(buf, timeout));
+        let escape = match buf[..count].iter().position(|byte| *byte == host::ESCAPE) {
+            Some(i) => i,
+            None => return Ok(count),
+        };
+        *self.held.lock().unwrap() = Some(Vec::new());
+        let result = self.host.menu(&mut *self.inner, &mut io::stderr(), &buf[escape + 1..count]);
+        {
+            // Still holding the lock, so later output can't get ahead of it.
+            let mut held = self.held.lock().unwrap();
+            if let Some(bytes) = held.take() {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&bytes);
+                let _ = stdout.flush();
+            }
+        }
+        try!(result);
+        if escape == 0 { Err(would_block()) } else { Ok(escape) }
+    }
+}
+
+// Holds guest output back while the host menu has the terminal, without keeping the guest waiting.
+struct PausableOutput {
+    inner: io::Stdout,
+    held: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl Write for PausableOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut held = self.held.lock().unwrap();
+        match *held {
+            Some(ref mut bytes) => {
+                let room = HELD_OUTPUT_LIMIT.saturating_sub(bytes.len());
+                bytes.extend_from_slice(&buf[..buf.len().min(room)]);
+                Ok(buf.len())
+            },
+            None => self.inner.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads the descriptor directly; io::Stdin buffers, which would hide input from poll().
+struct StdinInput;
+
+impl ConsoleInput for StdinInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if !try!(tty::wait_readable(libc::STDIN_FILENO, timeout)) {
+            return Err(would_block());
+        }
+        match unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted { Err(would_block()) } else { Err(err) }
+            },
+            count => Ok(count as usize),
+        }
+    }
+}
+
+pub struct FileBackend {
+    input: Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+}
+
+impl ConsoleBackend for FileBackend {
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let backend = *self;
+        (Box::new(ReadInput { input: backend.input }), backend.output)
+    }
+}
+
+// Files never keep us waiting, so the timeout doesn't matter.
+struct ReadInput {
+    input: Box<dyn Read + Send>,
+}
+
+impl ConsoleInput for ReadInput {
+    fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+// Input from a buffer and output to a shared one, so tests can drive a console without a host.
+#[cfg(test)]
+pub struct MemoryBackend {
+    input: Vec<u8>,
+    output: SharedOutput,
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    // Input ends once all of it has been read.
+    pub fn new(input: &[u8]) -> (MemoryBackend, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let backend = MemoryBackend {
+            input: input.to_vec(),
+            output: SharedOutput(output.clone()),
+        };
+        (backend, output)
+    }
+}
+
+#[cfg(test)]
+impl ConsoleBackend for MemoryBackend {
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let backend = *self;
+        (Box::new(ReadInput { input: Box::new(io::Cursor::new(backend.input)) }), Box::new(backend.output))
+    }
+}
+
+#[cfg(test)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// What the socket backends need from a connection.
+trait Stream: Read + Write + Send {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()>;
+    fn clone_stream(&self) -> io::Result<Box<dyn Stream>>;
+    fn close(&self);
+}
+
+impl Stream for TcpStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        try!(self.set_nonblocking(false));
+        try!(self.set_nodelay(true));
+        self.set_read_timeout(Some(timeout))
+    }
+    fn clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(try!(self.try_clone())))
+    }
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        try!(self.set_nonblocking(false));
+        self.set_read_timeout(Some(timeout))
+    }
+    fn clone_stream(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(try!(self.try_clone())))
+    }
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+trait Listener: Send {
+    fn set_nonblocking(&self) -> io::Result<()>;
+    // Never blocks; Ok(None) if nobody is waiting.
+    fn accept_stream(&self) -> io::Result<Option<(Box<dyn Stream>, String)>>;
+}
+
+impl Listener for TcpListener {
+    fn set_nonblocking(&self) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, true)
+    }
+    fn accept_stream(&self) -> io::Result<Option<(Box<dyn Stream>, String)>> {
+        match self.accept() {
+            Ok((stream, peer)) => Ok(Some((Box::new(stream), peer.to_string()))),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Listener for UnixListener {
+    fn set_nonblocking(&self) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, true)
+    }
+    fn accept_stream(&self) -> io::Result<Option<(Box<dyn Stream>, String)>> {
+        match self.accept() {
+            Ok((stream, _)) => Ok(Some((Box::new(stream), "Unix socket".to_string()))),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Serves a single client at a time; the machine keeps running between clients.
+pub struct SocketBackend {
+    listener: Box<dyn Listener>,
+    telnet: bool,
+    description: String,
+    // Unix sockets leave a file behind.
+    path: Option<PathBuf>,
+}
+
+impl SocketBackend {
+    fn listen(listener: Box<dyn Listener>, telnet: bool, description: String, path: Option<PathBuf>) -> io::Result<SocketBackend> {
+        try!(listener.set_nonblocking());
+        Ok(SocketBackend {
+            listener: listener,
+            telnet: telnet,
+            description: description,
+            path: path,
+        })
+    }
+}
+
+impl ConsoleBackend for SocketBackend {
+    fn describe(&self) -> Option<String> {
+        Some(self.description.clone())
+    }
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let client = Arc::new(Mutex::new(None));
+        let input = SocketInput {
+            listener: self.listener,
+            path: self.path,
+            stream: None,
+            client: client.clone(),
+            decoder: if self.telnet { Some(TelnetDecoder::new()) } else { None },
+        };
+        let output = SocketOutput {
+            client: client,
+            encoder: if self.telnet { Some(TelnetEncoder::new()) } else { None },
+        };
+        (Box::new(input), Box::new(output))
+    }
+}
+
+struct SocketInput {
+    listener: Box<dyn Listener>,
+    path: Option<PathBuf>,
+    stream: Option<Box<dyn Stream>>,
+    client: Arc<Mutex<Option<Box<dyn Stream>>>>,
+    decoder: Option<TelnetDecoder>,
+}
+
+impl SocketInput {
+    fn accept(&mut self, timeout: Duration) -> io::Result<bool> {
+        let (mut stream, peer) = match try!(self.listener.accept_stream()) {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        try!(stream.set_timeout(timeout));
+        if let Some(ref mut decoder) = self.decoder {
+            *decoder = TelnetDecoder::new();
+            try!(stream.write_all(TelnetDecoder::negotiation()));
+        }
+        *self.client.lock().unwrap() = Some(try!(stream.clone_stream()));
+        self.stream = Some(stream);
+        let _ = writeln!(io::stderr(), "console: Client connected from {}.", peer);
+        Ok(true)
+    }
+    fn disconnect(&mut self) {
+        *self.client.lock().unwrap() = None;
+        if let Some(stream) = self.stream.take() {
+            stream.close();
+        }
+        let _ = writeln!(io::stderr(), "console: Client disconnected.");
+    }
+}
+
+impl Drop for SocketInput {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl ConsoleInput for SocketInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if self.stream.is_none() {
+            let deadline = Instant::now() + timeout;
+            while !try!(self.accept(timeout)) {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(would_block());
+                }
+                thread::sleep(cmp::min(deadline - now, Duration::from_millis(ACCEPT_POLL_MILLIS)));
+            }
+        }
+        let mut raw: [u8; BUF_LENGTH] = [0; BUF_LENGTH];
+        let length = cmp::min(buf.len(), BUF_LENGTH);
+        let result = self.stream.as_mut().unwrap().read(&mut raw[..length]);
+        let count = match result {
+            Ok(0) => {
+                self.disconnect();
+                return Err(would_block());
+            },
+            Ok(count) => count,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+                return Err(would_block());
+            },
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "console: Connection error: {}", err);
+                self.disconnect();
+                return Err(would_block());
+            },
+        };
+        match self.decoder {
+            Some(ref mut decoder) => {
+                let mut input = Vec::with_capacity(count);
+                let mut replies = Vec::new();
+                decoder.decode(&raw[..count], &mut input, &mut replies);
+                if !replies.is_empty() {
+                    let _ = self.stream.as_mut().unwrap().write_all(&replies);
+                }
+                if input.is_empty() {
+                    return Err(would_block());
+                }
+                buf[..input.len()].copy_from_slice(&input);
+                Ok(input.len())
+            },
+            None => {
+                buf[..count].copy_from_slice(&raw[..count]);
+                Ok(count)
+            },
+        }
+    }
+}
+
+// Discards output while nobody is connected.
+struct SocketOutput {
+    client: Arc<Mutex<Option<Box<dyn Stream>>>>,
+    encoder: Option<TelnetEncoder>,
+}
+
+impl Write for SocketOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut encoded = Vec::new();
+        let bytes = match self.encoder {
+            Some(ref mut encoder) => {
+                encoder.encode(buf, &mut encoded);
+                &encoded[..]
+            },
+            None => buf,
+        };
+        let mut client = self.client.lock().unwrap();
+        let failed = match *client {
+            Some(ref mut stream) => stream.write_all(bytes).is_err(),
+            None => false,
+        };
+        if failed {
+            // The input side notices the dead connection; stop writing to it meanwhile.
+            *client = None;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("metachronism-{}-{}", process::id(), name))
+    }
+
+    // Reads until count bytes have come, or the input ends or dries up.
+    fn read_some(input: &mut dyn ConsoleInput, count: usize) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut bytes = Vec::new();
+        let mut buf = [0; BUF_LENGTH];
+        while bytes.len() < count && Instant::now() < deadline {
+            match input.read(&mut buf, Duration::from_millis(10)) {
+                Ok(0) => break,
+                Ok(n) => bytes.extend_from_slice(&buf[..n]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => panic!("{}", err),
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_backend_specs() {
+        assert!(match "stdio".parse() { Ok(BackendSpec::Stdio) => true, _ => false });
+        assert!(match "telnet:2323".parse() { Ok(BackendSpec::Telnet(ref x)) => x == "127.0.0.1:2323", _ => false });
+        assert!(match "tcp:0.0.0.0:23".parse() { Ok(BackendSpec::Tcp(ref x)) => x == "0.0.0.0:23", _ => false });
+        assert!(match "unix:/tmp/console".parse() { Ok(BackendSpec::Unix(ref x)) => x == Path::new("/tmp/console"), _ => false });
+        assert!(match "file:in,out".parse() {
+            Ok(BackendSpec::File(Some(ref input), Some(ref output))) => input == Path::new("in") && output == Path::new("out"),
+            _ => false,
+        });
+        assert!(match "file:,out".parse() { Ok(BackendSpec::File(None, Some(_))) => true, _ => false });
+        assert!(match "file:".parse() { Ok(BackendSpec::File(None, None)) => true, _ => false });
+        for spec in ["", "unix:", "telnet:x", "tcp:", "serial:1"].iter() {
+            assert!(spec.parse::<BackendSpec>().is_err(), "Accepted {}", spec);
+        }
+    }
+
+    #[test]
+    fn file_input_ends_with_the_file() {
+        let path = temp_path("file-in");
+        fs::write(&path, b"typed").unwrap();
+        let (output, written) = MemoryBackend::new(b"");
+        let backend = Box::new(FileBackend {
+            input: Box::new(File::open(&path).unwrap()),
+            output: Box::new(output.output),
+        });
+        let (mut input, mut output) = backend.split();
+        assert_eq!(read_some(&mut *input, 10), b"typed");
+        let mut buf = [0; BUF_LENGTH];
+        assert_eq!(input.read(&mut buf, Duration::from_millis(10)).unwrap(), 0);
+        output.write_all(b"shown").unwrap();
+        assert_eq!(&written.lock().unwrap()[..], b"shown");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unix_socket_backend_serves_a_client() {
+        let path = temp_path("console.sock");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let backend = Box::new(SocketBackend::listen(Box::new(listener), false, "Unix socket console".to_string(), Some(path.clone())).unwrap());
+        let (mut input, mut output) = backend.split();
+        // Output with nobody there goes nowhere.
+        output.write_all(b"lost").unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"hello").unwrap();
+        assert_eq!(read_some(&mut *input, 5), b"hello");
+        output.write_all(b"there").unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"there");
+        // The socket file goes with the backend.
+        drop(input);
+        assert!(!path.exists());
+    }
+}
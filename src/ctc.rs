@@ -0,0 +1,356 @@
+// A Zilog Z80 CTC: four down counters at consecutive ports, each either a timer on the system
+//     clock or a counter of edges on its CLK/TRG input, reloading at zero.
+//
+// The system clock is the host's, scaled to the configured rate, so timers keep real time however
+// fast the CPU runs. The core gives devices no way to interrupt the CPU, so interrupt enables and
+// the vector are accepted and ignored; guests read the counters, or cascade them, instead.
+
+use super::ConcurrentDevice;
+
+use z80e_core_rust::IoDevice;
+
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+const POLL_MILLIS: u64 = 1;
+
+pub const CHANNELS: usize = 4;
+// Only the first three channels have a ZC/TO output.
+const ZERO_COUNT_OUTPUTS: usize = 3;
+pub const DEFAULT_CLOCK: u64 = 4_000_000;
+
+// Channel control word.
+const COUNTER_MODE: u8 = 1 << 6;
+const PRESCALE_256: u8 = 1 << 5;
+const TRIGGER_START: u8 = 1 << 3;
+const CONSTANT_FOLLOWS: u8 = 1 << 2;
+const RESET: u8 = 1 << 1;
+const CONTROL: u8 = 1 << 0;
+
+// What drives a channel's CLK/TRG input.
+#[derive(Clone, Copy)]
+pub enum Trigger {
+    None,
+    // A square wave at this many hertz.
+    Clock(u64),
+    // The ZC/TO output of an earlier channel, for cascading.
+    ZeroCount(usize),
+}
+
+#[derive(Clone, Copy)]
+pub struct CtcConfig {
+    pub base_port: u8,
+    pub clock: u64,
+    pub triggers: [Trigger; CHANNELS],
+}
+
+impl CtcConfig {
+    pub fn new(base_port: u8, clock: u64) -> CtcConfig {
+        CtcConfig {
+            base_port: base_port,
+            clock: clock,
+            triggers: [Trigger::None; CHANNELS],
+        }
+    }
+    pub fn describe(&self) -> String {
+        let mut description = format!("ports {}-{}, {} Hz", self.base_port, self.base_port + 3, self.clock);
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            match *trigger {
+                Trigger::None => (),
+                Trigger::Clock(hz) => description.push_str(&format!(", trigger {} at {} Hz", index, hz)),
+                Trigger::ZeroCount(source) => description.push_str(&format!(", trigger {} from channel {}", index, source)),
+            }
+        }
+        description
+    }
+}
+
+impl FromStr for CtcConfig {
+    type Err = String;
+    // base_port, then any of ,clock=hz ,trgN=hz ,trgN=zcM
+    fn from_str(s: &str) -> Result<CtcConfig, String> {
+        let mut fields = s.split(',');
+        let base = fields.next().unwrap_or("");
+        let mut config = match u8::from_str(base) {
+            Ok(x) if x <= 0xfc => CtcConfig::new(x, DEFAULT_CLOCK),
+            Ok(x) => return Err(format!("No room for four ports at {}", x)),
+            Err(err) => return Err(format!("{} ← {}", base, err)),
+        };
+        let hertz = |s: &str| -> Result<u64, String> {
+            match u64::from_str(s) {
+                Ok(x) if x > 0 => Ok(x),
+                Ok(_) => Err("A clock can't be 0 Hz".to_string()),
+                Err(err) => Err(format!("{} ← {}", s, err)),
+            }
+        };
+        for field in fields {
+            let (name, value) = match field.find('=') {
+                Some(i) => (&field[..i], &field[i + 1..]),
+                None => return Err(format!("Expected clock=hz or trgN=hz|zcM: {}", field)),
+            };
+            if name == "clock" {
+                config.clock = try!(hertz(value));
+                continue;
+            }
+            let index = match (name.starts_with("trg"), usize::from_str(&name[name.len().min(3)..])) {
+                (true, Ok(x)) if x < CHANNELS => x,
+                _ => return Err(format!("Expected clock or trg0 to trg3: {}", name)),
+            };
+            config.triggers[index] = if let Some(source) = value.strip_prefix("zc") {
+                match usize::from_str(source) {
+                    Ok(x) if x < index && x < ZERO_COUNT_OUTPUTS => Trigger::ZeroCount(x),
+                    Ok(x) => return Err(format!("Channel {} can't drive channel {}", x, index)),
+                    Err(err) => return Err(format!("{} ← {}", value, err)),
+                }
+            } else {
+                Trigger::Clock(try!(hertz(value)))
+            };
+        }
+        Ok(config)
+    }
+}
+
+struct Channel {
+    control: u8,
+    // 1-256; a time constant of 0 means 256.
+    constant: u16,
+    count: u16,
+    // By a reset, until a time constant arrives.
+    stopped: bool,
+    expecting_constant: bool,
+    // A timer started by a trigger edge that hasn't come yet.
+    waiting: bool,
+    // System clock ticks towards the next prescaler output.
+    phase: u64,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            control: 0,
+            constant: 256,
+            count: 256,
+            stopped: true,
+            expecting_constant: false,
+            waiting: false,
+            phase: 0,
+        }
+    }
+    // Counts down, reloading at each zero, and returns how many zeros there were.
+    fn count_down(&mut self, pulses: u64) -> u64 {
+        let count = self.count as u64;
+        if pulses < count {
+            self.count -= pulses as u16;
+            return 0;
+        }
+        let constant = self.constant as u64;
+        let after = pulses - count;
+        self.count = (constant - after % constant) as u16;
+        1 + after / constant
+    }
+}
+
+struct State {
+    clock: u64,
+    triggers: [Trigger; CHANNELS],
+    start: Instant,
+    // System clock ticks accounted for.
+    ticks: u64,
+    channels: [Channel; CHANNELS],
+}
+
+impl State {
+    // Catches the counters up with the clock.
+    fn advance(&mut self) {
+        let elapsed = self.start.elapsed();
+        let now = elapsed.as_secs() * self.clock + elapsed.subsec_nanos() as u64 * self.clock / 1_000_000_000;
+        if now <= self.ticks { return; }
+        let (from, to) = (self.ticks, now);
+        self.ticks = now;
+        let mut zero_counts = [0u64; CHANNELS];
+        for index in 0..CHANNELS {
+            let edges = match self.triggers[index] {
+                Trigger::None => 0,
+                Trigger::Clock(hz) => {
+                    let edge = |ticks: u64| (ticks as u128 * hz as u128 / self.clock as u128) as u64;
+                    edge(to) - edge(from)
+                },
+                Trigger::ZeroCount(source) => zero_counts[source],
+            };
+            let channel = &mut self.channels[index];
+            if channel.stopped { continue; }
+            let pulses = if channel.control & COUNTER_MODE != 0 {
+                edges
+            } else if channel.waiting {
+                channel.waiting = edges == 0;
+                continue;
+            } else {
+                let prescale = if channel.control & PRESCALE_256 != 0 { 256 } else { 16 };
+                let total = channel.phase + (to - from);
+                channel.phase = total % prescale;
+                total / prescale
+            };
+            zero_counts[index] = channel.count_down(pulses);
+        }
+    }
+    fn write(&mut self, index: usize, value: u8) {
+        self.advance();
+        let channel = &mut self.channels[index];
+        if channel.expecting_constant {
+            channel.expecting_constant = false;
+            channel.constant = if value == 0 { 256 } else { value as u16 };
+            // A running channel takes the new constant at its next zero.
+            if channel.stopped {
+                channel.stopped = false;
+                channel.count = channel.constant;
+                channel.phase = 0;
+                channel.waiting = channel.control & (COUNTER_MODE | TRIGGER_START) == TRIGGER_START;
+            }
+        } else if value & CONTROL != 0 {
+            channel.control = value;
+            channel.expecting_constant = value & CONSTANT_FOLLOWS != 0;
+            if value & RESET != 0 {
+                channel.stopped = true;
+                channel.waiting = false;
+            }
+        }
+        // Anything else is the interrupt vector, for channel 0 only, and has nowhere to go.
+    }
+}
+
+#[derive(Clone)]
+pub struct Ctc {
+    state: Arc<Mutex<State>>,
+}
+
+impl Ctc {
+    pub fn new(config: &CtcConfig) -> Ctc {
+        Ctc {
+            state: Arc::new(Mutex::new(State {
+                clock: config.clock,
+                triggers: config.triggers,
+                start: Instant::now(),
+                ticks: 0,
+                channels: [Channel::new(), Channel::new(), Channel::new(), Channel::new()],
+            })),
+        }
+    }
+    pub fn port(&self, channel: usize) -> CtcPort {
+        CtcPort { ctc: self.clone(), channel: channel }
+    }
+}
+
+impl ConcurrentDevice for Ctc {
+    fn run(&mut self, die: Arc<AtomicBool>, _timeout: Duration) {
+        while !die.load(Ordering::Acquire) {
+            self.state.lock().unwrap().advance();
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+    }
+}
+
+pub struct CtcPort {
+    ctc: Ctc,
+    channel: usize,
+}
+
+impl IoDevice for CtcPort {
+    // The down counter as it stands.
+    fn read_in(&self) -> u8 {
+        let mut state = self.ctc.state.lock().unwrap();
+        state.advance();
+        state.channels[self.channel].count as u8
+    }
+    fn write_out(&mut self, value: u8) {
+        self.ctc.state.lock().unwrap().write(self.channel, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(constant: u16, count: u16) -> Channel {
+        Channel { constant: constant, count: count, stopped: false, ..Channel::new() }
+    }
+
+    // Makes it as if the CTC started this long ago.
+    fn age(ctc: &Ctc, elapsed: Duration) {
+        let mut state = ctc.state.lock().unwrap();
+        state.start -= elapsed;
+    }
+
+    #[test]
+    fn count_down_reloads_at_zero() {
+        let mut counter = channel(10, 10);
+        assert_eq!(counter.count_down(3), 0);
+        assert_eq!(counter.count, 7);
+        assert_eq!(counter.count_down(7), 1);
+        assert_eq!(counter.count, 10);
+        // Zeros at 10 and 20.
+        assert_eq!(counter.count_down(25), 2);
+        assert_eq!(counter.count, 5);
+        // A new constant waits for the next reload.
+        counter.constant = 4;
+        assert_eq!(counter.count_down(6), 1);
+        assert_eq!(counter.count, 3);
+        let mut counter = channel(256, 256);
+        assert_eq!(counter.count_down(3 * 256), 3);
+        assert_eq!(counter.count, 256);
+    }
+
+    #[test]
+    fn timer_runs_on_the_prescaled_clock() {
+        // 16 Hz and a prescaler of 16 make a pulse a second.
+        let ctc = Ctc::new(&CtcConfig::new(0, 16));
+        let mut port = ctc.port(0);
+        port.write_out(CONTROL | CONSTANT_FOLLOWS | RESET);
+        port.write_out(4);
+        assert_eq!(port.read_in(), 4);
+        // A quarter of a pulse over, so the test running slowly changes nothing.
+        age(&ctc, Duration::from_millis(10_250));
+        // Zeros after 4 and 8 pulses, then 2 more.
+        assert_eq!(port.read_in(), 2);
+    }
+
+    #[test]
+    fn constant_of_zero_is_256() {
+        let ctc = Ctc::new(&CtcConfig::new(0, 16));
+        let mut port = ctc.port(1);
+        port.write_out(CONTROL | CONSTANT_FOLLOWS | RESET);
+        port.write_out(0);
+        assert_eq!(ctc.state.lock().unwrap().channels[1].count, 256);
+        age(&ctc, Duration::from_millis(1_250));
+        assert_eq!(port.read_in(), 255);
+    }
+
+    #[test]
+    fn counters_cascade() {
+        let mut config = CtcConfig::new(0, 16);
+        config.triggers[1] = Trigger::ZeroCount(0);
+        let ctc = Ctc::new(&config);
+        let mut timer = ctc.port(0);
+        timer.write_out(CONTROL | CONSTANT_FOLLOWS | RESET);
+        timer.write_out(2);
+        let mut counter = ctc.port(1);
+        counter.write_out(CONTROL | COUNTER_MODE | CONSTANT_FOLLOWS | RESET);
+        counter.write_out(3);
+        // 10 pulses make 5 zeros on channel 0, and channel 1 counts them.
+        age(&ctc, Duration::from_millis(10_250));
+        assert_eq!(counter.read_in(), 1);
+    }
+
+    #[test]
+    fn reset_stops_a_channel() {
+        let ctc = Ctc::new(&CtcConfig::new(0, 16));
+        let mut port = ctc.port(2);
+        port.write_out(CONTROL | CONSTANT_FOLLOWS | RESET);
+        port.write_out(8);
+        port.write_out(CONTROL | RESET);
+        age(&ctc, Duration::from_millis(3_250));
+        assert_eq!(port.read_in(), 8);
+    }
+}
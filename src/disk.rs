@@ -3,24 +3,27 @@ use super::{ ConcurrentDevice };
 extern crate memmap;
 use z80e_core_rust::{ IoDevice };
 use self::memmap::{ Mmap };
-pub use self::memmap::{ MmapView, Protection };
+pub use self::memmap::{ MmapViewSync, Protection };
 
 use std::sync::{ Arc, Condvar, Mutex };
 use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 use std::io::{ self, ErrorKind, Write };
-use std::path::Path;
-use std::{ str, mem, ptr };
+use std::path::{ Component, Path, PathBuf };
+use std::str::FromStr;
+use std::{ str, thread };
 
 // Sector size must be a power of two
 const SECTOR_SIZE: u16 = 128;
 
-// Max disk must be a power of two <= 16.
-const MAX_DISK: u8 = 16;
+// Drives are selected with a single byte.
+pub const MAX_DRIVES: u16 = 0x100;
+pub const DEFAULT_DRIVES: u16 = 16;
 
 // Status port bitflags.
 //     8-bit values, but "must" be usize to avoid overly-verbose casts for AtomicUsize calls.
 const READY: usize = 1 << 0;
+const CHANGED: usize = 1 << 1;
 const ERROR: usize = 1 << 7;
 
 // Commands.
@@ -34,6 +37,148 @@ const RESET: u8 = 6;
 const OPEN: u8 = 7;
 const CLOSE: u8 = 8;
 const DPB: u8 = 9;
+const LBA: u8 = 10;
+const MEDIA: u8 = 11;
+
+// Mechanical characteristics of a drive.
+//     Step rate and head settle are in milliseconds per track and per seek respectively.
+//     Interleave is the physical distance between logically consecutive sectors.
+#[derive(Clone, Copy)]
+pub struct Timing {
+    pub step_rate: u32,
+    pub head_settle: u32,
+    pub rpm: u32,
+    pub interleave: u16,
+}
+
+impl Timing {
+    // IBM 3740-style 8" single density drive.
+    pub fn floppy_8in() -> Timing {
+        Timing {
+            step_rate: 8,
+            head_settle: 15,
+            rpm: 360,
+            interleave: 6,
+        }
+    }
+    // Typical 5.25" double density drive.
+    pub fn floppy_5in() -> Timing {
+        Timing {
+            step_rate: 6,
+            head_settle: 15,
+            rpm: 300,
+            interleave: 2,
+        }
+    }
+    // Time for the given access, given where the head is and how far the disk has turned.
+    fn delay(&self, head: u16, track: u16, sector: u16, spt: u16, elapsed: Duration) -> Duration {
+        let mut micros: u64 = 0;
+        if head != track {
+            let steps = if head > track { head - track } else { track - head } as u64;
+            micros += steps * self.step_rate as u64 * 1000 + self.head_settle as u64 * 1000;
+        }
+        if self.rpm != 0 && spt != 0 {
+            let revolution = 60_000_000 / self.rpm as u64;
+            let sector_time = revolution / spt as u64;
+            let spt = spt as u64;
+            let interleave = self.interleave as u64 % spt;
+            let interleave = if interleave == 0 { 1 } else { interleave };
+            let per_pass = spt / gcd(spt, interleave);
+            let sector = sector as u64 % spt;
+            let slot = (sector * interleave + sector / per_pass) % spt;
+            let now = (elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64 + micros) % revolution;
+            micros += (slot * sector_time + revolution - now) % revolution + sector_time;
+        }
+        Duration::new(micros / 1_000_000, ((micros % 1_000_000) * 1000) as u32)
+    }
+}
+
+impl FromStr for Timing {
+    type Err = String;
+    // Either a preset name or step_rate,head_settle,rpm,interleave.
+    fn from_str(s: &str) -> Result<Timing, String> {
+        match s {
+            "8in" | "8" => return Ok(Timing::floppy_8in()),
+            "5.25in" | "5in" | "5.25" => return Ok(Timing::floppy_5in()),
+            _ => (),
+        }
+        let fields: Vec<&str> = s.split(',').collect();
+        if fields.len() != 4 {
+            return Err(format!("Expected a preset (8in, 5.25in) or step,settle,rpm,interleave: {}", s));
+        }
+        let mut values = [0u32; 4];
+        for (value, field) in values.iter_mut().zip(fields.iter()) {
+            *value = match u32::from_str(field) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("{} ← {}", field, err)),
+            };
+        }
+        if values[3] > u16::max_value() as u32 {
+            return Err(format!("Interleave too large: {}", values[3]));
+        }
+        Ok(Timing {
+            step_rate: values[0],
+            head_settle: values[1],
+            rpm: values[2],
+            interleave: values[3] as u16,
+        })
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// Whether a file name sent by the guest stays inside the image directory once joined onto it.
+fn in_directory(file_name: &str) -> bool {
+    Path::new(file_name).components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        Component::ParentDir | Component::RootDir | Component::Prefix(_) => false,
+    })
+}
+
+fn command_name(command: u8) -> &'static str {
+    match command {
+        NOP => "NOP",
+        SEL_DSK => "SEL_DSK",
+        SEL_TRK => "SEL_TRK",
+        SEL_SEC => "SEL_SEC",
+        READ => "READ",
+        WRITE => "WRITE",
+        RESET => "RESET",
+        OPEN => "OPEN",
+        CLOSE => "CLOSE",
+        DPB => "DPB",
+        LBA => "LBA",
+        MEDIA => "MEDIA",
+        _ => "?",
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct DriveStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub seeks: u64,
+    pub errors: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl DriveStats {
+    pub fn is_idle(&self) -> bool {
+        self.reads == 0 && self.writes == 0 && self.seeks == 0 && self.errors == 0
+    }
+}
+
+struct Drive {
+    disk: Option<Disk>,
+    // Media was inserted or ejected by the host since the guest last asked.
+    changed: bool,
+}
+
+// One line per command, shared between controllers.
+pub type TraceLog = Arc<Mutex<Box<dyn Write + Send>>>;
 
 #[derive(Clone)]
 pub struct DiskController {
@@ -41,16 +186,81 @@ pub struct DiskController {
     command_cond: Arc<Condvar>,
     buffer: Arc<Mutex<Buffer>>,
     parameters: Arc<Mutex<Parameters>>,
+    drives: Arc<Mutex<Vec<Drive>>>,
+    timings: Arc<Mutex<Vec<Option<Timing>>>>,
+    directory: Arc<PathBuf>,
+    stats: Arc<Mutex<Vec<DriveStats>>>,
+    trace: Arc<Mutex<Option<(String, TraceLog)>>>,
 }
 
 impl DiskController {
-    pub fn new() -> DiskController {
+    // Image file names sent by the guest are resolved relative to directory.
+    pub fn new(drives: u16, directory: PathBuf) -> DiskController {
+        assert!(drives > 0 && drives <= MAX_DRIVES, "Bad drive count: {}", drives);
         DiskController {
             status: Arc::new(AtomicUsize::new(0)),
             command_cond: Arc::new(Condvar::new()),
             buffer: Arc::new(Mutex::new(Buffer::new())),
             parameters: Arc::new(Mutex::new(Parameters::new())),
+            drives: Arc::new(Mutex::new((0..drives).map(|_| Drive { disk: None, changed: false }).collect())),
+            timings: Arc::new(Mutex::new(vec![None; drives as usize])),
+            directory: Arc::new(directory),
+            stats: Arc::new(Mutex::new(vec![DriveStats::default(); drives as usize])),
+            trace: Arc::new(Mutex::new(None)),
+        }
+    }
+    // Without a timing model, commands complete as fast as the host allows.
+    pub fn set_timing(&self, drive: u8, timing: Option<Timing>) -> Result<(), String> {
+        let mut timings = self.timings.lock().unwrap();
+        if drive as usize >= timings.len() {
+            return Err(format!("No such drive: {} (max: {})", drive, timings.len() - 1));
         }
+        timings[drive as usize] = timing;
+        Ok(())
+    }
+    pub fn drives(&self) -> u16 {
+        self.drives.lock().unwrap().len() as u16
+    }
+    // Swaps media behind the guest's back, flagging the drive as changed.
+    pub fn insert<T: AsRef<Path>>(&self, drive: u8, path: &T) -> io::Result<()> {
+        let disk = try!(Disk::open(&self.directory.join(path), Protection::ReadWrite));
+        self.swap(drive, Some(disk))
+    }
+    pub fn eject(&self, drive: u8) -> io::Result<()> {
+        self.swap(drive, None)
+    }
+    fn swap(&self, drive: u8, disk: Option<Disk>) -> io::Result<()> {
+        // Parameters before drives, in the order the controller thread takes them.
+        let mut parameters = self.parameters.lock().unwrap();
+        let mut drives = self.drives.lock().unwrap();
+        match drives.get_mut(drive as usize) {
+            Some(slot) => {
+                slot.disk = disk;
+                slot.changed = true;
+            },
+            None => return Err(io::Error::new(ErrorKind::NotFound, format!("No such drive: {}", drive))),
+        }
+        // The old selection may be past the end of the new medium.
+        if parameters.disk == drive {
+            parameters.track = 0;
+            parameters.sector = 0;
+        }
+        self.status.fetch_or(CHANGED, Ordering::SeqCst);
+        Ok(())
+    }
+    pub fn is_loaded(&self, drive: u8) -> bool {
+        match self.drives.lock().unwrap().get(drive as usize) {
+            Some(slot) => slot.disk.is_some(),
+            None => false,
+        }
+    }
+    // A snapshot of the per-drive counters, indexed by drive.
+    pub fn stats(&self) -> Vec<DriveStats> {
+        self.stats.lock().unwrap().clone()
+    }
+    // Trace lines are prefixed with name to tell controllers apart in a shared log.
+    pub fn set_trace(&self, name: String, log: Option<TraceLog>) {
+        *self.trace.lock().unwrap() = log.map(|log| (name, log));
     }
     pub fn status_port(&self) -> StatusPort {
         StatusPort::new(self.clone())
@@ -156,7 +366,8 @@ impl IoDevice for DataPort {
         if (self.controller.status.fetch_and(!READY, Ordering::SeqCst) & READY) != 0 {
             {
                 let mut buffer = self.controller.buffer.lock().unwrap();
-                buffer.bytes[buffer.i as usize] = value;
+                let i = buffer.i as usize;
+                buffer.bytes[i] = value;
                 buffer.i = ((buffer.i as usize + 1) & (SECTOR_SIZE - 1) as usize) as u16;
             }
         } else {
@@ -168,7 +379,7 @@ impl IoDevice for DataPort {
 }
 
 pub struct Disk {
-    view: MmapView,
+    view: MmapViewSync,
     pub tracks: u16,
     pub spt: u16,
     dpb: [u8; 17],
@@ -176,7 +387,7 @@ pub struct Disk {
 
 impl Disk {
     pub fn open<T: AsRef<Path>>(path: &T, protection: Protection) -> io::Result<Disk> {
-        let file = try!(Mmap::open_path(path, protection)).into_view();
+        let file = try!(Mmap::open_path(path, protection)).into_view_sync();
         let (header, image) = try!(file.split_at(128));
         let header = unsafe { header.as_slice() };
         if match str::from_utf8(&header[0..10]) {
@@ -201,17 +412,19 @@ impl Disk {
             dpb: dpb
         })
     }
+    // Where a sector starts in the image, if the image is long enough to hold it; the header's
+    //     geometry isn't to be trusted that far.
+    fn offset(&self, track: u16, sector: u16) -> Option<usize> {
+        let off = ((track as usize * self.spt as usize) + sector as usize) * SECTOR_SIZE as usize;
+        if off + SECTOR_SIZE as usize <= self.view.len() { Some(off) } else { None }
+    }
 }
 
 impl ConcurrentDevice for DiskController {
     fn run(&mut self, die: Arc<AtomicBool>, timeout: Duration) {
-        let mut disks = unsafe {
-            let mut disks: [Option<Disk>; MAX_DISK as usize] = mem::uninitialized();
-            for disk in disks.iter_mut() {
-                ptr::write(disk, None);
-            };
-            disks
-        };
+        let count = self.drives() as usize;
+        let mut heads: Vec<u16> = vec![0; count];
+        let epoch = Instant::now();
         let mut parameters = self.parameters.lock().unwrap();
         loop {
             if die.load(Ordering::Acquire) { break; }
@@ -226,12 +439,14 @@ impl ConcurrentDevice for DiskController {
             if (status & ERROR) != 0 && parameters.command != RESET {
                 continue;
             };
+            let mut delay = None;
             {
                 let mut buffer = self.buffer.lock().unwrap();
+                let mut drives = self.drives.lock().unwrap();
                 match parameters.command {
                     NOP => (),
                     SEL_DSK => {
-                        if buffer.bytes[0] < MAX_DISK {
+                        if (buffer.bytes[0] as usize) < count {
                             parameters.disk = buffer.bytes[0];
                         } else {
                             self.status.fetch_or(ERROR, Ordering::SeqCst);
@@ -239,7 +454,7 @@ impl ConcurrentDevice for DiskController {
                     },
                     SEL_TRK => {
                         let track = buffer.bytes[0] as u16 | ((buffer.bytes[1] as u16) << 8);
-                        match disks[parameters.disk as usize] {
+                        match drives[parameters.disk as usize].disk {
                             Some(ref disk) => {
                                 if track < disk.tracks {
                                     parameters.track = track;
@@ -253,10 +468,9 @@ impl ConcurrentDevice for DiskController {
                         }
                     },
                     SEL_SEC => {
-                        match disks[parameters.disk as usize] {
+                        match drives[parameters.disk as usize].disk {
                             Some(ref disk) => {
                                 let sector = buffer.bytes[0] as u16 | ((buffer.bytes[1] as u16) << 8);
-                                println!("Selecting sector: {}", sector);
                                 if sector < disk.spt {
                                     parameters.sector = sector;
                                 } else {
@@ -269,13 +483,19 @@ impl ConcurrentDevice for DiskController {
                         }
                     },
                     READ => {
-                        match disks[parameters.disk as usize] {
+                        match drives[parameters.disk as usize].disk {
                             Some(ref disk) => {
-                                let off = ((parameters.track as usize * disk.spt as usize)
-                                           + parameters.sector as usize) * SECTOR_SIZE as usize;
-                                let bytes = unsafe { disk.view.as_slice() };
-                                for (i, byte) in buffer.bytes.iter_mut().enumerate() {
-                                    *byte = bytes[off + i];
+                                delay = self.access(&mut heads, &parameters, disk.spt, epoch);
+                                match disk.offset(parameters.track, parameters.sector) {
+                                    Some(off) => {
+                                        let bytes = unsafe { disk.view.as_slice() };
+                                        for (i, byte) in buffer.bytes.iter_mut().enumerate() {
+                                            *byte = bytes[off + i];
+                                        }
+                                    },
+                                    None => {
+                                        self.status.fetch_or(ERROR, Ordering::SeqCst);
+                                    },
                                 }
                             },
                             None => {
@@ -284,14 +504,20 @@ impl ConcurrentDevice for DiskController {
                         }
                     },
                     WRITE => {
-                        match disks[parameters.disk as usize] {
+                        match drives[parameters.disk as usize].disk {
                             Some(ref mut disk) => {
-                                let off = ((parameters.track as usize * disk.spt as usize)
-                                           + parameters.sector as usize) * SECTOR_SIZE as usize;
-                                let bytes = unsafe { disk.view.as_mut_slice() };
-                                for (i, byte) in buffer.bytes.iter().enumerate() {
-                                    bytes[off + i] = *byte;
-                                };
+                                delay = self.access(&mut heads, &parameters, disk.spt, epoch);
+                                match disk.offset(parameters.track, parameters.sector) {
+                                    Some(off) => {
+                                        let bytes = unsafe { disk.view.as_mut_slice() };
+                                        for (i, byte) in buffer.bytes.iter().enumerate() {
+                                            bytes[off + i] = *byte;
+                                        };
+                                    },
+                                    None => {
+                                        self.status.fetch_or(ERROR, Ordering::SeqCst);
+                                    },
+                                }
                             },
                             None => {
                                 self.status.fetch_or(ERROR, Ordering::SeqCst);
@@ -299,15 +525,41 @@ impl ConcurrentDevice for DiskController {
                         }
 
                     }
+                    LBA => {
+                        match drives[parameters.disk as usize].disk {
+                            Some(ref disk) => {
+                                let block = buffer.bytes[0] as u32
+                                    | ((buffer.bytes[1] as u32) << 8)
+                                    | ((buffer.bytes[2] as u32) << 16);
+                                let track = (block / disk.spt as u32) as u16;
+                                let sector = (block % disk.spt as u32) as u16;
+                                if block < disk.tracks as u32 * disk.spt as u32 && disk.offset(track, sector).is_some() {
+                                    parameters.track = track;
+                                    parameters.sector = sector;
+                                } else {
+                                    self.status.fetch_or(ERROR, Ordering::SeqCst);
+                                }
+                            },
+                            None => {
+                                self.status.fetch_or(ERROR, Ordering::SeqCst);
+                            },
+                        }
+                    },
                     RESET => {
                         self.status.fetch_and(!ERROR, Ordering::SeqCst);
                     },
                     OPEN => {
                         match str::from_utf8(buffer.bytes.split(|a| *a == 0).next().unwrap()) {
+                            Ok(file_name) if !in_directory(file_name) => {
+                                let _ = writeln!(io::stderr(), "disk: Refused file outside the image directory: {}", file_name);
+                                self.status.fetch_or(ERROR, Ordering::SeqCst);
+                            },
                             Ok(file_name) => {
-                                match Disk::open(&file_name, Protection::ReadWrite) {
+                                match Disk::open(&self.directory.join(file_name), Protection::ReadWrite) {
                                     Ok(disk) => {
-                                        disks[parameters.disk as usize] = Some(disk);
+                                        drives[parameters.disk as usize].disk = Some(disk);
+                                        parameters.track = 0;
+                                        parameters.sector = 0;
                                     },
                                     Err(err) => {
                                         let mut stderr = io::stderr();
@@ -324,10 +576,23 @@ impl ConcurrentDevice for DiskController {
                         }
                     },
                     CLOSE => {
-                        disks[parameters.disk as usize] = None;
+                        drives[parameters.disk as usize].disk = None;
+                    },
+                    MEDIA => {
+                        // One bit per drive, drive 0 in bit 0 of byte 0.
+                        for byte in buffer.bytes.iter_mut() {
+                            *byte = 0;
+                        }
+                        for (i, drive) in drives.iter_mut().enumerate() {
+                            if drive.changed {
+                                buffer.bytes[i / 8] |= 1 << (i % 8);
+                                drive.changed = false;
+                            }
+                        }
+                        self.status.fetch_and(!CHANGED, Ordering::SeqCst);
                     },
                     DPB => {
-                        match disks[parameters.disk as usize] {
+                        match drives[parameters.disk as usize].disk {
                             Some(ref disk) => {
                                 for (a, b) in disk.dpb.iter().zip(buffer.bytes.iter_mut()) {
                                     *b = *a;
@@ -345,6 +610,279 @@ impl ConcurrentDevice for DiskController {
                 }
                 buffer.i = 0;
             }
+            let failed = (self.status.load(Ordering::SeqCst) & ERROR) != 0;
+            self.record(&parameters, failed, delay, epoch);
+            if let Some(delay) = delay {
+                // READY stays off meanwhile, so only the host can get in, to swap media.
+                drop(parameters);
+                thread::sleep(delay);
+                parameters = self.parameters.lock().unwrap();
+            }
+        }
+    }
+}
+
+impl DiskController {
+    // Moves the selected drive's head and returns how long the access takes, if the drive is timed.
+    fn access(&self, heads: &mut [u16], parameters: &Parameters, spt: u16, epoch: Instant) -> Option<Duration> {
+        let timing = self.timings.lock().unwrap()[parameters.disk as usize];
+        let head = &mut heads[parameters.disk as usize];
+        if *head != parameters.track {
+            self.stats.lock().unwrap()[parameters.disk as usize].seeks += 1;
+        }
+        let delay = timing.map(|timing| timing.delay(*head, parameters.track, parameters.sector, spt, epoch.elapsed()));
+        *head = parameters.track;
+        delay
+    }
+    fn record(&self, parameters: &Parameters, failed: bool, delay: Option<Duration>, epoch: Instant) {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            let stats = &mut stats[parameters.disk as usize];
+            if failed {
+                stats.errors += 1;
+            } else {
+                match parameters.command {
+                    READ => {
+                        stats.reads += 1;
+                        stats.bytes_read += SECTOR_SIZE as u64;
+                    },
+                    WRITE => {
+                        stats.writes += 1;
+                        stats.bytes_written += SECTOR_SIZE as u64;
+                    },
+                    _ => (),
+                }
+            }
+        }
+        if let Some((ref name, ref log)) = *self.trace.lock().unwrap() {
+            let elapsed = epoch.elapsed();
+            let mut log = log.lock().unwrap();
+            let _ = write!(log, "{}.{:06} {} {} drive={} track={} sector={}",
+                           elapsed.as_secs(), elapsed.subsec_nanos() / 1000, name,
+                           command_name(parameters.command), parameters.disk, parameters.track, parameters.sector);
+            if let Some(delay) = delay {
+                let _ = write!(log, " delay={}us", delay.as_secs() * 1_000_000 + (delay.subsec_nanos() / 1000) as u64);
+            }
+            let _ = writeln!(log, " {}", if failed { "error" } else { "ok" });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{ env, fs, process };
+
+    fn fields(timing: &Timing) -> (u32, u32, u32, u16) {
+        (timing.step_rate, timing.head_settle, timing.rpm, timing.interleave)
+    }
+
+    #[test]
+    fn timing_presets() {
+        for name in ["8in", "8"].iter() {
+            assert_eq!(fields(&name.parse().unwrap()), fields(&Timing::floppy_8in()));
+        }
+        for name in ["5.25in", "5in", "5.25"].iter() {
+            assert_eq!(fields(&name.parse().unwrap()), fields(&Timing::floppy_5in()));
+        }
+        assert_eq!(fields(&"3,10,300,1".parse().unwrap()), (3, 10, 300, 1));
+    }
+
+    #[test]
+    fn timing_rejects_malformed_specs() {
+        for spec in ["", "3.5in", "8in,", "1,2,3", "1,2,3,4,5", "a,2,3,4", "-1,2,3,4", "1,2,3,65536", "1,,3,4"].iter() {
+            assert!(spec.parse::<Timing>().is_err(), "Accepted {}", spec);
+        }
+    }
+
+    #[test]
+    fn seek_time() {
+        // Without rotation, only the steps and the settle count.
+        let timing: Timing = "8,15,0,1".parse().unwrap();
+        assert_eq!(timing.delay(0, 10, 0, 26, Duration::from_secs(0)), Duration::from_millis(95));
+        assert_eq!(timing.delay(10, 0, 0, 26, Duration::from_secs(0)), Duration::from_millis(95));
+        assert_eq!(timing.delay(5, 5, 0, 26, Duration::from_secs(0)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn interleave_places_every_sector_once() {
+        let timing = Timing::floppy_8in();
+        let revolution = 60_000_000 / 360;
+        let sector_time = revolution / 26;
+        // From the index hole, each sector comes round at its slot and takes a sector time to read.
+        let mut slots: Vec<u64> = (0..26).map(|sector| {
+            let delay = timing.delay(0, 0, sector, 26, Duration::from_secs(0));
+            let micros = delay.as_secs() * 1_000_000 + (delay.subsec_nanos() / 1000) as u64;
+            micros / sector_time - 1
+        }).collect();
+        assert_eq!(&slots[..3], &[0, 6, 12]);
+        // The second pass starts one slot on.
+        assert_eq!(slots[13], 1);
+        slots.sort();
+        assert_eq!(slots, (0..26).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn rotation_waits_after_the_seek() {
+        let timing = Timing::floppy_8in();
+        let revolution = 60_000_000 / 360;
+        let sector_time = revolution / 26;
+        // The seek takes 23ms, by which time sector 0 has gone by.
+        let micros = 23_000 + (revolution - 23_000) + sector_time;
+        assert_eq!(timing.delay(0, 1, 0, 26, Duration::from_secs(0)), Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000));
+    }
+
+    const SPT: u16 = 26;
+
+    // An image in the temporary directory, gone again when dropped. The header claims the 77
+    //     tracks of an 8" floppy; only data_tracks of them are really there.
+    struct Image {
+        path: PathBuf,
+    }
+
+    impl Image {
+        fn new(name: &str, data_tracks: usize) -> Image {
+            let path = env::temp_dir().join(format!("metachronism-{}-{}.dsk", process::id(), name));
+            let mut bytes = vec![0; 128];
+            bytes[..10].copy_from_slice(b"<CPM_Disk>");
+            // SPT 26, BSH 3, DSM 242, OFF 2.
+            bytes[32..34].copy_from_slice(&[SPT as u8, 0]);
+            bytes[34] = 3;
+            bytes[37..39].copy_from_slice(&[242, 0]);
+            bytes[45..47].copy_from_slice(&[2, 0]);
+            bytes.resize(128 + data_tracks * SPT as usize * SECTOR_SIZE as usize, 0xe5);
+            fs::write(&path, &bytes).unwrap();
+            Image { path: path }
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    struct Running {
+        controller: DiskController,
+        die: Arc<AtomicBool>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Running {
+        fn new() -> Running {
+            let controller = DiskController::new(2, env::temp_dir());
+            let die = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let mut controller = controller.clone();
+                let die = die.clone();
+                thread::spawn(move || controller.run(die, Duration::from_millis(10)))
+            };
+            let running = Running { controller: controller, die: die, thread: Some(thread) };
+            running.wait();
+            running
+        }
+        fn wait(&self) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while self.controller.status.load(Ordering::SeqCst) & READY == 0 {
+                assert!(Instant::now() < deadline, "The controller never became ready.");
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        // Sends a command the way the BIOS does, arguments first, and returns the status after.
+        fn command(&self, command: u8, arguments: &[u8]) -> usize {
+            let mut data = self.controller.data_port();
+            for &byte in arguments {
+                data.write_out(byte);
+            }
+            self.controller.status_port().write_out(command);
+            self.wait();
+            self.controller.status.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.die.store(true, Ordering::Release);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    #[test]
+    fn sectors_past_the_end_of_a_short_image_are_errors() {
+        let image = Image::new("short", 1);
+        let running = Running::new();
+        running.controller.insert(0, &image.path).unwrap();
+        assert_eq!(running.command(SEL_TRK, &[1, 0]) & ERROR, 0);
+        assert_eq!(running.command(READ, &[]) & ERROR, ERROR);
+        assert_eq!(running.command(RESET, &[]) & ERROR, 0);
+        assert_eq!(running.command(WRITE, &[]) & ERROR, ERROR);
+        assert_eq!(running.command(RESET, &[]) & ERROR, 0);
+        // Block 26 is the first sector of track 1.
+        assert_eq!(running.command(LBA, &[SPT as u8, 0, 0]) & ERROR, ERROR);
+        assert_eq!(running.command(RESET, &[]) & ERROR, 0);
+        // What is there still reads, so the controller came through.
+        assert_eq!(running.command(SEL_TRK, &[0, 0]) & ERROR, 0);
+        assert_eq!(running.command(READ, &[]) & ERROR, 0);
+    }
+
+    #[test]
+    fn a_swap_clears_the_selection() {
+        let full = Image::new("full", 77);
+        let short = Image::new("swapped", 1);
+        let running = Running::new();
+        running.controller.insert(0, &full.path).unwrap();
+        assert_eq!(running.command(SEL_TRK, &[50, 0]) & ERROR, 0);
+        assert_eq!(running.command(SEL_SEC, &[3, 0]) & ERROR, 0);
+        running.controller.insert(0, &short.path).unwrap();
+        {
+            let parameters = running.controller.parameters.lock().unwrap();
+            assert_eq!((parameters.track, parameters.sector), (0, 0));
+        }
+        assert_eq!(running.command(READ, &[]) & ERROR, 0);
+        // Another drive's selection is left alone.
+        assert_eq!(running.command(SEL_TRK, &[50, 0]) & ERROR, 0);
+        running.controller.eject(1).unwrap();
+        assert_eq!(running.controller.parameters.lock().unwrap().track, 50);
+    }
+
+    #[test]
+    fn the_host_can_swap_media_while_a_drive_is_busy() {
+        let image = Image::new("busy", 2);
+        let running = Running::new();
+        running.controller.set_timing(0, Some("500,0,0,1".parse().unwrap())).unwrap();
+        running.controller.insert(0, &image.path).unwrap();
+        assert_eq!(running.command(SEL_TRK, &[1, 0]) & ERROR, 0);
+        // The seek to track 1 takes half a second.
+        running.controller.status_port().write_out(READ);
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        running.controller.eject(1).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(250));
+        running.wait();
+    }
+
+    fn open(running: &Running, file_name: &str) -> usize {
+        let mut arguments = file_name.as_bytes().to_vec();
+        arguments.push(0);
+        running.command(OPEN, &arguments)
+    }
+
+    #[test]
+    fn open_stays_inside_the_image_directory() {
+        let image = Image::new("open", 1);
+        let running = Running::new();
+        let file_name = image.path.file_name().unwrap().to_str().unwrap();
+        let directory = env::temp_dir();
+        let parent = directory.file_name().unwrap().to_str().unwrap();
+        for name in [image.path.to_str().unwrap(), &format!("../{}/{}", parent, file_name), &format!("./../{}/{}", parent, file_name)].iter() {
+            assert_eq!(open(&running, name) & ERROR, ERROR, "Opened {}", name);
+            assert!(!running.controller.is_loaded(0), "Opened {}", name);
+            assert_eq!(running.command(RESET, &[]) & ERROR, 0);
         }
+        assert_eq!(open(&running, &format!("./{}", file_name)) & ERROR, 0);
+        assert!(running.controller.is_loaded(0));
     }
 }
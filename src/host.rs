@@ -0,0 +1,435 @@
+use super::ConcurrentDevice;
+
+use console::{ self, ConsoleInput };
+use disk::DiskController;
+use screen::Screen;
+use stdio_dev::StdioDevice;
+use tape::Tape;
+use xmodem;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+use std::io::{ self, BufRead, BufReader, ErrorKind, Read, Write };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::os::unix::process::CommandExt;
+use std::path::{ Path, PathBuf };
+use std::process::{ self, Command };
+use std::str::FromStr;
+use std::{ env, fs, str, thread };
+
+// How often an idle control socket checks whether it should quit.
+const ACCEPT_POLL_MILLIS: u64 = 100;
+// How long the menu waits for a key before checking again.
+const MENU_POLL_MILLIS: u64 = 100;
+
+// Keys the menu's line editor understands.
+const ETX: u8 = 0x03;
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7f;
+
+// Ctrl-], as in telnet.
+pub const ESCAPE: u8 = 0x1d;
+
+// What the machine should do after a command.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Continue,
+    Quit,
+    Reset,
+}
+
+// Operator commands acting on a live machine.
+#[derive(Clone)]
+pub struct Host {
+    disk_controllers: Vec<DiskController>,
+    screen: Arc<Mutex<Screen>>,
+    tape: Tape,
+    console: StdioDevice,
+    // The exit status, once something has asked for the machine to stop.
+    stop: Arc<Mutex<Option<i32>>>,
+    // Whether the stop is to start over.
+    restart: Arc<AtomicBool>,
+    // Tells the CPU to halt, once a stop has been asked for.
+    cpu_stop: Arc<AtomicBool>,
+}
+
+impl Host {
+    // cpu_stop is the flag the CPU's memory watches.
+    pub fn new(disk_controllers: Vec<DiskController>, screen: Arc<Mutex<Screen>>, tape: Tape, console: StdioDevice,
+               cpu_stop: Arc<AtomicBool>) -> Host {
+        Host {
+            disk_controllers: disk_controllers,
+            screen: screen,
+            tape: tape,
+            console: console,
+            stop: Arc::new(Mutex::new(None)),
+            restart: Arc::new(AtomicBool::new(false)),
+            cpu_stop: cpu_stop,
+        }
+    }
+    // What the console would look like on the guest's terminal.
+    pub fn screen(&self) -> &Arc<Mutex<Screen>> {
+        &self.screen
+    }
+    // Runs one command line, writing its response to out.
+    pub fn execute(&self, line: &str, out: &mut dyn Write) -> io::Result<Action> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let mut action = Action::Continue;
+        let result = match words.first() {
+            None => Ok(()),
+            Some(&"help") | Some(&"?") => {
+                try!(writeln!(out, "insert [controller:]drive image  Insert a disk image, flagging media change."));
+                try!(writeln!(out, "eject [controller:]drive         Eject a disk image, flagging media change."));
+                try!(writeln!(out, "drives                           List drives with media loaded."));
+                try!(writeln!(out, "stats                            Show disk activity counters."));
+                try!(writeln!(out, "screen [attributes]              Show the console screen, optionally marking attributes."));
+                try!(writeln!(out, "reader load path                 Put a tape in the reader; |command reads from a command."));
+                try!(writeln!(out, "reader rewind|eject              Start the reader's tape over, or take it out."));
+                try!(writeln!(out, "punch load path                  Put a fresh tape in the punch; |command punches to one."));
+                try!(writeln!(out, "punch eject                      Finish the punched tape."));
+                try!(writeln!(out, "tapes                            Show what's in the reader and punch."));
+                try!(writeln!(out, "xmodem send|send-1k path         Send a file to an XMODEM receive running on the guest."));
+                try!(writeln!(out, "xmodem receive path              Take a file from an XMODEM send running on the guest."));
+                try!(writeln!(out, "reset                            Restart the machine from scratch."));
+                try!(writeln!(out, "quit                             Stop the machine."));
+                Ok(())
+            },
+            Some(&"quit") => {
+                action = Action::Quit;
+                Ok(())
+            },
+            Some(&"reset") => {
+                action = Action::Reset;
+                Ok(())
+            },
+            Some(&"insert") => {
+                if words.len() != 3 {
+                    Err("usage: insert [controller:]drive image".to_string())
+                } else {
+                    self.drive(words[1]).and_then(|(controller, drive)| {
+                        controller.insert(drive, &words[2]).map_err(|err| format!("{}: {}", words[2], err))
+                    })
+                }
+            },
+            Some(&"eject") => {
+                if words.len() != 2 {
+                    Err("usage: eject [controller:]drive".to_string())
+                } else {
+                    self.drive(words[1]).and_then(|(controller, drive)| {
+                        controller.eject(drive).map_err(|err| err.to_string())
+                    })
+                }
+            },
+            Some(&"drives") => {
+                for (i, controller) in self.disk_controllers.iter().enumerate() {
+                    for drive in 0..controller.drives() {
+                        if controller.is_loaded(drive as u8) {
+                            try!(writeln!(out, "{}:{} loaded", i, drive));
+                        }
+                    }
+                }
+                Ok(())
+            },
+            Some(&"stats") => self.report(out).map_err(|err| err.to_string()),
+            Some(&"reader") | Some(&"punch") => self.tape_command(line, &words),
+            Some(&"xmodem") => self.xmodem_command(line, &words, out),
+            Some(&"tapes") => {
+                let (reader, punch) = self.tape.describe();
+                try!(writeln!(out, "reader: {}", reader.unwrap_or("empty".to_string())));
+                try!(writeln!(out, "punch: {}", punch.unwrap_or("empty".to_string())));
+                Ok(())
+            },
+            Some(&"screen") => {
+                match words.get(1) {
+                    None => self.screen.lock().unwrap().dump(out, false).map_err(|err| err.to_string()),
+                    Some(&"attributes") => self.screen.lock().unwrap().dump(out, true).map_err(|err| err.to_string()),
+                    Some(_) => Err("usage: screen [attributes]".to_string()),
+                }
+            },
+            Some(command) => Err(format!("Unknown command: {} (try help)", command)),
+        };
+        try!(match result {
+            Ok(()) => writeln!(out, "ok"),
+            Err(err) => writeln!(out, "error: {}", err),
+        });
+        Ok(action)
+    }
+    // Activity counters for every drive that has seen any.
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (i, controller) in self.disk_controllers.iter().enumerate() {
+            for (drive, stats) in controller.stats().iter().enumerate() {
+                if stats.is_idle() { continue; }
+                try!(writeln!(out, "disk {}:{}: {} reads ({} bytes), {} writes ({} bytes), {} seeks, {} errors",
+                              i, drive, stats.reads, stats.bytes_read, stats.writes, stats.bytes_written,
+                              stats.seeks, stats.errors));
+            }
+        }
+        Ok(())
+    }
+    pub fn perform(&self, action: Action) {
+        match action {
+            Action::Continue => (),
+            Action::Quit => self.quit(),
+            Action::Reset => self.reset(),
+        }
+    }
+    pub fn quit(&self) {
+        self.exit(0)
+    }
+    // Asks for the machine to stop with the given exit status, for callers that need to report
+    //     failure. The CPU halts at its next instruction and the devices are shut down in order, so
+    //     printers and punches get to finish; the first status asked for is the one used.
+    pub fn exit(&self, status: i32) {
+        self.stop_with(status, false)
+    }
+    // Asks for the machine to stop as on quit, then start over.
+    pub fn reset(&self) {
+        self.stop_with(0, true)
+    }
+    fn stop_with(&self, status: i32, restart: bool) {
+        let mut stop = self.stop.lock().unwrap();
+        if stop.is_none() {
+            *stop = Some(status);
+            self.restart.store(restart, Ordering::SeqCst);
+            self.cpu_stop.store(true, Ordering::SeqCst);
+        }
+    }
+    pub fn stopping(&self) -> Option<i32> {
+        *self.stop.lock().unwrap()
+    }
+    pub fn restarting(&self) -> bool {
+        self.restart.load(Ordering::SeqCst)
+    }
+    // Starts over with the same command line, once the machine has stopped; disk images are shared
+    //     mappings, so nothing is lost.
+    pub fn restart(&self) -> ! {
+        let err = match env::current_exe() {
+            Ok(path) => Command::new(path).args(env::args_os().skip(1)).exec(),
+            Err(err) => err,
+        };
+        let _ = writeln!(io::stderr(), "Unable to reset: {}", err);
+        process::exit(1);
+    }
+    // Talks to the operator over a console until they ask to go back to the guest.
+    //     pending holds keys typed after the escape that brought us here.
+    pub fn menu(&self, input: &mut dyn ConsoleInput, out: &mut dyn Write, pending: &[u8]) -> io::Result<()> {
+        let mut out = CrLf { inner: out };
+        let mut pending: VecDeque<u8> = pending.iter().cloned().collect();
+        try!(writeln!(out, "\n[Host menu: help lists commands, an empty line or ^] resumes.]"));
+        loop {
+            try!(write!(out, "host> "));
+            try!(out.flush());
+            let line = match try!(read_line(input, &mut out, &mut pending)) {
+                Some(ref line) if !line.trim().is_empty() => line.clone(),
+                _ => break,
+            };
+            let action = try!(self.execute(&line, &mut out));
+            self.perform(action);
+            if action != Action::Continue {
+                return out.flush();
+            }
+        }
+        try!(writeln!(out, "[Resuming.]"));
+        out.flush()
+    }
+    fn tape_command(&self, line: &str, words: &[&str]) -> Result<(), String> {
+        // Commands can have spaces in them, so the spec is the rest of the line.
+        let spec = line.trim().splitn(3, char::is_whitespace).nth(2).map(|x| x.trim()).unwrap_or("");
+        let result = match (words[0], words.get(1).cloned()) {
+            ("reader", Some("load")) if !spec.is_empty() => self.tape.load_reader(spec),
+            ("reader", Some("rewind")) => self.tape.rewind_reader(),
+            ("reader", Some("eject")) => {
+                self.tape.eject_reader();
+                Ok(())
+            },
+            ("punch", Some("load")) if !spec.is_empty() => self.tape.load_punch(spec),
+            ("punch", Some("eject")) => {
+                self.tape.eject_punch();
+                Ok(())
+            },
+            ("reader", _) => return Err("usage: reader load path, reader rewind or reader eject".to_string()),
+            _ => return Err("usage: punch load path or punch eject".to_string()),
+        };
+        result.map_err(|err| if spec.is_empty() { err.to_string() } else { format!("{}: {}", spec, err) })
+    }
+    // Runs the transfer over the console, the guest's output going to us until it's done.
+    fn xmodem_command(&self, line: &str, words: &[&str], out: &mut dyn Write) -> Result<(), String> {
+        let path = line.trim().splitn(3, char::is_whitespace).nth(2).map(|x| x.trim()).unwrap_or("");
+        let one_k = match words.get(1).cloned() {
+            Some("send") if !path.is_empty() => false,
+            Some("send-1k") if !path.is_empty() => true,
+            Some("receive") if !path.is_empty() => {
+                let mut file = try!(File::create(path).map_err(|err| format!("{}: {}", path, err)));
+                let _ = writeln!(out, "[Waiting for the guest to start sending.]");
+                let _ = out.flush();
+                let result = xmodem::receive(&mut self.console.divert());
+                return match result {
+                    Ok(data) => {
+                        try!(file.write_all(&data).map_err(|err| format!("{}: {}", path, err)));
+                        let _ = writeln!(out, "Received {} bytes.", data.len());
+                        Ok(())
+                    },
+                    Err(err) => {
+                        let _ = fs::remove_file(path);
+                        Err(err)
+                    },
+                };
+            },
+            _ => return Err("usage: xmodem send path, xmodem send-1k path or xmodem receive path".to_string()),
+        };
+        let mut data = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut data)).map_err(|err| format!("{}: {}", path, err)));
+        let _ = writeln!(out, "[Waiting for the guest to start receiving.]");
+        let _ = out.flush();
+        let blocks = try!(xmodem::send(&mut self.console.divert(), &data, one_k));
+        let _ = writeln!(out, "Sent {} bytes in {} blocks.", data.len(), blocks);
+        Ok(())
+    }
+    // [controller:]drive, controller defaulting to 0.
+    fn drive(&self, spec: &str) -> Result<(&DiskController, u8), String> {
+        let (controller, drive) = match spec.find(':') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => ("0", spec),
+        };
+        let controller = try!(usize::from_str(controller).map_err(|err| format!("{} ← {}", controller, err)));
+        let drive = try!(u8::from_str(drive).map_err(|err| format!("{} ← {}", drive, err)));
+        match self.disk_controllers.get(controller) {
+            Some(x) if (drive as u16) < x.drives() => Ok((x, drive)),
+            Some(_) => Err(format!("No such drive: {}", spec)),
+            None => Err(format!("No such disk controller: {}", controller)),
+        }
+    }
+}
+
+// Accepts host commands, one per line, over a Unix domain socket.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    host: Host,
+}
+
+impl ControlServer {
+    pub fn bind<T: AsRef<Path>>(path: &T, host: Host) -> io::Result<ControlServer> {
+        let listener = try!(console::bind_unix(path));
+        try!(listener.set_nonblocking(true));
+        Ok(ControlServer {
+            listener: listener,
+            path: path.as_ref().to_path_buf(),
+            host: host,
+        })
+    }
+    fn serve(&self, stream: UnixStream, die: &AtomicBool, timeout: Duration) -> io::Result<()> {
+        try!(stream.set_nonblocking(false));
+        try!(stream.set_read_timeout(Some(timeout)));
+        let mut writer = try!(stream.try_clone());
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        loop {
+            if die.load(Ordering::Acquire) { return Ok(()); }
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    if line.last() != Some(&b'\n') { continue; }
+                    match str::from_utf8(&line) {
+                        Ok(command) => {
+                            let action = try!(self.host.execute(command, &mut writer));
+                            self.host.perform(action);
+                            if action == Action::Quit { return Ok(()); }
+                        },
+                        Err(err) => try!(writeln!(writer, "error: Bad UTF-8 in command: {}", err)),
+                    }
+                    line.clear();
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl ConcurrentDevice for ControlServer {
+    fn run(&mut self, die: Arc<AtomicBool>, timeout: Duration) {
+        loop {
+            if die.load(Ordering::Acquire) { break; }
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = self.serve(stream, &die, timeout) {
+                        let _ = writeln!(io::stderr(), "control: Connection error: {}", err);
+                    }
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                },
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "control: Accept failed: {}", err);
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                },
+            }
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Raw terminals don't turn LF into CR LF for us.
+struct CrLf<'a> {
+    inner: &'a mut dyn Write,
+}
+
+impl<'a> Write for CrLf<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split(|byte| *byte == b'\n').enumerate() {
+            if line.0 > 0 {
+                try!(self.inner.write_all(b"\r\n"));
+            }
+            try!(self.inner.write_all(line.1));
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// None if the operator backs out with ^] or ^C, or input ends.
+fn read_line(input: &mut dyn ConsoleInput, out: &mut dyn Write, pending: &mut VecDeque<u8>) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let mut buf = [0u8; 0x100];
+    loop {
+        let byte = match pending.pop_front() {
+            Some(byte) => byte,
+            None => {
+                match input.read(&mut buf, Duration::from_millis(MENU_POLL_MILLIS)) {
+                    Ok(0) => return Ok(None),
+                    Ok(count) => pending.extend(buf[..count].iter().cloned()),
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => (),
+                    Err(err) => return Err(err),
+                }
+                continue;
+            },
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                try!(write!(out, "\r\n"));
+                return Ok(Some(line));
+            },
+            ESCAPE | ETX => {
+                try!(write!(out, "\r\n"));
+                return Ok(None);
+            },
+            BS | DEL => {
+                if line.pop().is_some() {
+                    try!(out.write_all(b"\x08 \x08"));
+                }
+            },
+            0x20..=0x7e => {
+                line.push(byte as char);
+                try!(out.write_all(&[byte]));
+            },
+            _ => (),
+        }
+        try!(out.flush());
+    }
+}
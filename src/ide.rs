@@ -0,0 +1,728 @@
+// An ATA drive on an 8-bit IDE or CompactFlash board: the task file at eight consecutive ports,
+//     with a host image file as the medium, for BIOSes like RomWBW's that expect one.
+//
+// Commands run as soon as they're written, so BSY is never seen. Only the master is there; with
+//     the slave selected the status reads 0 and commands go nowhere. Boards like these don't wire
+//     the control block registers or the interrupt, so neither is emulated, and the data register
+//     is always a byte wide, as if 8-bit transfers had been turned on.
+
+use disk::TraceLog;
+
+use z80e_core_rust::IoDevice;
+
+use std::fs::{ File, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
+use std::iter;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::time::Instant;
+
+pub const SECTOR_SIZE: usize = 512;
+// The most LBA28 can reach.
+const MAX_SECTORS: u64 = 1 << 28;
+// The geometry reported for CHS addressing.
+const HEADS: u64 = 16;
+const SECTORS_PER_TRACK: u64 = 63;
+const MAX_CYLINDERS: u64 = 16383;
+
+// Register offsets from the base port.
+pub const PORTS: u8 = 8;
+const DATA: u8 = 0;
+const ERROR: u8 = 1;
+const COUNT: u8 = 2;
+const LBA_LOW: u8 = 3;
+const LBA_MID: u8 = 4;
+const LBA_HIGH: u8 = 5;
+const DEVICE: u8 = 6;
+const STATUS: u8 = 7;
+
+// Status register.
+const BSY: u8 = 1 << 7;
+const DRDY: u8 = 1 << 6;
+const DSC: u8 = 1 << 4;
+const DRQ: u8 = 1 << 3;
+const ERR: u8 = 1 << 0;
+// Error register.
+const UNC: u8 = 1 << 6;
+const IDNF: u8 = 1 << 4;
+const ABRT: u8 = 1 << 2;
+// Device register.
+const LBA_MODE: u8 = 1 << 6;
+const SLAVE: u8 = 1 << 4;
+
+// Commands.
+const RECALIBRATE: u8 = 0x10;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_NO_RETRY: u8 = 0x21;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_NO_RETRY: u8 = 0x31;
+const READ_VERIFY: u8 = 0x40;
+const READ_VERIFY_NO_RETRY: u8 = 0x41;
+const SEEK: u8 = 0x70;
+const DIAGNOSTIC: u8 = 0x90;
+const INITIALIZE: u8 = 0x91;
+const CHECK_POWER_MODE: u8 = 0xe5;
+const FLUSH_CACHE: u8 = 0xe7;
+const IDENTIFY: u8 = 0xec;
+const SET_FEATURES: u8 = 0xef;
+
+fn command_name(command: u8) -> &'static str {
+    match command {
+        READ_SECTORS | READ_SECTORS_NO_RETRY => "READ",
+        WRITE_SECTORS | WRITE_SECTORS_NO_RETRY => "WRITE",
+        READ_VERIFY | READ_VERIFY_NO_RETRY => "VERIFY",
+        IDENTIFY => "IDENTIFY",
+        _ => "?",
+    }
+}
+
+pub struct IdeConfig {
+    // None leaves it to the profile.
+    pub base_port: Option<u8>,
+    pub path: PathBuf,
+    pub read_only: bool,
+}
+
+impl FromStr for IdeConfig {
+    type Err = String;
+    // [base_port,]image[,ro]
+    fn from_str(s: &str) -> Result<IdeConfig, String> {
+        let fields: Vec<&str> = s.splitn(2, ',').collect();
+        let (base_port, rest) = if fields.len() == 2 && !fields[0].is_empty() && fields[0].bytes().all(|b| b.is_ascii_digit()) {
+            match u8::from_str(fields[0]) {
+                Ok(x) if x <= 0xff - (PORTS - 1) => (Some(x), fields[1]),
+                Ok(x) => return Err(format!("No room for eight ports at {}", x)),
+                Err(err) => return Err(format!("{} ← {}", fields[0], err)),
+            }
+        } else {
+            (None, s)
+        };
+        let (path, read_only) = match rest.strip_suffix(",ro") {
+            Some(path) => (path, true),
+            None => (rest, false),
+        };
+        if path.is_empty() {
+            return Err(format!("Expected [base_port,]image[,ro]: {}", s));
+        }
+        Ok(IdeConfig {
+            base_port: base_port,
+            path: PathBuf::from(path),
+            read_only: read_only,
+        })
+    }
+}
+
+// Words 10-19, 23-26 and 27-46 of the IDENTIFY data.
+const SERIAL: &str = "0";
+const FIRMWARE: &str = "1.0";
+const MODEL: &str = "Metachronism virtual CF";
+
+struct State {
+    image: File,
+    sectors: u64,
+    read_only: bool,
+    features: u8,
+    error: u8,
+    count: u8,
+    lba: [u8; 3],
+    device: u8,
+    status: u8,
+    command: u8,
+    buffer: [u8; SECTOR_SIZE],
+    index: usize,
+    // Sectors still to go after the one in the buffer.
+    remaining: u16,
+    trace: Option<(String, TraceLog)>,
+    epoch: Instant,
+}
+
+impl State {
+    fn address(&self) -> Option<u64> {
+        let address = if self.device & LBA_MODE != 0 {
+            (self.device as u64 & 0x0f) << 24 | (self.lba[2] as u64) << 16 | (self.lba[1] as u64) << 8 | self.lba[0] as u64
+        } else {
+            let cylinder = (self.lba[2] as u64) << 8 | self.lba[1] as u64;
+            let head = self.device as u64 & 0x0f;
+            let sector = self.lba[0] as u64;
+            if sector == 0 || sector > SECTORS_PER_TRACK {
+                return None;
+            }
+            (cylinder * HEADS + head) * SECTORS_PER_TRACK + sector - 1
+        };
+        if address < self.sectors { Some(address) } else { None }
+    }
+    // Steps the address registers on to the next sector, as the drive does between sectors.
+    fn next_address(&mut self) {
+        if self.device & LBA_MODE != 0 {
+            let address = (self.device as u32 & 0x0f) << 24 | (self.lba[2] as u32) << 16 | (self.lba[1] as u32) << 8 | self.lba[0] as u32;
+            let address = address.wrapping_add(1);
+            self.lba = [address as u8, (address >> 8) as u8, (address >> 16) as u8];
+            self.device = (self.device & 0xf0) | ((address >> 24) as u8 & 0x0f);
+        } else if (self.lba[0] as u64) < SECTORS_PER_TRACK {
+            self.lba[0] += 1;
+        } else {
+            self.lba[0] = 1;
+            if ((self.device & 0x0f) as u64) < HEADS - 1 {
+                self.device += 1;
+            } else {
+                self.device &= 0xf0;
+                let cylinder = ((self.lba[2] as u16) << 8 | self.lba[1] as u16).wrapping_add(1);
+                self.lba[1] = cylinder as u8;
+                self.lba[2] = (cylinder >> 8) as u8;
+            }
+        }
+    }
+    fn cylinders(&self) -> u64 {
+        (self.sectors / (HEADS * SECTORS_PER_TRACK)).min(MAX_CYLINDERS)
+    }
+    fn fail(&mut self, error: u8) {
+        self.error = error;
+        self.status = DRDY | DSC | ERR;
+        self.remaining = 0;
+    }
+    fn finish(&mut self) {
+        self.error = 0;
+        self.status = DRDY | DSC;
+    }
+    fn read_sector(&mut self) -> Result<(), u8> {
+        let address = match self.address() {
+            Some(x) => x,
+            None => {
+                self.record(None, true);
+                return Err(IDNF);
+            },
+        };
+        let result = self.image.seek(SeekFrom::Start(address * SECTOR_SIZE as u64))
+            .and_then(|_| self.image.read_exact(&mut self.buffer));
+        self.record(Some(address), result.is_err());
+        result.map_err(|_| UNC)
+    }
+    fn write_sector(&mut self) -> Result<(), u8> {
+        let address = match self.address() {
+            Some(x) => x,
+            None => {
+                self.record(None, true);
+                return Err(IDNF);
+            },
+        };
+        let result = self.image.seek(SeekFrom::Start(address * SECTOR_SIZE as u64))
+            .and_then(|_| self.image.write_all(&self.buffer));
+        self.record(Some(address), result.is_err());
+        result.map_err(|_| ABRT)
+    }
+    // Loads the next sector of a read into the buffer, or ends the command.
+    fn next_read(&mut self) {
+        match self.read_sector() {
+            Ok(()) => {
+                self.index = 0;
+                self.status = DRDY | DSC | DRQ;
+            },
+            Err(error) => self.fail(error),
+        }
+    }
+    fn execute(&mut self, command: u8) {
+        self.command = command;
+        self.index = 0;
+        // A count of 0 asks for 256 sectors.
+        let sectors = if self.count == 0 { 256 } else { self.count as u16 };
+        match command {
+            READ_SECTORS | READ_SECTORS_NO_RETRY => {
+                self.error = 0;
+                self.remaining = sectors - 1;
+                self.next_read();
+            },
+            WRITE_SECTORS | WRITE_SECTORS_NO_RETRY => {
+                if self.read_only {
+                    self.record(self.address(), true);
+                    return self.fail(ABRT);
+                }
+                if self.address().is_none() {
+                    self.record(None, true);
+                    return self.fail(IDNF);
+                }
+                self.error = 0;
+                self.remaining = sectors - 1;
+                self.status = DRDY | DSC | DRQ;
+            },
+            READ_VERIFY | READ_VERIFY_NO_RETRY => {
+                for i in 0..sectors {
+                    if self.address().is_none() {
+                        self.record(None, true);
+                        return self.fail(IDNF);
+                    }
+                    if i + 1 < sectors {
+                        self.next_address();
+                    }
+                }
+                self.record(self.address(), false);
+                self.finish();
+            },
+            IDENTIFY => {
+                self.identify();
+                self.record(None, false);
+                self.error = 0;
+                self.remaining = 0;
+                self.status = DRDY | DSC | DRQ;
+            },
+            SET_FEATURES => match self.features {
+                // 8-bit transfers on and off, write cache on and off, and transfer mode.
+                0x01 | 0x81 | 0x02 | 0x82 | 0x03 => self.finish(),
+                _ => self.fail(ABRT),
+            },
+            DIAGNOSTIC => {
+                self.count = 1;
+                self.lba = [1, 0, 0];
+                self.device &= !0x0f;
+                self.status = DRDY | DSC;
+                // Device 0 passed; there's no device 1.
+                self.error = 0x01;
+            },
+            CHECK_POWER_MODE => {
+                // Active or idle.
+                self.count = 0xff;
+                self.finish();
+            },
+            SEEK => match self.address() {
+                Some(_) => self.finish(),
+                None => self.fail(IDNF),
+            },
+            RECALIBRATE..=0x1f | INITIALIZE | FLUSH_CACHE | 0x94..=0x99 | 0xe0..=0xe3 | 0xe6 => self.finish(),
+            _ => self.fail(ABRT),
+        }
+    }
+    fn identify(&mut self) {
+        let mut words = [0u16; SECTOR_SIZE / 2];
+        let cylinders = self.cylinders();
+        let capacity = cylinders * HEADS * SECTORS_PER_TRACK;
+        // A removable CompactFlash card.
+        words[0] = 0x848a;
+        words[1] = cylinders as u16;
+        words[3] = HEADS as u16;
+        words[6] = SECTORS_PER_TRACK as u16;
+        words[7] = (self.sectors >> 16) as u16;
+        words[8] = self.sectors as u16;
+        put_string(&mut words[10..20], SERIAL);
+        put_string(&mut words[23..27], FIRMWARE);
+        put_string(&mut words[27..47], MODEL);
+        // LBA.
+        words[49] = 1 << 9;
+        // Words 54-58 are valid.
+        words[53] = 1;
+        words[54] = cylinders as u16;
+        words[55] = HEADS as u16;
+        words[56] = SECTORS_PER_TRACK as u16;
+        words[57] = capacity as u16;
+        words[58] = (capacity >> 16) as u16;
+        words[60] = self.sectors as u16;
+        words[61] = (self.sectors >> 16) as u16;
+        for (i, word) in words.iter().enumerate() {
+            self.buffer[i * 2] = *word as u8;
+            self.buffer[i * 2 + 1] = (*word >> 8) as u8;
+        }
+    }
+    fn read_data(&mut self) -> u8 {
+        if self.status & DRQ == 0 || self.command == WRITE_SECTORS || self.command == WRITE_SECTORS_NO_RETRY {
+            return 0xff;
+        }
+        let byte = self.buffer[self.index];
+        self.index += 1;
+        if self.index == SECTOR_SIZE {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                self.next_address();
+                self.next_read();
+            } else {
+                self.finish();
+            }
+        }
+        byte
+    }
+    fn write_data(&mut self, value: u8) {
+        if self.status & DRQ == 0 || !(self.command == WRITE_SECTORS || self.command == WRITE_SECTORS_NO_RETRY) {
+            return;
+        }
+        self.buffer[self.index] = value;
+        self.index += 1;
+        if self.index == SECTOR_SIZE {
+            self.index = 0;
+            if let Err(error) = self.write_sector() {
+                return self.fail(error);
+            }
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                self.next_address();
+            } else {
+                self.finish();
+            }
+        }
+    }
+    fn record(&self, address: Option<u64>, failed: bool) {
+        if let Some((ref name, ref log)) = self.trace {
+            let elapsed = self.epoch.elapsed();
+            let mut log = log.lock().unwrap();
+            let _ = write!(log, "{}.{:06} {} {}", elapsed.as_secs(), elapsed.subsec_nanos() / 1000, name, command_name(self.command));
+            if let Some(address) = address {
+                let _ = write!(log, " lba={}", address);
+            }
+            let _ = writeln!(log, " {}", if failed { "error" } else { "ok" });
+        }
+    }
+}
+
+// ATA strings are space-padded, with the first character of each pair in the high byte.
+fn put_string(words: &mut [u16], s: &str) {
+    let mut bytes = s.bytes().chain(iter::repeat(b' '));
+    for word in words.iter_mut() {
+        let high = bytes.next().unwrap();
+        let low = bytes.next().unwrap();
+        *word = (high as u16) << 8 | low as u16;
+    }
+}
+
+#[derive(Clone)]
+pub struct Ide {
+    state: Arc<Mutex<State>>,
+}
+
+impl Ide {
+    // Anything past the last whole sector of the image is out of reach.
+    pub fn open<T: AsRef<Path>>(path: &T, read_only: bool) -> io::Result<Ide> {
+        let image = try!(OpenOptions::new().read(true).write(!read_only).open(path));
+        let sectors = (try!(image.metadata()).len() / SECTOR_SIZE as u64).min(MAX_SECTORS);
+        Ok(Ide {
+            state: Arc::new(Mutex::new(State {
+                image: image,
+                sectors: sectors,
+                read_only: read_only,
+                features: 0,
+                error: 0x01,
+                count: 1,
+                lba: [1, 0, 0],
+                device: 0,
+                status: DRDY | DSC,
+                command: 0,
+                buffer: [0; SECTOR_SIZE],
+                index: 0,
+                remaining: 0,
+                trace: None,
+                epoch: Instant::now(),
+            })),
+        })
+    }
+    pub fn sectors(&self) -> u64 {
+        self.state.lock().unwrap().sectors
+    }
+    // Trace lines are prefixed with name, as the disk controllers' are.
+    pub fn set_trace(&self, name: String, log: Option<TraceLog>) {
+        self.state.lock().unwrap().trace = log.map(|log| (name, log));
+    }
+    // The register at offset from the base port.
+    pub fn port(&self, offset: u8) -> IdePort {
+        assert!(offset < PORTS, "Bad IDE register: {}", offset);
+        IdePort { ide: self.clone(), register: offset }
+    }
+}
+
+pub struct IdePort {
+    ide: Ide,
+    register: u8,
+}
+
+impl IoDevice for IdePort {
+    fn read_in(&self) -> u8 {
+        let mut state = self.ide.state.lock().unwrap();
+        if state.device & SLAVE != 0 {
+            return 0;
+        }
+        match self.register {
+            DATA => state.read_data(),
+            ERROR => state.error,
+            COUNT => state.count,
+            LBA_LOW => state.lba[0],
+            LBA_MID => state.lba[1],
+            LBA_HIGH => state.lba[2],
+            // The obsolete bits 7 and 5 read as set.
+            DEVICE => state.device | 0xa0,
+            STATUS => state.status & !BSY,
+            _ => unreachable!(),
+        }
+    }
+    fn write_out(&mut self, value: u8) {
+        let mut state = self.ide.state.lock().unwrap();
+        match self.register {
+            DATA => if state.device & SLAVE == 0 { state.write_data(value) },
+            ERROR => state.features = value,
+            COUNT => state.count = value,
+            LBA_LOW => state.lba[0] = value,
+            LBA_MID => state.lba[1] = value,
+            LBA_HIGH => state.lba[2] = value,
+            DEVICE => state.device = value,
+            STATUS => if state.device & SLAVE == 0 { state.execute(value) },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // An image in the temporary directory, gone again when dropped. Each sector starts with its
+    //     own address and is filled with its low byte.
+    struct Image {
+        path: PathBuf,
+    }
+
+    impl Image {
+        fn new(name: &str, sectors: u64) -> Image {
+            let path = env::temp_dir().join(format!("metachronism-{}-{}.img", process::id(), name));
+            let mut bytes = Vec::with_capacity(sectors as usize * SECTOR_SIZE);
+            for address in 0..sectors {
+                let mut sector = [address as u8; SECTOR_SIZE];
+                sector[..4].copy_from_slice(&[address as u8, (address >> 8) as u8, (address >> 16) as u8, (address >> 24) as u8]);
+                bytes.extend_from_slice(&sector);
+            }
+            fs::write(&path, &bytes).unwrap();
+            Image { path: path }
+        }
+        fn sector(&self, address: u64) -> Vec<u8> {
+            let bytes = fs::read(&self.path).unwrap();
+            let start = address as usize * SECTOR_SIZE;
+            bytes[start..start + SECTOR_SIZE].to_vec()
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn ports(ide: &Ide) -> Vec<IdePort> {
+        (0..PORTS).map(|offset| ide.port(offset)).collect()
+    }
+
+    fn lba(ports: &mut [IdePort], address: u32, count: u8) {
+        ports[COUNT as usize].write_out(count);
+        ports[LBA_LOW as usize].write_out(address as u8);
+        ports[LBA_MID as usize].write_out((address >> 8) as u8);
+        ports[LBA_HIGH as usize].write_out((address >> 16) as u8);
+        ports[DEVICE as usize].write_out(0xa0 | LBA_MODE | (address >> 24) as u8 & 0x0f);
+    }
+
+    fn chs(ports: &mut [IdePort], cylinder: u16, head: u8, sector: u8, count: u8) {
+        ports[COUNT as usize].write_out(count);
+        ports[LBA_LOW as usize].write_out(sector);
+        ports[LBA_MID as usize].write_out(cylinder as u8);
+        ports[LBA_HIGH as usize].write_out((cylinder >> 8) as u8);
+        ports[DEVICE as usize].write_out(0xa0 | head);
+    }
+
+    fn command(ports: &mut [IdePort], command: u8) -> u8 {
+        ports[STATUS as usize].write_out(command);
+        ports[STATUS as usize].read_in()
+    }
+
+    // Reads while the drive offers data.
+    fn read_data(ports: &[IdePort]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while ports[STATUS as usize].read_in() & DRQ != 0 {
+            bytes.push(ports[DATA as usize].read_in());
+        }
+        bytes
+    }
+
+    // The addresses at the start of each sector read.
+    fn addresses(bytes: &[u8]) -> Vec<u32> {
+        bytes.chunks(SECTOR_SIZE).map(|x| x[0] as u32 | (x[1] as u32) << 8 | (x[2] as u32) << 16 | (x[3] as u32) << 24).collect()
+    }
+
+    #[test]
+    fn identify() {
+        let image = Image::new("identify", 2 * HEADS * SECTORS_PER_TRACK + 5);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC);
+        assert_eq!(command(&mut ports, IDENTIFY), DRDY | DSC | DRQ);
+        let words: Vec<u16> = read_data(&ports).chunks(2).map(|x| x[0] as u16 | (x[1] as u16) << 8).collect();
+        assert_eq!(words.len(), SECTOR_SIZE / 2);
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC);
+        assert_eq!(words[1], 2);
+        assert_eq!(words[60] as u64 | (words[61] as u64) << 16, ide.sectors());
+        assert_eq!(words[27], (b'M' as u16) << 8 | b'e' as u16);
+        assert!(words[49] & 1 << 9 != 0);
+    }
+
+    #[test]
+    fn lba_read_steps_through_sectors() {
+        let image = Image::new("lba-read", 300);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        lba(&mut ports, 0xfe, 3);
+        assert_eq!(command(&mut ports, READ_SECTORS), DRDY | DSC | DRQ);
+        let bytes = read_data(&ports);
+        assert_eq!(addresses(&bytes), [0xfe, 0xff, 0x100]);
+        assert_eq!(bytes[SECTOR_SIZE + 4], 0xff);
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC);
+        // Left at the last sector read.
+        assert_eq!(ports[LBA_LOW as usize].read_in(), 0x00);
+        assert_eq!(ports[LBA_MID as usize].read_in(), 0x01);
+    }
+
+    #[test]
+    fn lba_stepping_carries_into_the_device_register() {
+        let image = Image::new("lba-carry", 1);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut state = ide.state.lock().unwrap();
+        state.device = LBA_MODE | 0x02;
+        state.lba = [0xff, 0xff, 0xff];
+        state.next_address();
+        assert_eq!(state.lba, [0, 0, 0]);
+        assert_eq!(state.device, LBA_MODE | 0x03);
+        state.device = LBA_MODE | 0x0f;
+        state.lba = [0xff, 0xff, 0xff];
+        state.next_address();
+        assert_eq!(state.device, LBA_MODE);
+    }
+
+    #[test]
+    fn chs_read_steps_through_heads_and_cylinders() {
+        let image = Image::new("chs-read", 2 * HEADS * SECTORS_PER_TRACK);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        // The last sector of the first track, then the first of the next head.
+        chs(&mut ports, 0, 0, SECTORS_PER_TRACK as u8, 2);
+        command(&mut ports, READ_SECTORS);
+        assert_eq!(addresses(&read_data(&ports)), [62, 63]);
+        assert_eq!(ports[LBA_LOW as usize].read_in(), 1);
+        assert_eq!(ports[DEVICE as usize].read_in() & 0x0f, 1);
+        // The last sector of the first cylinder, then the first of the next.
+        chs(&mut ports, 0, HEADS as u8 - 1, SECTORS_PER_TRACK as u8, 2);
+        command(&mut ports, READ_SECTORS);
+        let last = (HEADS * SECTORS_PER_TRACK) as u32 - 1;
+        assert_eq!(addresses(&read_data(&ports)), [last, last + 1]);
+        assert_eq!(ports[LBA_LOW as usize].read_in(), 1);
+        assert_eq!(ports[LBA_MID as usize].read_in(), 1);
+        assert_eq!(ports[DEVICE as usize].read_in() & 0x0f, 0);
+    }
+
+    #[test]
+    fn multiple_sector_write() {
+        let image = Image::new("write", 20);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        lba(&mut ports, 10, 3);
+        assert_eq!(command(&mut ports, WRITE_SECTORS), DRDY | DSC | DRQ);
+        // Reading the data register gets nothing while writing.
+        assert_eq!(ports[DATA as usize].read_in(), 0xff);
+        for i in 0..3 * SECTOR_SIZE {
+            assert!(ports[STATUS as usize].read_in() & DRQ != 0);
+            ports[DATA as usize].write_out((i / SECTOR_SIZE) as u8 + 0xa0);
+        }
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC);
+        assert_eq!(ports[LBA_LOW as usize].read_in(), 12);
+        assert_eq!(image.sector(9)[4], 9);
+        assert_eq!(image.sector(10), vec![0xa0; SECTOR_SIZE]);
+        assert_eq!(image.sector(12), vec![0xa2; SECTOR_SIZE]);
+        assert_eq!(image.sector(13)[4], 13);
+    }
+
+    #[test]
+    fn count_of_zero_is_256_sectors() {
+        let image = Image::new("count", 300);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        lba(&mut ports, 10, 0);
+        command(&mut ports, READ_SECTORS);
+        let addresses = addresses(&read_data(&ports));
+        assert_eq!(addresses.len(), 256);
+        assert_eq!(addresses[255], 265);
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC);
+        // Verifying checks all 256 are there.
+        lba(&mut ports, 300 - 256, 0);
+        assert_eq!(command(&mut ports, READ_VERIFY), DRDY | DSC);
+        lba(&mut ports, 300 - 255, 0);
+        assert_eq!(command(&mut ports, READ_VERIFY), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), IDNF);
+    }
+
+    #[test]
+    fn sectors_out_of_reach_are_not_found() {
+        let image = Image::new("idnf", 2 * HEADS * SECTORS_PER_TRACK);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        lba(&mut ports, ide.sectors() as u32, 1);
+        assert_eq!(command(&mut ports, READ_SECTORS), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), IDNF);
+        assert_eq!(command(&mut ports, WRITE_SECTORS), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), IDNF);
+        // CHS sectors count from 1.
+        chs(&mut ports, 0, 0, 0, 1);
+        assert_eq!(command(&mut ports, SEEK), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), IDNF);
+        // A read running off the end stops after the last sector there is.
+        lba(&mut ports, ide.sectors() as u32 - 1, 2);
+        command(&mut ports, READ_SECTORS);
+        assert_eq!(read_data(&ports).len(), SECTOR_SIZE);
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), IDNF);
+        // A good command clears the error.
+        lba(&mut ports, 0, 1);
+        command(&mut ports, READ_SECTORS);
+        assert_eq!(ports[ERROR as usize].read_in(), 0);
+    }
+
+    #[test]
+    fn refused_commands_are_aborted() {
+        let image = Image::new("abrt", 4);
+        let ide = Ide::open(&image.path, true).unwrap();
+        let mut ports = ports(&ide);
+        lba(&mut ports, 1, 1);
+        assert_eq!(command(&mut ports, WRITE_SECTORS), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), ABRT);
+        ports[DATA as usize].write_out(0);
+        assert_eq!(image.sector(1)[4], 1);
+        assert_eq!(command(&mut ports, 0x00), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), ABRT);
+        ports[ERROR as usize].write_out(0x55);
+        assert_eq!(command(&mut ports, SET_FEATURES), DRDY | DSC | ERR);
+        assert_eq!(ports[ERROR as usize].read_in(), ABRT);
+        ports[ERROR as usize].write_out(0x01);
+        assert_eq!(command(&mut ports, SET_FEATURES), DRDY | DSC);
+    }
+
+    #[test]
+    fn slave_is_absent() {
+        let image = Image::new("slave", 4);
+        let ide = Ide::open(&image.path, false).unwrap();
+        let mut ports = ports(&ide);
+        ports[DEVICE as usize].write_out(0xa0 | LBA_MODE | SLAVE);
+        assert_eq!(command(&mut ports, IDENTIFY), 0);
+        ports[DEVICE as usize].write_out(0xa0 | LBA_MODE);
+        assert_eq!(ports[STATUS as usize].read_in(), DRDY | DSC);
+    }
+
+    #[test]
+    fn config_base_port_is_optional() {
+        let config = IdeConfig::from_str("16,cf.img,ro").unwrap();
+        assert_eq!(config.base_port, Some(16));
+        assert_eq!(config.path, PathBuf::from("cf.img"));
+        assert!(config.read_only);
+        let config = IdeConfig::from_str("cf.img").unwrap();
+        assert_eq!(config.base_port, None);
+        assert_eq!(config.path, PathBuf::from("cf.img"));
+        assert!(!config.read_only);
+        let config = IdeConfig::from_str("cf,1.img,ro").unwrap();
+        assert_eq!(config.base_port, None);
+        assert_eq!(config.path, PathBuf::from("cf,1.img"));
+        assert!(config.read_only);
+        assert!(IdeConfig::from_str("250,cf.img").is_err());
+        assert!(IdeConfig::from_str("300,cf.img").is_err());
+        assert!(IdeConfig::from_str("16,").is_err());
+    }
+}
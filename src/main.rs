@@ -1,24 +1,58 @@
 extern crate z80e_core_rust;
 extern crate goss;
+extern crate libc;
+extern crate regex;
 
 mod mmu;
 mod stdio_dev;
 mod disk;
 mod debug;
+mod host;
+mod console;
+mod telnet;
+mod pty;
+mod script;
+mod terminal;
+mod screen;
+mod printer;
+mod tape;
+mod asciicast;
+mod translate;
+mod xmodem;
+mod modem;
+mod tty;
+mod sio;
+mod ctc;
+mod acia;
+mod ide;
 
-use mmu::{ Memory, MMU };
-use stdio_dev::{ StdioDevice };
+use mmu::{ Memory, MemoryMap, MMU, PagedMemory, StoppableMemory };
+use stdio_dev::{ EofPolicy, Pacing, StdioDevice };
 
-use disk::{ Disk, Protection };
+use disk::{ DiskController, Timing, TraceLog };
 use z80e_core_rust::Cpu;
 
 use debug::DebugDevice;
+use host::{ ControlServer, Host };
+use console::{ BackendSpec, ConsoleInput };
+use terminal::{ Terminal, TerminalInput, TerminalOutput };
+use screen::{ Screen, ScreenOutput };
+use printer::{ Printer, PrinterConfig };
+use tape::Tape;
+use asciicast::RecordConfig;
+use translate::{ Translation, TranslateInput, TranslateOutput };
+use sio::{ ChannelLine, Sio, SioConfig };
+use ctc::{ Ctc, CtcConfig };
+use acia::Acia;
+use ide::{ Ide, IdeConfig };
 
 use std::io::{ Read, Write };
 use std::str::FromStr;
 use std::env;
 use std::fs::File;
-use std::sync::{ Arc };
+use std::path::PathBuf;
+use std::process;
+use std::sync::{ Arc, Mutex };
 use std::sync::atomic::{ AtomicBool, Ordering };
 use std::time::Duration;
 use std::thread;
@@ -29,6 +63,100 @@ struct BankImage {
     file: File,
 }
 
+struct DiskControllerConfig {
+    status_port: u8,
+    data_port: u8,
+    drives: u16,
+    directory: PathBuf,
+}
+
+impl FromStr for DiskControllerConfig {
+    type Err = String;
+    // status_port,data_port[,drives[,directory]]
+    fn from_str(s: &str) -> Result<DiskControllerConfig, String> {
+        let fields: Vec<&str> = s.splitn(4, ',').collect();
+        if fields.len() < 2 {
+            return Err(format!("Expected status_port,data_port[,drives[,directory]]: {}", s));
+        }
+        let mut ports = [0u8; 2];
+        for (port, field) in ports.iter_mut().zip(fields.iter()) {
+            *port = match u8::from_str(field) {
+                Ok(x) => x,
+                Err(err) => return Err(format!("{} ← {}", field, err)),
+            };
+        }
+        let drives = match fields.get(2) {
+            Some(field) => match u16::from_str(field) {
+                Ok(x) if x > 0 && x <= disk::MAX_DRIVES => x,
+                Ok(x) => return Err(format!("Bad drive count: {} (max: {})", x, disk::MAX_DRIVES)),
+                Err(err) => return Err(format!("{} ← {}", field, err)),
+            },
+            None => disk::DEFAULT_DRIVES,
+        };
+        Ok(DiskControllerConfig {
+            status_port: ports[0],
+            data_port: ports[1],
+            drives: drives,
+            directory: PathBuf::from(fields.get(3).unwrap_or(&".")),
+        })
+    }
+}
+
+// A serial line like the console, with its own host transport. The data port is the one after
+//     the status port, where the BIOS looks for it.
+struct SerialPortConfig {
+    base_port: u8,
+    backend: BackendSpec,
+}
+
+impl FromStr for SerialPortConfig {
+    type Err = String;
+    // base_port,backend
+    fn from_str(s: &str) -> Result<SerialPortConfig, String> {
+        let fields: Vec<&str> = s.splitn(2, ',').collect();
+        if fields.len() < 2 {
+            return Err(format!("Expected base_port,backend: {}", s));
+        }
+        let base_port = match u8::from_str(fields[0]) {
+            Ok(x) if x < 0xff => x,
+            Ok(x) => return Err(format!("No room for two ports at {}", x)),
+            Err(err) => return Err(format!("{} ← {}", fields[0], err)),
+        };
+        Ok(SerialPortConfig {
+            base_port: base_port,
+            backend: try!(BackendSpec::from_str(fields[1])),
+        })
+    }
+}
+
+// Which machine the guest finds itself in.
+#[derive(Clone, Copy, PartialEq)]
+enum Profile {
+    // Banked memory and the status/data port devices the CP/M BIOS here is written for.
+    Metachronism,
+    // 512K ROM 512K RAM paging and a 68B50 ACIA console, for RC2014 ROMs. Images load at 16K
+    //     pages instead of banks. The core can't interrupt the CPU yet, so ROMs that leave the
+    //     ACIA to IM 1 interrupts instead of polling it won't get far.
+    Rc2014,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Profile, String> {
+        match s {
+            "metachronism" => Ok(Profile::Metachronism),
+            "rc2014" => Ok(Profile::Rc2014),
+            _ => Err(format!("Expected metachronism or rc2014: {}", s)),
+        }
+    }
+}
+
+// Where the RC2014's boards usually sit.
+const RC2014_PAGE_PORT: u8 = 0x78;
+const RC2014_PAGING_ENABLE_PORT: u8 = 0x7c;
+const RC2014_ACIA_PORT: u8 = 0x80;
+const RC2014_IDE_PORT: u8 = 0x10;
+
 const NUM_BANKS: u8 = 1;
 const DIE_TIMEOUT_SECS: u64 = 1;
 const DIE_TIMEOUT_NANOS: u32 = 0;
@@ -37,14 +165,39 @@ pub trait ConcurrentDevice {
     fn run(&mut self, die: Arc<AtomicBool>, timeout: Duration);
 }
 
+// Records which device owns each I/O port, refusing to hand one out twice.
+fn claim_port(owners: &mut Vec<Option<String>>, port: u8, owner: &str) {
+    if let Some(ref other) = owners[port as usize] {
+        let _ = writeln!(std::io::stderr(), "Port {} requested by {} is already used by {}.", port, owner, other);
+        panic!("I/O port conflict.");
+    }
+    owners[port as usize] = Some(owner.to_string());
+}
+
 fn main() {
     let mut num_banks = NUM_BANKS;
     let mut stderr = std::io::stderr();
-    let memory;
-    let mut mmu;
+    let mut profile = Profile::Metachronism;
+    let memory_map;
+    let mut disk_configs: Vec<DiskControllerConfig> = Vec::new();
+    let mut ide_configs: Vec<IdeConfig> = Vec::new();
+    let mut timings: Vec<(usize, u8, Timing)> = Vec::new();
+    let mut trace: Option<TraceLog> = None;
+    let mut control_path: Option<String> = None;
+    let mut console = BackendSpec::Stdio;
+    let mut serial_configs: Vec<SerialPortConfig> = Vec::new();
+    let mut sio_configs: Vec<SioConfig> = Vec::new();
+    let mut ctc_configs: Vec<CtcConfig> = Vec::new();
+    let mut emulation: Option<Terminal> = None;
+    let mut printer_config: Option<PrinterConfig> = None;
+    let tape = Tape::new();
+    let mut eof_policy = EofPolicy::default();
+    let mut pacing = Pacing::default();
+    let mut translation: Option<Translation> = None;
+    let mut record_config: Option<RecordConfig> = None;
     {
         let mut images: Vec<BankImage> = Vec::new();
-        match goss::getopt(env::args(), "l:n:") {
+        match goss::getopt(env::args(), "a:c:C:d:e:I:k:l:m:n:p:P:r:s:S:t:T:u:x:z:Z:") {
             Ok(mut got_opt) => {
                 for opt in got_opt.opts {
                     match opt.switch {
@@ -94,6 +247,215 @@ fn main() {
                                 file: file,
                             });
                         },
+                        'c' => {
+                            let arg = opt.argument.unwrap();
+                            console = match BackendSpec::from_str(&arg[..]) {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-c: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend console.");
+                                },
+                            };
+                        },
+                        // Shorthand for -s 15,backend, the BIOS's SER1 and the usual place for a
+                        //     modem.
+                        'm' => {
+                            let arg = opt.argument.unwrap();
+                            match BackendSpec::from_str(&arg[..]) {
+                                Ok(x) => serial_configs.push(SerialPortConfig {
+                                    base_port: 15,
+                                    backend: x,
+                                }),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-m: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend modem port.");
+                                },
+                            };
+                        },
+                        's' => {
+                            let arg = opt.argument.unwrap();
+                            match SerialPortConfig::from_str(&arg[..]) {
+                                Ok(config) => serial_configs.push(config),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-s: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend serial port.");
+                                },
+                            }
+                        },
+                        'C' => control_path = opt.argument,
+                        'P' => {
+                            let arg = opt.argument.unwrap();
+                            profile = match Profile::from_str(&arg[..]) {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-P: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend machine profile.");
+                                },
+                            };
+                        },
+                        'e' => {
+                            let arg = opt.argument.unwrap();
+                            emulation = match Terminal::from_str(&arg[..]) {
+                                Ok(x) => Some(x),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-e: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend terminal type.");
+                                },
+                            };
+                        },
+                        'p' => {
+                            let arg = opt.argument.unwrap();
+                            printer_config = match PrinterConfig::from_str(&arg[..]) {
+                                Ok(x) => Some(x),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-p: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend printer.");
+                                },
+                            };
+                        },
+                        'r' => {
+                            let arg = opt.argument.unwrap();
+                            if let Err(err) = tape.load_reader(&arg) {
+                                let _ = writeln!(stderr, "-r: Unable to load reader: {} → {}", arg, err);
+                                panic!("I/O error.");
+                            }
+                        },
+                        'u' => {
+                            let arg = opt.argument.unwrap();
+                            if let Err(err) = tape.load_punch(&arg) {
+                                let _ = writeln!(stderr, "-u: Unable to load punch: {} → {}", arg, err);
+                                panic!("I/O error.");
+                            }
+                        },
+                        'z' => {
+                            let arg = opt.argument.unwrap();
+                            eof_policy = match EofPolicy::from_str(&arg[..]) {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-z: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend end-of-input policy.");
+                                },
+                            };
+                        },
+                        'k' => {
+                            let arg = opt.argument.unwrap();
+                            pacing = match Pacing::from_str(&arg[..]) {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-k: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend input pacing.");
+                                },
+                            };
+                        },
+                        'a' => {
+                            let arg = opt.argument.unwrap();
+                            record_config = match RecordConfig::from_str(&arg[..]) {
+                                Ok(x) => Some(x),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-a: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend recording.");
+                                },
+                            };
+                        },
+                        'x' => {
+                            let arg = opt.argument.unwrap();
+                            translation = match Translation::from_str(&arg[..]) {
+                                Ok(x) => Some(x),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-x: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend console translation.");
+                                },
+                            };
+                        },
+                        'S' => {
+                            let arg = opt.argument.unwrap();
+                            match SioConfig::from_str(&arg[..]) {
+                                Ok(config) => sio_configs.push(config),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-S: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend SIO channel.");
+                                },
+                            }
+                        },
+                        'Z' => {
+                            let arg = opt.argument.unwrap();
+                            match CtcConfig::from_str(&arg[..]) {
+                                Ok(config) => ctc_configs.push(config),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-Z: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend CTC.");
+                                },
+                            }
+                        },
+                        'd' => {
+                            let arg = opt.argument.unwrap();
+                            match DiskControllerConfig::from_str(&arg[..]) {
+                                Ok(config) => disk_configs.push(config),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-d: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend disk controller.");
+                                },
+                            }
+                        },
+                        'I' => {
+                            let arg = opt.argument.unwrap();
+                            match IdeConfig::from_str(&arg[..]) {
+                                Ok(config) => ide_configs.push(config),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-I: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend IDE drive.");
+                                },
+                            }
+                        },
+                        't' => {
+                            let arg = opt.argument.unwrap();
+                            let subopts: Vec<&str> = arg.splitn(2, '=').collect();
+                            if subopts.len() < 2 {
+                                let _ = writeln!(stderr, "-t: Bad argument: {}", arg);
+                                panic!("Expected [controller:]drive=timing.");
+                            }
+                            let mut controller = 0;
+                            let mut drive = subopts[0];
+                            if let Some(i) = drive.find(':') {
+                                controller = match usize::from_str(&drive[..i]) {
+                                    Ok(x) => x,
+                                    Err(err) => {
+                                        let _ = writeln!(stderr, "-t: Bad argument: {} → {} ← {}", arg, &drive[..i], err);
+                                        panic!("Unable to comprehend number.");
+                                    },
+                                };
+                                drive = &drive[i + 1..];
+                            }
+                            let drive = match u8::from_str(drive) {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-t: Bad argument: {} → {} ← {}", arg, drive, err);
+                                    panic!("Unable to comprehend number.");
+                                },
+                            };
+                            match Timing::from_str(subopts[1]) {
+                                Ok(timing) => timings.push((controller, drive, timing)),
+                                Err(err) => {
+                                    let _ = writeln!(stderr, "-t: Bad argument: {} → {}", arg, err);
+                                    panic!("Unable to comprehend drive timing.");
+                                },
+                            }
+                        },
+                        'T' => {
+                            let arg = opt.argument.unwrap();
+                            let log: Box<dyn Write + Send> = if arg == "-" {
+                                Box::new(std::io::stderr())
+                            } else {
+                                match File::create(&arg) {
+                                    Ok(file) => Box::new(file),
+                                    Err(err) => {
+                                        let _ = writeln!(stderr, "-T: Unable to create trace log: {} → {}", arg, err);
+                                        panic!("I/O error.");
+                                    },
+                                }
+                            };
+                            trace = Some(Arc::new(Mutex::new(log)));
+                        },
                         switch @ _ => { let _ = writeln!(stderr, "Unhandled switch: -{}", switch); },
                     }
                 }
@@ -122,89 +484,437 @@ fn main() {
                 }
             },
         }
-        memory = Memory::new(num_banks);
-        mmu = MMU::new(memory.clone());
-        let mut bank_0_initialized = false;
-        for image in images.iter_mut() {
-            if image.bank >= num_banks as usize {
-                let _ = writeln!(stderr, "Invalid bank: {} for image {}", image.bank, image.name);
-                panic!("Total number of banks too low for index. (Number of banks: {})", num_banks);
-            }
-            let mut image_temp: Vec<u8> = Vec::new();
-            match image.file.read_to_end(&mut image_temp) {
-                Ok(_) => {
-                    if image_temp.len() > mmu::BANK_SIZE {
-                        let _ = writeln!(stderr, "Invalid image: {}", image.name);
-                        panic!("The file is too large for the bank size ({} bytes).", mmu::BANK_SIZE);
+        memory_map = match profile {
+            Profile::Metachronism => {
+                let memory = Memory::new(num_banks);
+                let mut bank_0_initialized = false;
+                for image in images.iter_mut() {
+                    if image.bank >= num_banks as usize {
+                        let _ = writeln!(stderr, "Invalid bank: {} for image {}", image.bank, image.name);
+                        panic!("Total number of banks too low for index. (Number of banks: {})", num_banks);
                     }
-                },
-                Err(err) => {
-                    let _ = writeln!(stderr, "-l: Read error: {}", err);
-                    panic!("Unable to read bank image.");
+                    let mut image_temp: Vec<u8> = Vec::new();
+                    match image.file.read_to_end(&mut image_temp) {
+                        Ok(_) => {
+                            if image_temp.len() > mmu::BANK_SIZE {
+                                let _ = writeln!(stderr, "Invalid image: {}", image.name);
+                                panic!("The file is too large for the bank size ({} bytes).", mmu::BANK_SIZE);
+                            }
+                        },
+                        Err(err) => {
+                            let _ = writeln!(stderr, "-l: Read error: {}", err);
+                            panic!("Unable to read bank image.");
+                        }
+                    }
+                    let mut bank = match memory.banks[image.bank].lock() {
+                        Ok(x) => x,
+                        Err(err) => {
+                            let _ = writeln!(stderr, "-l: Mutex error: {}", err);
+                            panic!("Unable to acquire mutex for bank {}.", image.bank);
+                        }
+                    };
+                    for i in 0..image_temp.len() {
+                        bank[i] = image_temp[i];
+                    }
+                    if image.bank == 0 { bank_0_initialized = true; };
                 }
-            }
-            let mut bank = match memory.banks[image.bank].lock() {
-                Ok(x) => x,
-                Err(err) => {
-                    let _ = writeln!(stderr, "-l: Mutex error: {}", err);
-                    panic!("Unable to acquire mutex for bank {}.", image.bank);
+                if !bank_0_initialized {
+                    let _ = writeln!(stderr, "Memory not ready.");
+                    panic!("You must load an image for bank 0. (-l)")
                 }
-            };
-            for i in 0..image_temp.len() {
-                bank[i] = image_temp[i];
+                MemoryMap::Banked(MMU::new(memory))
+            },
+            Profile::Rc2014 => {
+                let paged = PagedMemory::new();
+                let mut page_0_initialized = false;
+                for image in images.iter_mut() {
+                    let mut image_temp: Vec<u8> = Vec::new();
+                    if let Err(err) = image.file.read_to_end(&mut image_temp) {
+                        let _ = writeln!(stderr, "-l: Read error: {}", err);
+                        panic!("Unable to read page image.");
+                    }
+                    if let Err(err) = paged.load(image.bank, &image_temp) {
+                        let _ = writeln!(stderr, "Invalid image: {} → {}", image.name, err);
+                        panic!("The file doesn't fit in memory.");
+                    }
+                    if image.bank == 0 { page_0_initialized = true; };
+                }
+                if !page_0_initialized {
+                    let _ = writeln!(stderr, "Memory not ready.");
+                    panic!("You must load an image for page 0. (-l)")
+                }
+                MemoryMap::Paged(paged)
+            },
+        };
+    }
+    let mut port_owners: Vec<Option<String>> = vec![None; 0x100];
+    let cpu_stop = Arc::new(AtomicBool::new(false));
+    let mut memory = StoppableMemory::new(memory_map, cpu_stop.clone());
+    let mut cpu = Cpu::new(&mut memory);
+    match memory.map {
+        MemoryMap::Banked(ref mut mmu) => for port in 0..4 {
+            claim_port(&mut port_owners, port, "MMU");
+            cpu.install_device(port, &mut mmu.bank_registers[port as usize]);
+        },
+        MemoryMap::Paged(ref mut paged) => {
+            for window in 0..4 {
+                let port = RC2014_PAGE_PORT + window as u8;
+                claim_port(&mut port_owners, port, "page registers");
+                cpu.install_device(port, &mut paged.page_registers[window]);
             }
-            if image.bank == 0 { bank_0_initialized = true; };
-        }
-        if !bank_0_initialized {
-            let _ = writeln!(stderr, "Memory not ready.");
-            panic!("You must load an image for bank 0. (-l)")
-        }
+            claim_port(&mut port_owners, RC2014_PAGING_ENABLE_PORT, "page registers");
+            cpu.install_device(RC2014_PAGING_ENABLE_PORT, &mut paged.paging_enable);
+        },
     }
-    let mut cpu = Cpu::new(&mut mmu);
-    cpu.install_device(0, &mut mmu.bank_registers[0]);
-    cpu.install_device(1, &mut mmu.bank_registers[1]);
-    cpu.install_device(2, &mut mmu.bank_registers[2]);
-    cpu.install_device(3, &mut mmu.bank_registers[3]);
 
     let device = StdioDevice::new();
-    cpu.install_device(4, &mut device.get_control_port());
-    cpu.install_device(5, &mut device.get_data_port());
+    device.set_pacing(pacing);
+    let mut acia = None;
+    match profile {
+        Profile::Metachronism => {
+            claim_port(&mut port_owners, 4, "console");
+            claim_port(&mut port_owners, 5, "console");
+            cpu.install_device(4, &mut device.get_control_port());
+            cpu.install_device(5, &mut device.get_data_port());
+        },
+        Profile::Rc2014 => {
+            println!("Profile: RC2014, ACIA console at {}.", RC2014_ACIA_PORT);
+            let console_acia = Acia::new(device.clone());
+            claim_port(&mut port_owners, RC2014_ACIA_PORT, "ACIA");
+            claim_port(&mut port_owners, RC2014_ACIA_PORT + 1, "ACIA");
+            cpu.install_device(RC2014_ACIA_PORT, &mut console_acia.control_port());
+            cpu.install_device(RC2014_ACIA_PORT + 1, &mut console_acia.data_port());
+            acia = Some(console_acia);
+        },
+    }
+
+    if let Some(ref config) = printer_config {
+        println!("Printer: {}.", config.describe());
+    }
+    let printer = Printer::new(printer_config.unwrap_or_else(PrinterConfig::discard));
+    // The RC2014 has nowhere for these to go.
+    if profile == Profile::Metachronism {
+        claim_port(&mut port_owners, 6, "debug");
+        cpu.install_device(6, &mut DebugDevice::new());
+
+        claim_port(&mut port_owners, 9, "printer");
+        claim_port(&mut port_owners, 10, "printer");
+        cpu.install_device(9, &mut printer.status_port());
+        cpu.install_device(10, &mut printer.data_port());
+
+        claim_port(&mut port_owners, 11, "tape reader");
+        claim_port(&mut port_owners, 12, "tape reader");
+        claim_port(&mut port_owners, 13, "tape punch");
+        claim_port(&mut port_owners, 14, "tape punch");
+        cpu.install_device(11, &mut tape.reader_status_port());
+        cpu.install_device(12, &mut tape.reader_data_port());
+        cpu.install_device(13, &mut tape.punch_status_port());
+        cpu.install_device(14, &mut tape.punch_data_port());
+    }
+
+    let mut stdio_users = if let BackendSpec::Stdio = console { 1 } else { 0 };
+    for config in serial_configs.iter() {
+        if let BackendSpec::Stdio = config.backend { stdio_users += 1; }
+    }
+    for config in sio_configs.iter() {
+        if let ChannelLine::Backend(BackendSpec::Stdio) = config.line { stdio_users += 1; }
+    }
+    if stdio_users > 1 {
+        let _ = writeln!(stderr, "Only one of the console, the serial ports and the SIO channels can use stdio.");
+        panic!("Unable to share stdio.");
+    }
+    // Lines whose backends are opened once the host is up, and what to call them.
+    let mut lines: Vec<(String, &BackendSpec, StdioDevice)> = Vec::new();
+    // Known by their ports, like the other devices, rather than a number that needn't match the
+    //     BIOS's SER1 and SER2.
+    for config in serial_configs.iter() {
+        let serial_port = StdioDevice::new();
+        let (status_port, data_port) = (config.base_port, config.base_port + 1);
+        let owner = format!("serial port at {}", status_port);
+        claim_port(&mut port_owners, status_port, &owner);
+        claim_port(&mut port_owners, data_port, &owner);
+        cpu.install_device(status_port, &mut serial_port.get_control_port());
+        cpu.install_device(data_port, &mut serial_port.get_data_port());
+        let name = format!("Serial port at {}", status_port);
+        lines.push((name, &config.backend, serial_port));
+    }
+
+    // Channels given with the same base port belong to the same chip.
+    let mut sio_bases: Vec<u8> = Vec::new();
+    for config in sio_configs.iter() {
+        if !sio_bases.contains(&config.base_port) {
+            sio_bases.push(config.base_port);
+        }
+    }
+    let mut sios = Vec::new();
+    for &base in sio_bases.iter() {
+        let mut channel_lines = [None, None];
+        for config in sio_configs.iter().filter(|x| x.base_port == base) {
+            let letter = if config.channel == sio::CHANNEL_A { 'A' } else { 'B' };
+            if channel_lines[config.channel].is_some() {
+                let _ = writeln!(stderr, "-S: SIO at {} has channel {} twice.", base, letter);
+                panic!("Unable to comprehend SIO channel.");
+            }
+            let line = match config.line {
+                ChannelLine::Console => device.clone(),
+                ChannelLine::Backend(ref spec) => {
+                    let line = StdioDevice::new();
+                    let name = format!("SIO at {} channel {}", base, letter);
+                    lines.push((name, spec, line.clone()));
+                    line
+                },
+            };
+            channel_lines[config.channel] = Some(line);
+        }
+        let [a, b] = channel_lines;
+        let sio = Sio::new(a, b);
+        let owner = format!("SIO at {}", base);
+        for port in base..base + 4 {
+            claim_port(&mut port_owners, port, &owner);
+        }
+        cpu.install_device(base, &mut sio.control_port(sio::CHANNEL_A));
+        cpu.install_device(base + 1, &mut sio.data_port(sio::CHANNEL_A));
+        cpu.install_device(base + 2, &mut sio.control_port(sio::CHANNEL_B));
+        cpu.install_device(base + 3, &mut sio.data_port(sio::CHANNEL_B));
+        sios.push(sio);
+    }
 
-    cpu.install_device(6, &mut DebugDevice::new());
+    let mut ctcs = Vec::new();
+    for config in ctc_configs.iter() {
+        println!("CTC: {}.", config.describe());
+        let ctc = Ctc::new(config);
+        let owner = format!("CTC at {}", config.base_port);
+        for channel in 0..ctc::CHANNELS {
+            let port = config.base_port + channel as u8;
+            claim_port(&mut port_owners, port, &owner);
+            cpu.install_device(port, &mut ctc.port(channel));
+        }
+        ctcs.push(ctc);
+    }
 
-    let disk_controller = disk::DiskController::new();
-    cpu.install_device(7, &mut disk_controller.status_port());
-    cpu.install_device(8, &mut disk_controller.data_port());
+    if disk_configs.is_empty() && profile == Profile::Metachronism {
+        disk_configs.push(DiskControllerConfig {
+            status_port: 7,
+            data_port: 8,
+            drives: disk::DEFAULT_DRIVES,
+            directory: PathBuf::from("."),
+        });
+    }
+    let mut disk_controllers = Vec::new();
+    for (i, config) in disk_configs.into_iter().enumerate() {
+        let disk_controller = DiskController::new(config.drives, config.directory);
+        let owner = format!("disk controller {}", i);
+        claim_port(&mut port_owners, config.status_port, &owner);
+        claim_port(&mut port_owners, config.data_port, &owner);
+        cpu.install_device(config.status_port, &mut disk_controller.status_port());
+        cpu.install_device(config.data_port, &mut disk_controller.data_port());
+        disk_controller.set_trace(format!("disk{}", i), trace.clone());
+        disk_controllers.push(disk_controller);
+    }
+    for (i, config) in ide_configs.iter().enumerate() {
+        let base_port = match (config.base_port, profile) {
+            (Some(x), _) => x,
+            (None, Profile::Rc2014) => RC2014_IDE_PORT,
+            // 16 is SER1 here, so there's no usual place to fall back on.
+            (None, Profile::Metachronism) => {
+                let _ = writeln!(stderr, "-I: No base port given for {}.", config.path.display());
+                panic!("Unable to place IDE drive.");
+            },
+        };
+        let drive = match Ide::open(&config.path, config.read_only) {
+            Ok(x) => x,
+            Err(err) => {
+                let _ = writeln!(stderr, "-I: Unable to open image: {} → {}", config.path.display(), err);
+                panic!("I/O error.");
+            },
+        };
+        println!("IDE at {}: {}, {} sectors{}.", base_port, config.path.display(), drive.sectors(),
+                 if config.read_only { ", read-only" } else { "" });
+        let owner = format!("IDE at {}", base_port);
+        for offset in 0..ide::PORTS {
+            let port = base_port + offset;
+            claim_port(&mut port_owners, port, &owner);
+            cpu.install_device(port, &mut drive.port(offset));
+        }
+        drive.set_trace(format!("ide{}", i), trace.clone());
+    }
+    for &(controller, drive, timing) in timings.iter() {
+        let result = match disk_controllers.get(controller) {
+            Some(disk_controller) => disk_controller.set_timing(drive, Some(timing)),
+            None => Err(format!("No such disk controller: {}", controller)),
+        };
+        if let Err(err) = result {
+            let _ = writeln!(stderr, "-t: {}", err);
+            panic!("Unable to set drive timing.");
+        }
+    }
 
     let die = Arc::new(AtomicBool::new(false));
     let timeout = Duration::new(DIE_TIMEOUT_SECS, DIE_TIMEOUT_NANOS);
     let mut device_threads = Vec::new();
+    let terminal = emulation.unwrap_or(Terminal::Ansi);
+    let screen = Arc::new(Mutex::new(Screen::new(terminal)));
+    let host = Host::new(disk_controllers.clone(), screen.clone(), tape.clone(), device.clone(), cpu_stop);
     {
-        let mut reader = device.get_reader();
+        let host = host.clone();
+        device.set_eof_policy(eof_policy, Box::new(move || host.quit()));
+    }
+    let backend = match console.open(&host) {
+        Ok(x) => x,
+        Err(err) => {
+            let _ = writeln!(stderr, "-c: Unable to open console → {}", err);
+            panic!("I/O error.");
+        },
+    };
+    if let Some(description) = backend.describe() {
+        println!("Console: {}.", description);
+    }
+    if let Some(carrier) = backend.carrier() {
+        device.set_carrier(carrier);
+    }
+    if let Some(ref translation) = translation {
+        println!("Console translation: {}.", translation.describe());
+    }
+    let (input, output) = backend.split();
+    let (input, output) = match record_config {
+        Some(ref config) => match asciicast::record(config, input, output) {
+            Ok(x) => {
+                println!("Recording: {}.", config.describe());
+                x
+            },
+            Err(err) => {
+                let _ = writeln!(stderr, "-a: Unable to start recording: {} → {}", config.describe(), err);
+                panic!("I/O error.");
+            },
+        },
+        None => (input, output),
+    };
+    // Translation sits nearest the host on the way in and nearest the guest on the way out, so
+    //     the terminal emulation and the screen only ever see what the guest does.
+    let input: Box<dyn ConsoleInput> = match translation {
+        Some(ref translation) => Box::new(TranslateInput::new(translation.input, input)),
+        None => input,
+    };
+    let (input, output): (Box<dyn ConsoleInput>, Box<dyn Write + Send>) = match terminal {
+        Terminal::Ansi => (input, output),
+        _ => (Box::new(TerminalInput::new(terminal, input)), Box::new(TerminalOutput::new(terminal, output))),
+    };
+    let output: Box<dyn Write + Send> = Box::new(ScreenOutput::new(screen, output));
+    let output: Box<dyn Write + Send> = match translation {
+        Some(ref translation) => Box::new(TranslateOutput::new(translation.output, output)),
+        None => output,
+    };
+    {
+        let mut reader = device.get_reader(input);
         let die = die.clone();
         let timeout = timeout.clone();
-        thread::spawn(move || reader.run(die, timeout));
+        device_threads.push(thread::spawn(move || reader.run(die, timeout)));
     }
     {
-        let mut writer = device.get_writer();
+        let mut writer = device.get_writer(output);
         let die = die.clone();
         let timeout = timeout.clone();
         device_threads.push(thread::spawn(move || writer.run(die, timeout)));
     }
+    for (name, spec, line) in lines {
+        let backend = match spec.open(&host) {
+            Ok(x) => x,
+            Err(err) => {
+                let _ = writeln!(stderr, "Unable to open {} → {}", name, err);
+                panic!("I/O error.");
+            },
+        };
+        match backend.describe() {
+            Some(description) => println!("{}: {}.", name, description),
+            None => println!("{}.", name),
+        }
+        if let Some(carrier) = backend.carrier() {
+            line.set_carrier(carrier);
+        }
+        let (input, output) = backend.split();
+        {
+            let mut reader = line.get_reader(input);
+            let die = die.clone();
+            let timeout = timeout.clone();
+            device_threads.push(thread::spawn(move || reader.run(die, timeout)));
+        }
+        {
+            let mut writer = line.get_writer(output);
+            let die = die.clone();
+            let timeout = timeout.clone();
+            device_threads.push(thread::spawn(move || writer.run(die, timeout)));
+        }
+    }
+    for sio in sios.iter() {
+        let mut sio = sio.clone();
+        let die = die.clone();
+        let timeout = timeout.clone();
+        device_threads.push(thread::spawn(move || sio.run(die, timeout)));
+    }
+    if let Some(ref acia) = acia {
+        let mut acia = acia.clone();
+        let die = die.clone();
+        let timeout = timeout.clone();
+        device_threads.push(thread::spawn(move || acia.run(die, timeout)));
+    }
+    for ctc in ctcs.iter() {
+        let mut ctc = ctc.clone();
+        let die = die.clone();
+        let timeout = timeout.clone();
+        device_threads.push(thread::spawn(move || ctc.run(die, timeout)));
+    }
     {
+        let mut printer = printer.clone();
+        let die = die.clone();
+        let timeout = timeout.clone();
+        device_threads.push(thread::spawn(move || printer.run(die, timeout)));
+    }
+    for disk_controller in disk_controllers.iter() {
         let mut device = disk_controller.clone();
         let die = die.clone();
         let timeout = timeout.clone();
         device_threads.push(thread::spawn(move || device.run(die, timeout)));
     }
+    if let Some(path) = control_path {
+        let mut server = match ControlServer::bind(&path, host.clone()) {
+            Ok(x) => x,
+            Err(err) => {
+                let _ = writeln!(stderr, "-C: Unable to bind control socket: {} → {}", path, err);
+                panic!("I/O error.");
+            },
+        };
+        let die = die.clone();
+        let timeout = timeout.clone();
+        device_threads.push(thread::spawn(move || server.run(die, timeout)));
+    }
     
 
     let cpu = Arc::new(&cpu);
+    // With no cycle limit, execute() returns when the CPU halts: either the guest ran a HALT, or
+    //     the host asked for a stop and the memory handed it one.
     let _ = cpu.execute(0);
+    let status = match host.stopping() {
+        Some(status) => status,
+        None => {
+            println!("The guest halted.");
+            0
+        },
+    };
+    tty::restore();
     die.store(true, Ordering::Release);
     println!("Waiting for all threads to quit…");
     for thread in device_threads {
         let _ = thread.join().unwrap();
     }
+    tape.eject_punch();
+    let _ = host.report(&mut stderr);
+    if host.restarting() {
+        println!("Restarting.");
+        host.restart();
+    }
+    if status != 0 {
+        println!("Exiting with status {}.", status);
+        process::exit(status);
+    }
     println!("Exiting successfully.")
 }
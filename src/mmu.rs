@@ -1,9 +1,13 @@
 use z80e_core_rust::{ self as z80, IoDevice };
 
 use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
 
 pub const BANK_SIZE: usize = 0x10000;
 
+// Where execute() gives control back.
+const HALT: u8 = 0x76;
+
 #[derive(Clone, Copy)]
 pub struct MMUBankRegister {
     bank: u8,
@@ -80,3 +84,137 @@ impl z80::Memory for MMU {
         }
     }
 }
+
+// The RC2014's 512K ROM 512K RAM board: 16K pages, the first 32 of them ROM, with a page register
+//     for each quarter of the address space. Until paging is turned on the first four ROM pages
+//     are seen in order, so a ROM can start at 0.
+pub const PAGE_SIZE: usize = 0x4000;
+pub const PAGES: usize = 64;
+pub const ROM_PAGES: usize = 32;
+
+#[derive(Clone, Copy)]
+pub struct PagingEnable {
+    enabled: bool,
+}
+
+impl IoDevice for PagingEnable {
+    fn read_in(&self) -> u8 {
+        self.enabled as u8
+    }
+    fn write_out(&mut self, value: u8) {
+        self.enabled = value & 1 != 0;
+    }
+}
+
+pub struct PagedMemory {
+    pub page_registers: [MMUBankRegister; 4],
+    pub paging_enable: PagingEnable,
+    pages: Vec<Mutex<[u8; PAGE_SIZE]>>,
+}
+
+impl PagedMemory {
+    pub fn new() -> PagedMemory {
+        let mut pages = Vec::new();
+        for _ in 0..PAGES {
+            pages.push(Mutex::new([0; PAGE_SIZE]));
+        }
+        PagedMemory {
+            page_registers: [MMUBankRegister { bank: 0 }; 4],
+            paging_enable: PagingEnable { enabled: false },
+            pages: pages,
+        }
+    }
+    // Copies an image in from the start of a page, on into the pages after it.
+    pub fn load(&self, page: usize, image: &[u8]) -> Result<(), String> {
+        if page * PAGE_SIZE + image.len() > PAGES * PAGE_SIZE {
+            return Err(format!("{} bytes from page {} run past the last page ({})", image.len(), page, PAGES - 1));
+        }
+        for (offset, chunk) in image.chunks(PAGE_SIZE).enumerate() {
+            let mut memory = match self.pages[page + offset].lock() {
+                Ok(x) => x,
+                Err(err) => panic!("Page {} mutex poisoned: {}", page + offset, err),
+            };
+            memory[..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+    fn page(&self, address: u16) -> usize {
+        let window = (address >> 14) as usize;
+        if self.paging_enable.enabled {
+            self.page_registers[window].bank as usize % PAGES
+        } else {
+            window
+        }
+    }
+}
+
+impl z80::Memory for PagedMemory {
+    fn read_byte(&self, address: u16) -> u8 {
+        let page_num = self.page(address);
+        let page = match self.pages[page_num].lock() {
+            Ok(x) => x,
+            Err(err) => panic!("Page {} mutex poisoned: {}", page_num, err),
+        };
+        page[address as usize % PAGE_SIZE]
+    }
+    fn write_byte(&mut self, address: u16, value: u8) {
+        let page_num = self.page(address);
+        if page_num >= ROM_PAGES {
+            let mut page = match self.pages[page_num].lock() {
+                Ok(x) => x,
+                Err(err) => panic!("Page {} mutex poisoned: {}", page_num, err),
+            };
+            page[address as usize % PAGE_SIZE] = value;
+        }
+    }
+}
+
+// Whichever memory the machine profile has.
+pub enum MemoryMap {
+    Banked(MMU),
+    Paged(PagedMemory),
+}
+
+impl z80::Memory for MemoryMap {
+    fn read_byte(&self, address: u16) -> u8 {
+        match *self {
+            MemoryMap::Banked(ref mmu) => z80::Memory::read_byte(mmu, address),
+            MemoryMap::Paged(ref paged) => z80::Memory::read_byte(paged, address),
+        }
+    }
+    fn write_byte(&mut self, address: u16, value: u8) {
+        match *self {
+            MemoryMap::Banked(ref mut mmu) => z80::Memory::write_byte(mmu, address, value),
+            MemoryMap::Paged(ref mut paged) => z80::Memory::write_byte(paged, address, value),
+        }
+    }
+}
+
+// Memory as the CPU sees it. Once stop is set every read is a HALT, so the CPU comes to rest at the
+//     next instruction and execute() returns.
+pub struct StoppableMemory {
+    pub map: MemoryMap,
+    stop: Arc<AtomicBool>,
+}
+
+impl StoppableMemory {
+    pub fn new(map: MemoryMap, stop: Arc<AtomicBool>) -> StoppableMemory {
+        StoppableMemory {
+            map: map,
+            stop: stop,
+        }
+    }
+}
+
+impl z80::Memory for StoppableMemory {
+    fn read_byte(&self, address: u16) -> u8 {
+        if self.stop.load(Ordering::SeqCst) {
+            HALT
+        } else {
+            z80::Memory::read_byte(&self.map, address)
+        }
+    }
+    fn write_byte(&mut self, address: u16, value: u8) {
+        z80::Memory::write_byte(&mut self.map, address, value)
+    }
+}
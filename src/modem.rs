@@ -0,0 +1,509 @@
+// A Hayes-compatible modem on a serial port, for period communications software.
+//
+// Offline, what the guest sends is read as AT commands. ATDT host:port dials out over TCP, and
+// with a listening address, incoming connections ring and ATA (or S0) answers them. Online, the
+// usual +++ with a second's quiet either side comes back to commands with the call still up, and
+// what the other end sends until ATO is lost.
+
+use console::{ self, ConsoleBackend, ConsoleInput };
+use tty;
+
+use std::collections::VecDeque;
+use std::io::{ self, ErrorKind, Read, Write };
+use std::net::{ TcpListener, TcpStream, ToSocketAddrs };
+use std::os::unix::io::AsRawFd;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+use std::{ cmp, thread };
+
+const POLL_MILLIS: u64 = 10;
+const RING_SECS: u64 = 6;
+// Callers who are never answered give up after this many rings.
+const MAX_RINGS: u8 = 10;
+const DIAL_TIMEOUT_SECS: u64 = 30;
+// Longest command line kept, as on the real thing.
+const MAX_COMMAND: usize = 40;
+// Dialling a bare name gets the telnet port.
+const DEFAULT_PORT: u16 = 23;
+
+const REGISTERS: usize = 32;
+// S-registers with meanings here.
+const S_ANSWER_RINGS: usize = 0;
+const S_RING_COUNT: usize = 1;
+const S_ESCAPE: usize = 2;
+const S_CR: usize = 3;
+const S_LF: usize = 4;
+const S_BS: usize = 5;
+// In fiftieths of a second.
+const S_GUARD: usize = 12;
+
+#[derive(Clone, Copy)]
+enum Response {
+    Ok,
+    Connect,
+    Ring,
+    NoCarrier,
+    Error,
+    Busy,
+}
+
+impl Response {
+    // The word, and the digit ATV0 gives instead.
+    fn codes(self) -> (&'static str, u8) {
+        match self {
+            Response::Ok => ("OK", 0),
+            Response::Connect => ("CONNECT", 1),
+            Response::Ring => ("RING", 2),
+            Response::NoCarrier => ("NO CARRIER", 3),
+            Response::Error => ("ERROR", 4),
+            Response::Busy => ("BUSY", 7),
+        }
+    }
+}
+
+fn default_registers() -> [u8; REGISTERS] {
+    let mut registers = [0u8; REGISTERS];
+    registers[S_ESCAPE] = b'+';
+    registers[S_CR] = b'\r';
+    registers[S_LF] = b'\n';
+    registers[S_BS] = 0x08;
+    registers[S_GUARD] = 50;
+    registers
+}
+
+struct ModemState {
+    registers: [u8; REGISTERS],
+    echo: bool,
+    quiet: bool,
+    verbose: bool,
+    // The command line being typed, and the last one run, for A/.
+    command: Vec<u8>,
+    last_command: Vec<u8>,
+    // Responses and echo waiting for the guest to read them.
+    to_guest: VecDeque<u8>,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    // Passing data; a call can stay up in command mode after +++.
+    online: bool,
+    // A call ringing but not yet answered.
+    incoming: Option<TcpStream>,
+    next_ring: Instant,
+    // Escape detection: when the guest last sent data, and how much of +++ has arrived.
+    last_data: Instant,
+    escapes: usize,
+    last_escape: Instant,
+    carrier: Arc<AtomicBool>,
+}
+
+impl ModemState {
+    fn reset(&mut self) {
+        self.hang_up();
+        self.registers = default_registers();
+        self.echo = true;
+        self.quiet = false;
+        self.verbose = true;
+    }
+    fn guard(&self) -> Duration {
+        Duration::from_millis(self.registers[S_GUARD] as u64 * 20)
+    }
+    fn respond(&mut self, response: Response) {
+        if self.quiet { return; }
+        let (word, digit) = response.codes();
+        let (cr, lf) = (self.registers[S_CR], self.registers[S_LF]);
+        if self.verbose {
+            self.to_guest.extend([cr, lf].iter().cloned());
+            self.to_guest.extend(word.bytes());
+            self.to_guest.extend([cr, lf].iter().cloned());
+        } else {
+            self.to_guest.push_back(b'0' + digit);
+            self.to_guest.push_back(cr);
+        }
+    }
+    // Informational text, as from ATI and ATSn?.
+    fn say(&mut self, text: &str) {
+        let (cr, lf) = (self.registers[S_CR], self.registers[S_LF]);
+        self.to_guest.extend([cr, lf].iter().cloned());
+        self.to_guest.extend(text.bytes());
+    }
+    fn connect(&mut self, stream: TcpStream) -> Response {
+        let _ = stream.set_nodelay(true);
+        self.stream = Some(stream);
+        self.online = true;
+        self.escapes = 0;
+        self.last_data = Instant::now();
+        self.carrier.store(true, Ordering::SeqCst);
+        Response::Connect
+    }
+    fn hang_up(&mut self) {
+        self.stream = None;
+        self.online = false;
+        self.carrier.store(false, Ordering::SeqCst);
+    }
+    fn dial(&mut self, number: &str) -> Response {
+        if self.stream.is_some() {
+            return Response::Error;
+        }
+        let address = if number.contains(':') { number.to_string() } else { format!("{}:{}", number, DEFAULT_PORT) };
+        let addresses = match address.to_socket_addrs() {
+            Ok(x) => x,
+            Err(_) => return Response::NoCarrier,
+        };
+        let mut response = Response::NoCarrier;
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, Duration::from_secs(DIAL_TIMEOUT_SECS)) {
+                Ok(stream) => return self.connect(stream),
+                Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => response = Response::Busy,
+                Err(_) => (),
+            }
+        }
+        response
+    }
+    // Runs what follows AT, stopping early at commands that change mode.
+    fn execute(&mut self, command: &[u8]) -> Response {
+        let command: Vec<u8> = command.iter().map(|byte| byte.to_ascii_uppercase()).collect();
+        let mut i = 0;
+        // A number following a command letter, if there is one.
+        let number = |i: &mut usize| -> Option<usize> {
+            let start = *i;
+            while *i < command.len() && command[*i].is_ascii_digit() { *i += 1; }
+            String::from_utf8_lossy(&command[start..*i]).parse().ok()
+        };
+        while i < command.len() {
+            let letter = command[i];
+            i += 1;
+            match letter {
+                b' ' => (),
+                b'A' => {
+                    return match self.incoming.take() {
+                        Some(stream) if self.stream.is_none() => self.connect(stream),
+                        _ => Response::NoCarrier,
+                    };
+                },
+                b'D' => {
+                    let number: String = String::from_utf8_lossy(&command[i..]).trim().trim_start_matches(&['T', 'P'][..]).trim().to_lowercase();
+                    if number.is_empty() { return Response::Error; }
+                    return self.dial(&number);
+                },
+                b'O' => {
+                    number(&mut i);
+                    if self.stream.is_none() { return Response::NoCarrier; }
+                    self.online = true;
+                    self.escapes = 0;
+                    return Response::Connect;
+                },
+                b'E' => self.echo = number(&mut i).unwrap_or(0) != 0,
+                b'Q' => self.quiet = number(&mut i).unwrap_or(0) != 0,
+                b'V' => self.verbose = number(&mut i).unwrap_or(0) != 0,
+                b'H' => {
+                    number(&mut i);
+                    self.hang_up();
+                },
+                b'Z' => {
+                    number(&mut i);
+                    self.reset();
+                },
+                b'I' => {
+                    number(&mut i);
+                    self.say("Metachronism Hayes-compatible modem");
+                },
+                b'S' => {
+                    let register = match number(&mut i) {
+                        Some(x) if x < REGISTERS => x,
+                        _ => return Response::Error,
+                    };
+                    match command.get(i) {
+                        Some(&b'=') => {
+                            i += 1;
+                            match number(&mut i) {
+                                Some(x) if x <= 0xff => self.registers[register] = x as u8,
+                                _ => return Response::Error,
+                            }
+                        },
+                        Some(&b'?') => {
+                            i += 1;
+                            let value = format!("{:03}", self.registers[register]);
+                            self.say(&value);
+                        },
+                        _ => return Response::Error,
+                    }
+                },
+                // Speaker, result code sets and the like: nothing to do, but software sends them.
+                b'L' | b'M' | b'X' | b'B' | b'C' | b'N' | b'W' | b'Y' => { number(&mut i); },
+                b'&' if i < command.len() => {
+                    i += 1;
+                    number(&mut i);
+                },
+                _ => return Response::Error,
+            }
+        }
+        Response::Ok
+    }
+    // Takes a byte the guest sent while offline.
+    fn command_byte(&mut self, byte: u8) {
+        if self.echo {
+            self.to_guest.push_back(byte);
+        }
+        if byte == self.registers[S_CR] {
+            let line = self.command.split_off(0);
+            if line.len() >= 2 && line[..2].eq_ignore_ascii_case(b"AT") {
+                let response = self.execute(&line[2..]);
+                self.respond(response);
+                self.last_command = line;
+            }
+        } else if byte == self.registers[S_BS] {
+            self.command.pop();
+        } else if byte == self.registers[S_LF] {
+            // Line ends are CR; an LF after it is just tidiness.
+        } else if self.command.len() < MAX_COMMAND {
+            self.command.push(byte);
+            if self.command.eq_ignore_ascii_case(b"A/") {
+                self.command.clear();
+                let line = self.last_command.clone();
+                if line.len() >= 2 {
+                    let response = self.execute(&line[2..]);
+                    self.respond(response);
+                }
+            }
+        }
+    }
+    // Takes a byte the guest sent while online, watching for the escape.
+    fn data_byte(&mut self, byte: u8) -> io::Result<()> {
+        let now = Instant::now();
+        let guard = self.guard();
+        if byte == self.registers[S_ESCAPE] && self.escapes < 3 && (self.escapes > 0 || now - self.last_data >= guard) {
+            self.escapes += 1;
+            self.last_escape = now;
+        } else {
+            self.escapes = 0;
+        }
+        self.last_data = now;
+        match self.stream {
+            Some(ref mut stream) => stream.write_all(&[byte]),
+            None => Ok(()),
+        }
+    }
+    // Keeps time for the escape and for ringing.
+    fn poll(&mut self) {
+        let now = Instant::now();
+        if self.online && self.escapes == 3 && now - self.last_escape >= self.guard() {
+            self.online = false;
+            self.escapes = 0;
+            self.respond(Response::Ok);
+        }
+        if self.incoming.is_none() {
+            let call = match self.listener {
+                Some(ref listener) => listener.accept().ok(),
+                None => None,
+            };
+            if let Some((stream, _)) = call {
+                // Busy: the caller just gets hung up on.
+                if self.stream.is_none() {
+                    let _ = stream.set_nonblocking(false);
+                    self.incoming = Some(stream);
+                    self.registers[S_RING_COUNT] = 0;
+                    self.next_ring = now;
+                }
+            }
+        }
+        if self.incoming.is_some() && now >= self.next_ring {
+            if self.registers[S_RING_COUNT] >= MAX_RINGS {
+                self.incoming = None;
+                return;
+            }
+            self.registers[S_RING_COUNT] += 1;
+            self.next_ring = now + Duration::from_secs(RING_SECS);
+            self.respond(Response::Ring);
+            let rings = self.registers[S_ANSWER_RINGS];
+            if rings > 0 && self.registers[S_RING_COUNT] >= rings {
+                let stream = self.incoming.take().unwrap();
+                let response = self.connect(stream);
+                self.respond(response);
+            }
+        }
+    }
+}
+
+pub struct ModemBackend {
+    state: Arc<Mutex<ModemState>>,
+    carrier: Arc<AtomicBool>,
+    description: String,
+}
+
+impl ModemBackend {
+    // Answers calls to address if there is one.
+    pub fn open(address: Option<&str>) -> io::Result<ModemBackend> {
+        let listener = match address {
+            Some(address) => {
+                let listener = try!(TcpListener::bind(address));
+                try!(listener.set_nonblocking(true));
+                Some(listener)
+            },
+            None => None,
+        };
+        let description = match address {
+            Some(address) => format!("Hayes modem answering on {}", address),
+            None => "Hayes modem".to_string(),
+        };
+        let carrier = Arc::new(AtomicBool::new(false));
+        let now = Instant::now();
+        let state = ModemState {
+            registers: default_registers(),
+            echo: true,
+            quiet: false,
+            verbose: true,
+            command: Vec::new(),
+            last_command: Vec::new(),
+            to_guest: VecDeque::new(),
+            listener: listener,
+            stream: None,
+            online: false,
+            incoming: None,
+            next_ring: now,
+            last_data: now,
+            escapes: 0,
+            last_escape: now,
+            carrier: carrier.clone(),
+        };
+        Ok(ModemBackend {
+            state: Arc::new(Mutex::new(state)),
+            carrier: carrier,
+            description: description,
+        })
+    }
+}
+
+impl ConsoleBackend for ModemBackend {
+    fn describe(&self) -> Option<String> {
+        Some(self.description.clone())
+    }
+    fn carrier(&self) -> Option<Arc<AtomicBool>> {
+        Some(self.carrier.clone())
+    }
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        (Box::new(ModemInput { state: self.state.clone() }), Box::new(ModemOutput { state: self.state }))
+    }
+}
+
+struct ModemInput {
+    state: Arc<Mutex<ModemState>>,
+}
+
+impl ConsoleInput for ModemInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.poll();
+                if !state.to_guest.is_empty() {
+                    let count = cmp::min(buf.len(), state.to_guest.len());
+                    for (slot, byte) in buf[..count].iter_mut().zip(state.to_guest.drain(..count)) {
+                        *slot = byte;
+                    }
+                    return Ok(count);
+                }
+                let online = state.online;
+                let result = match state.stream {
+                    Some(ref mut stream) => {
+                        match tty::wait_readable(stream.as_raw_fd(), Duration::from_millis(0)) {
+                            Ok(true) if online => Some(stream.read(buf)),
+                            // In command mode what arrives is thrown away, but the other end going
+                            //     away still drops the call.
+                            Ok(true) => match stream.read(buf) {
+                                Ok(0) => Some(Ok(0)),
+                                Ok(_) => None,
+                                Err(err) => Some(Err(err)),
+                            },
+                            Ok(false) => None,
+                            Err(err) => Some(Err(err)),
+                        }
+                    },
+                    None => None,
+                };
+                match result {
+                    Some(Ok(0)) | Some(Err(_)) => {
+                        state.hang_up();
+                        state.respond(Response::NoCarrier);
+                        continue;
+                    },
+                    Some(Ok(count)) => return Ok(count),
+                    None => (),
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(console::would_block());
+            }
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(POLL_MILLIS)));
+        }
+    }
+}
+
+struct ModemOutput {
+    state: Arc<Mutex<ModemState>>,
+}
+
+impl Write for ModemOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        for &byte in buf {
+            if state.online {
+                if state.data_byte(byte).is_err() {
+                    state.hang_up();
+                    state.respond(Response::NoCarrier);
+                }
+            } else {
+                state.command_byte(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads from the modem until text turns up, or gives up after a second.
+    fn read_until(input: &mut dyn ConsoleInput, text: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut seen = Vec::new();
+        let mut buf = [0; 64];
+        while Instant::now() < deadline {
+            if let Ok(count) = input.read(&mut buf, Duration::from_millis(POLL_MILLIS)) {
+                seen.extend_from_slice(&buf[..count]);
+            }
+            if String::from_utf8_lossy(&seen).contains(text) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn call_dropped_in_command_mode_hangs_up() {
+        let remote = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend = Box::new(ModemBackend::open(None).unwrap());
+        let state = backend.state.clone();
+        let carrier = backend.carrier.clone();
+        let (mut input, mut output) = backend.split();
+        write!(output, "ATD127.0.0.1:{}\r", remote.local_addr().unwrap().port()).unwrap();
+        let (mut far, _) = remote.accept().unwrap();
+        assert!(read_until(&mut *input, "CONNECT"));
+        // As after +++.
+        state.lock().unwrap().online = false;
+        // What arrives meanwhile is lost, but the call stays up.
+        far.write_all(b"hello").unwrap();
+        assert!(!read_until(&mut *input, "hello"));
+        assert!(carrier.load(Ordering::SeqCst));
+        far.write_all(b"bye").unwrap();
+        drop(far);
+        assert!(read_until(&mut *input, "NO CARRIER"));
+        assert!(!carrier.load(Ordering::SeqCst));
+        assert!(state.lock().unwrap().stream.is_none());
+    }
+}
@@ -0,0 +1,331 @@
+use super::ConcurrentDevice;
+
+use z80e_core_rust::IoDevice;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::process::{ Child, Command, Stdio };
+use std::str::FromStr;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+
+// Status port bits.
+const READY: u8 = 1 << 0;
+const ERROR: u8 = 1 << 7;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const FF: u8 = 0x0c;
+
+// How much the guest can get ahead of a slow printer before it has to wait.
+const BUF_LENGTH: usize = 0x100;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Newline {
+    // Whatever the guest sends.
+    Raw,
+    // CR LF becomes LF, for host text files.
+    Lf,
+    // A bare LF becomes CR LF.
+    CrLf,
+}
+
+#[derive(Clone)]
+enum Target {
+    Discard,
+    File(PathBuf),
+    // Run through sh -c with PAGE set to the page number.
+    Pipe(String),
+}
+
+#[derive(Clone)]
+pub struct PrinterConfig {
+    target: Target,
+    // Start a new file or command at every form feed.
+    pages: bool,
+    newline: Newline,
+}
+
+impl PrinterConfig {
+    pub fn discard() -> PrinterConfig {
+        PrinterConfig {
+            target: Target::Discard,
+            pages: false,
+            newline: Newline::Raw,
+        }
+    }
+    pub fn describe(&self) -> String {
+        match self.target {
+            Target::Discard => "discarded".to_string(),
+            Target::File(ref path) if self.pages => format!("{} (a file per page)", page_path(path, 1).display()),
+            Target::File(ref path) => path.display().to_string(),
+            Target::Pipe(ref command) => format!("|{}", command),
+        }
+    }
+}
+
+impl FromStr for PrinterConfig {
+    type Err = String;
+    // path or |command, then any of ,pages ,newline=raw|lf|crlf
+    fn from_str(s: &str) -> Result<PrinterConfig, String> {
+        let mut fields = s.split(',');
+        let target = match fields.next() {
+            Some(x) if x.starts_with('|') && x.len() > 1 => Target::Pipe(x[1..].to_string()),
+            Some(x) if !x.is_empty() => Target::File(PathBuf::from(x)),
+            _ => return Err(format!("Expected path or |command: {}", s)),
+        };
+        let mut config = PrinterConfig {
+            target: target,
+            pages: false,
+            newline: Newline::Raw,
+        };
+        for field in fields {
+            match field {
+                "pages" => config.pages = true,
+                "newline=raw" => config.newline = Newline::Raw,
+                "newline=lf" => config.newline = Newline::Lf,
+                "newline=crlf" => config.newline = Newline::CrLf,
+                _ => return Err(format!("Expected pages or newline=raw|lf|crlf: {}", field)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+// out.txt becomes out-001.txt.
+fn page_path(path: &Path, page: usize) -> PathBuf {
+    let stem = path.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{:03}.{}", stem, page, extension.to_string_lossy()),
+        None => format!("{}-{:03}", stem, page),
+    };
+    path.with_file_name(name)
+}
+
+// Where the current page is going.
+enum Sink {
+    Discard,
+    File(File),
+    Pipe(Child),
+}
+
+impl Sink {
+    fn open(config: &PrinterConfig, page: usize) -> io::Result<Sink> {
+        match config.target {
+            Target::Discard => Ok(Sink::Discard),
+            Target::File(ref path) => {
+                let path = if config.pages { page_path(path, page) } else { path.clone() };
+                Ok(Sink::File(try!(File::create(path))))
+            },
+            Target::Pipe(ref command) => {
+                let child = try!(Command::new("sh").arg("-c").arg(command)
+                                 .env("PAGE", page.to_string())
+                                 .stdin(Stdio::piped())
+                                 .spawn());
+                Ok(Sink::Pipe(child))
+            },
+        }
+    }
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match *self {
+            Sink::Discard => Ok(()),
+            Sink::File(ref mut file) => file.write_all(bytes),
+            Sink::Pipe(ref mut child) => child.stdin.as_mut().unwrap().write_all(bytes),
+        }
+    }
+    fn close(self) -> io::Result<()> {
+        match self {
+            Sink::Discard => Ok(()),
+            Sink::File(mut file) => file.flush(),
+            Sink::Pipe(mut child) => {
+                drop(child.stdin.take());
+                let status = try!(child.wait());
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::Other, format!("Print command failed: {}", status)))
+                }
+            },
+        }
+    }
+}
+
+struct PrinterState {
+    buffer: Mutex<VecDeque<u8>>,
+    // Signalled when the guest adds to the buffer.
+    changed: Condvar,
+    error: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Printer {
+    config: PrinterConfig,
+    state: Arc<PrinterState>,
+}
+
+impl Printer {
+    pub fn new(config: PrinterConfig) -> Printer {
+        Printer {
+            config: config,
+            state: Arc::new(PrinterState {
+                buffer: Mutex::new(VecDeque::new()),
+                changed: Condvar::new(),
+                error: AtomicBool::new(false),
+            }),
+        }
+    }
+    pub fn status_port(&self) -> PrinterStatus {
+        PrinterStatus { printer: self.clone() }
+    }
+    pub fn data_port(&self) -> PrinterData {
+        PrinterData { printer: self.clone() }
+    }
+    fn fail(&self, err: io::Error) {
+        if !self.state.error.swap(true, Ordering::SeqCst) {
+            let _ = writeln!(io::stderr(), "printer: {}", err);
+        }
+    }
+}
+
+pub struct PrinterStatus {
+    printer: Printer,
+}
+
+impl IoDevice for PrinterStatus {
+    fn read_in(&self) -> u8 {
+        let mut status = 0;
+        if self.printer.state.buffer.lock().unwrap().len() < BUF_LENGTH {
+            status |= READY;
+        }
+        if self.printer.state.error.load(Ordering::SeqCst) {
+            status |= ERROR;
+        }
+        status
+    }
+    fn write_out(&mut self, value: u8) {
+        let _ = value;
+    }
+}
+
+pub struct PrinterData {
+    printer: Printer,
+}
+
+impl IoDevice for PrinterData {
+    fn read_in(&self) -> u8 {
+        0
+    }
+    fn write_out(&mut self, value: u8) {
+        let mut buffer = self.printer.state.buffer.lock().unwrap();
+        if buffer.len() >= BUF_LENGTH {
+            let _ = writeln!(io::stderr(), "Printer written to when not ready.");
+            return;
+        }
+        buffer.push_back(value);
+        self.printer.state.changed.notify_all();
+    }
+}
+
+// Rewrites line endings a byte at a time.
+struct Converter {
+    newline: Newline,
+    pending_cr: bool,
+}
+
+impl Converter {
+    fn convert(&mut self, byte: u8, out: &mut Vec<u8>) {
+        match self.newline {
+            Newline::Raw => out.push(byte),
+            Newline::Lf => {
+                if self.pending_cr && byte != LF {
+                    out.push(CR);
+                }
+                if byte != CR {
+                    out.push(byte);
+                }
+            },
+            Newline::CrLf => {
+                if byte == LF && !self.pending_cr {
+                    out.push(CR);
+                }
+                out.push(byte);
+            },
+        }
+        self.pending_cr = byte == CR;
+    }
+    // Lets go of a CR held back in case an LF followed.
+    fn flush(&mut self, out: &mut Vec<u8>) {
+        if self.pending_cr && self.newline == Newline::Lf {
+            out.push(CR);
+        }
+        self.pending_cr = false;
+    }
+}
+
+impl ConcurrentDevice for Printer {
+    fn run(&mut self, die: Arc<AtomicBool>, timeout: Duration) {
+        let mut converter = Converter {
+            newline: self.config.newline,
+            pending_cr: false,
+        };
+        let mut page = 0;
+        let mut sink: Option<Sink> = None;
+        loop {
+            let bytes: Vec<u8> = {
+                let mut buffer = self.state.buffer.lock().unwrap();
+                while buffer.is_empty() {
+                    if die.load(Ordering::Acquire) { break; }
+                    buffer = self.state.changed.wait_timeout(buffer, timeout).unwrap().0;
+                }
+                buffer.drain(..).collect()
+            };
+            if bytes.is_empty() { break; }
+            let mut out = Vec::with_capacity(bytes.len());
+            for &byte in bytes.iter() {
+                if self.config.pages && byte == FF {
+                    self.end_page(&mut converter, &mut page, &mut sink, &mut out);
+                } else {
+                    converter.convert(byte, &mut out);
+                }
+            }
+            self.emit(&mut page, &mut sink, &mut out);
+        }
+        let mut out = Vec::new();
+        self.end_page(&mut converter, &mut page, &mut sink, &mut out);
+    }
+}
+
+impl Printer {
+    // Sends out to the current page, starting one if need be.
+    fn emit(&self, page: &mut usize, sink: &mut Option<Sink>, out: &mut Vec<u8>) {
+        if out.is_empty() { return; }
+        if sink.is_none() {
+            *page += 1;
+            *sink = Some(match Sink::open(&self.config, *page) {
+                Ok(x) => x,
+                Err(err) => {
+                    self.fail(err);
+                    Sink::Discard
+                },
+            });
+        }
+        if let Some(ref mut sink) = *sink {
+            if let Err(err) = sink.write_all(out) {
+                self.fail(err);
+            }
+        }
+        out.clear();
+    }
+    fn end_page(&self, converter: &mut Converter, page: &mut usize, sink: &mut Option<Sink>, out: &mut Vec<u8>) {
+        converter.flush(out);
+        self.emit(page, sink, out);
+        if let Some(sink) = sink.take() {
+            if let Err(err) = sink.close() {
+                self.fail(err);
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use console::{ ConsoleBackend, ConsoleInput };
+use tty;
+
+use libc;
+
+use std::ffi::CStr;
+use std::fs::{ self, File };
+use std::io::{ self, ErrorKind, Read, Write };
+use std::mem;
+use std::os::unix::fs::symlink;
+use std::os::unix::io::{ AsRawFd, FromRawFd };
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+// A pseudo-terminal whose slave end behaves like the machine's serial line.
+pub struct PtyBackend {
+    master: File,
+    // Held open so the line survives terminal programs coming and going.
+    slave: File,
+    path: PathBuf,
+    link: Option<PathBuf>,
+}
+
+impl PtyBackend {
+    // link, if given, is a symlink to the slave for a name that doesn't change between runs.
+    pub fn open(link: Option<&Path>) -> io::Result<PtyBackend> {
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            File::from_raw_fd(fd)
+        };
+        let path = unsafe {
+            if libc::grantpt(master.as_raw_fd()) != 0 || libc::unlockpt(master.as_raw_fd()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(master.as_raw_fd());
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            PathBuf::from(try!(CStr::from_ptr(name).to_str().map_err(|err| {
+                io::Error::new(ErrorKind::InvalidData, err)
+            })))
+        };
+        let slave = try!(fs::OpenOptions::new().read(true).write(true).open(&path));
+        // Terminal programs get the bytes exactly as the guest sent them.
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Output nobody reads is dropped rather than stalling the guest.
+            let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
+            if flags < 0 || libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(link) = link {
+            if fs::symlink_metadata(link).map(|x| x.file_type().is_symlink()).unwrap_or(false) {
+                try!(fs::remove_file(link));
+            }
+            try!(symlink(&path, link));
+        }
+        Ok(PtyBackend {
+            master: master,
+            slave: slave,
+            path: path,
+            link: link.map(|link| link.to_path_buf()),
+        })
+    }
+}
+
+impl ConsoleBackend for PtyBackend {
+    fn describe(&self) -> Option<String> {
+        Some(match self.link {
+            Some(ref link) => format!("pseudo-terminal {} ({})", self.path.display(), link.display()),
+            None => format!("pseudo-terminal {}", self.path.display()),
+        })
+    }
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let backend = *self;
+        let output = match backend.master.try_clone() {
+            Ok(x) => x,
+            Err(err) => panic!("Unable to duplicate pseudo-terminal: {}", err),
+        };
+        let input = PtyInput {
+            master: backend.master,
+            _slave: backend.slave,
+            link: backend.link,
+        };
+        (Box::new(input), Box::new(PtyOutput { master: output }))
+    }
+}
+
+struct PtyInput {
+    master: File,
+    _slave: File,
+    link: Option<PathBuf>,
+}
+
+impl Drop for PtyInput {
+    fn drop(&mut self) {
+        if let Some(ref link) = self.link {
+            let _ = fs::remove_file(link);
+        }
+    }
+}
+
+impl ConsoleInput for PtyInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if !try!(tty::wait_readable(self.master.as_raw_fd(), timeout)) {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "No console input."));
+        }
+        match self.master.read(buf) {
+            // We hold the slave open, so there's always another reader coming.
+            Ok(0) => Err(io::Error::new(ErrorKind::WouldBlock, "No console input.")),
+            result => result,
+        }
+    }
+}
+
+struct PtyOutput {
+    master: File,
+}
+
+impl Write for PtyOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
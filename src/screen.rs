@@ -0,0 +1,368 @@
+// What a terminal attached to the console would be showing, so tests can look at the screen
+// instead of picking through escape sequences.
+
+use terminal::{ Attribute, Decoder, Op, Terminal };
+
+use std::io::{ self, Write };
+use std::sync::{ Arc, Mutex };
+
+pub const ROWS: usize = 24;
+pub const COLUMNS: usize = 80;
+
+const TAB_STOP: usize = 8;
+
+const REVERSE: u8 = 1 << 0;
+const DIM: u8 = 1 << 1;
+const BLINK: u8 = 1 << 2;
+const UNDERLINE: u8 = 1 << 3;
+
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    byte: u8,
+    attributes: u8,
+}
+
+const BLANK: Cell = Cell {
+    byte: b' ',
+    attributes: 0,
+};
+
+pub struct Screen {
+    terminal: Terminal,
+    decoder: Decoder,
+    cells: Vec<[Cell; COLUMNS]>,
+    row: usize,
+    column: usize,
+    attributes: u8,
+    cursor_visible: bool,
+    saved: (usize, usize, u8),
+    // ANSI terminals hold off wrapping until the next character, so a full last column doesn't scroll.
+    pending_wrap: bool,
+}
+
+impl Screen {
+    pub fn new(terminal: Terminal) -> Screen {
+        Screen {
+            terminal: terminal,
+            decoder: Decoder::new(terminal),
+            cells: vec![[BLANK; COLUMNS]; ROWS],
+            row: 0,
+            column: 0,
+            attributes: 0,
+            cursor_visible: true,
+            saved: (0, 0, 0),
+            pending_wrap: false,
+        }
+    }
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let mut ops = Vec::with_capacity(bytes.len());
+        self.decoder.decode(bytes, &mut ops);
+        for op in ops {
+            self.apply(op);
+        }
+    }
+    fn apply(&mut self, op: Op) {
+        match op {
+            Op::Print(_) => (),
+            _ => self.pending_wrap = false,
+        }
+        match op {
+            Op::Print(byte) => self.print(byte),
+            Op::CarriageReturn => self.column = 0,
+            Op::LineFeed => self.line_feed(),
+            Op::ReverseLineFeed => {
+                if self.row == 0 {
+                    self.cells.pop();
+                    self.cells.insert(0, [BLANK; COLUMNS]);
+                } else {
+                    self.row -= 1;
+                }
+            },
+            Op::Backspace | Op::Left => self.column = self.column.saturating_sub(1),
+            Op::Tab => self.column = ((self.column / TAB_STOP + 1) * TAB_STOP).min(COLUMNS - 1),
+            Op::Bell => (),
+            Op::Goto(row, column) => {
+                self.row = (row as usize).min(ROWS - 1);
+                self.column = (column as usize).min(COLUMNS - 1);
+            },
+            Op::Up => self.row = self.row.saturating_sub(1),
+            Op::Down => self.row = (self.row + 1).min(ROWS - 1),
+            Op::Right => self.column = (self.column + 1).min(COLUMNS - 1),
+            Op::Home => {
+                self.row = 0;
+                self.column = 0;
+            },
+            Op::ClearScreen => {
+                for row in self.cells.iter_mut() {
+                    *row = [BLANK; COLUMNS];
+                }
+                self.row = 0;
+                self.column = 0;
+            },
+            Op::ClearToEndOfLine => {
+                for cell in self.cells[self.row][self.column..].iter_mut() {
+                    *cell = BLANK;
+                }
+            },
+            Op::ClearToEndOfScreen => {
+                for cell in self.cells[self.row][self.column..].iter_mut() {
+                    *cell = BLANK;
+                }
+                for row in self.cells[self.row + 1..].iter_mut() {
+                    *row = [BLANK; COLUMNS];
+                }
+            },
+            Op::InsertLine => {
+                self.cells.pop();
+                self.cells.insert(self.row, [BLANK; COLUMNS]);
+            },
+            Op::DeleteLine => {
+                self.cells.remove(self.row);
+                self.cells.push([BLANK; COLUMNS]);
+            },
+            Op::Attribute(attribute, on) => {
+                let bit = match attribute {
+                    Attribute::Reverse => REVERSE,
+                    Attribute::Dim => DIM,
+                    Attribute::Blink => BLINK,
+                    Attribute::Underline => UNDERLINE,
+                };
+                if on { self.attributes |= bit; } else { self.attributes &= !bit; }
+            },
+            Op::ShowCursor(visible) => self.cursor_visible = visible,
+            Op::SaveCursor => self.saved = (self.row, self.column, self.attributes),
+            Op::RestoreCursor => {
+                let (row, column, attributes) = self.saved;
+                self.row = row;
+                self.column = column;
+                self.attributes = attributes;
+            },
+        }
+    }
+    fn print(&mut self, byte: u8) {
+        if self.pending_wrap {
+            self.pending_wrap = false;
+            self.column = 0;
+            self.line_feed();
+        }
+        self.cells[self.row][self.column] = Cell {
+            byte: byte,
+            attributes: self.attributes,
+        };
+        if self.column < COLUMNS - 1 {
+            self.column += 1;
+            return;
+        }
+        match self.terminal {
+            // The VT52 just keeps overwriting the last column.
+            Terminal::Vt52 => (),
+            Terminal::Ansi => self.pending_wrap = true,
+            Terminal::Adm3a | Terminal::Kaypro => {
+                self.column = 0;
+                self.line_feed();
+            },
+        }
+    }
+    fn line_feed(&mut self) {
+        if self.row == ROWS - 1 {
+            self.cells.remove(0);
+            self.cells.push([BLANK; COLUMNS]);
+        } else {
+            self.row += 1;
+        }
+    }
+    // The screen as text, one line per row, trailing blanks trimmed.
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(ROWS * (COLUMNS + 1));
+        for row in self.cells.iter() {
+            let line: String = row.iter().map(|cell| printable(cell.byte)).collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+    // A snapshot for people: the text in a frame, optionally with a line under each row marking
+    //     attributes (R reverse, D dim, B blink, U underline, * several), then the cursor.
+    pub fn dump(&self, out: &mut dyn Write, attributes: bool) -> io::Result<()> {
+        let rule: String = (0..COLUMNS).map(|_| '-').collect();
+        try!(writeln!(out, "+{}+", rule));
+        for row in self.cells.iter() {
+            let line: String = row.iter().map(|cell| printable(cell.byte)).collect();
+            try!(writeln!(out, "|{}|", line));
+            if attributes && row.iter().any(|cell| cell.attributes != 0) {
+                let marks: String = row.iter().map(|cell| match cell.attributes {
+                    0 => ' ',
+                    REVERSE => 'R',
+                    DIM => 'D',
+                    BLINK => 'B',
+                    UNDERLINE => 'U',
+                    _ => '*',
+                }).collect();
+                try!(writeln!(out, ":{}:", marks));
+            }
+        }
+        try!(writeln!(out, "+{}+", rule));
+        writeln!(out, "cursor {},{}{}", self.row, self.column, if self.cursor_visible { "" } else { " (hidden)" })
+    }
+}
+
+fn printable(byte: u8) -> char {
+    match byte {
+        0x20..=0x7e => byte as char,
+        _ => '?',
+    }
+}
+
+// Passes output along after showing it to the screen.
+pub struct ScreenOutput {
+    inner: Box<dyn Write + Send>,
+    screen: Arc<Mutex<Screen>>,
+}
+
+impl ScreenOutput {
+    pub fn new(screen: Arc<Mutex<Screen>>, inner: Box<dyn Write + Send>) -> ScreenOutput {
+        ScreenOutput {
+            inner: inner,
+            screen: screen,
+        }
+    }
+}
+
+impl Write for ScreenOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.screen.lock().unwrap().feed(buf);
+        try!(self.inner.write_all(buf));
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(terminal: Terminal, bytes: &[u8]) -> Screen {
+        let mut screen = Screen::new(terminal);
+        screen.feed(bytes);
+        screen
+    }
+
+    fn lines(screen: &Screen) -> Vec<String> {
+        screen.text().lines().map(|line| line.to_string()).collect()
+    }
+
+    fn screen_with_rows(terminal: Terminal, rows: &[&str]) -> Screen {
+        let mut screen = Screen::new(terminal);
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                screen.feed(b"\r\n");
+            }
+            screen.feed(row.as_bytes());
+        }
+        screen
+    }
+
+    fn full_line(byte: u8) -> Vec<u8> {
+        vec![byte; COLUMNS]
+    }
+
+    #[test]
+    fn ansi_holds_the_wrap_until_the_next_character() {
+        let mut screen = screen(Terminal::Ansi, &full_line(b'a'));
+        assert_eq!((screen.row, screen.column), (0, COLUMNS - 1));
+        // A CR LF after a full line mustn't leave a blank line behind.
+        screen.feed(b"\r\nb");
+        assert_eq!(lines(&screen)[..3], [String::from_utf8(full_line(b'a')).unwrap(), "b".to_string(), String::new()]);
+        screen.feed(&full_line(b'c')[1..]);
+        screen.feed(b"d");
+        assert_eq!(lines(&screen)[2], "d");
+        assert_eq!((screen.row, screen.column), (2, 1));
+    }
+
+    #[test]
+    fn vt52_overwrites_the_last_column() {
+        let mut screen = screen(Terminal::Vt52, &full_line(b'a'));
+        screen.feed(b"bc");
+        let line = lines(&screen)[0].clone();
+        assert_eq!(line.len(), COLUMNS);
+        assert!(line.ends_with("ac"));
+        assert_eq!((screen.row, screen.column), (0, COLUMNS - 1));
+        assert_eq!(lines(&screen)[1], "");
+    }
+
+    #[test]
+    fn adm3a_and_kaypro_wrap_straight_away() {
+        for &terminal in [Terminal::Adm3a, Terminal::Kaypro].iter() {
+            let screen = screen(terminal, &full_line(b'a'));
+            assert_eq!((screen.row, screen.column), (1, 0));
+        }
+    }
+
+    #[test]
+    fn line_feed_on_the_last_row_scrolls() {
+        let mut screen = Screen::new(Terminal::Adm3a);
+        for i in 0..ROWS + 1 {
+            screen.feed(format!("{}\r\n", i).as_bytes());
+        }
+        let text = lines(&screen);
+        assert_eq!(text[0], "2");
+        assert_eq!(text[ROWS - 2], ROWS.to_string());
+        assert_eq!(text[ROWS - 1], "");
+        assert_eq!((screen.row, screen.column), (ROWS - 1, 0));
+        // A reverse line feed on the top row scrolls the other way.
+        screen.feed(b"\x1e\x1bM");
+        assert_eq!(lines(&screen)[..2], ["2".to_string(), "3".to_string()]);
+        let mut screen = screen_with_rows(Terminal::Ansi, &["x", "y"]);
+        screen.feed(b"\x1b[H\x1bM");
+        assert_eq!(lines(&screen)[..3], ["".to_string(), "x".to_string(), "y".to_string()]);
+    }
+
+    #[test]
+    fn insert_and_delete_line_move_the_rows_below() {
+        let mut screen = screen_with_rows(Terminal::Ansi, &["a", "b", "c"]);
+        screen.feed(b"\x1b[2H\x1b[L");
+        assert_eq!(lines(&screen)[..4], ["a", "", "b", "c"]);
+        screen.feed(b"\x1b[2M");
+        assert_eq!(lines(&screen)[..3], ["a", "c", ""]);
+        // Kaypro spells them ESC E and ESC R.
+        let mut screen = screen_with_rows(Terminal::Kaypro, &["a", "b"]);
+        screen.feed(b"\x1e\x1bE");
+        assert_eq!(lines(&screen)[..3], ["", "a", "b"]);
+        screen.feed(b"\x1bR\x1bR");
+        assert_eq!(lines(&screen)[..2], ["b", ""]);
+        assert_eq!(lines(&screen).len(), ROWS);
+    }
+
+    #[test]
+    fn text_trims_blanks_and_marks_unprintable_bytes() {
+        let screen = screen(Terminal::Ansi, b"hi   \r\n\x80x\r\n   ");
+        let mut expected = "hi\n?x\n".to_string();
+        for _ in 2..ROWS {
+            expected.push('\n');
+        }
+        assert_eq!(screen.text(), expected);
+    }
+
+    #[test]
+    fn dump_frames_the_screen_and_marks_attributes() {
+        let screen = screen(Terminal::Ansi, b"\x1b[7mR\x1b[0m \x1b[4;5mX\x1b[0m\x1b[?25l");
+        let mut out = Vec::new();
+        screen.dump(&mut out, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let out: Vec<&str> = out.lines().collect();
+        let rule = format!("+{}+", "-".repeat(COLUMNS));
+        assert_eq!(out.len(), ROWS + 4);
+        assert_eq!(out[0], rule);
+        assert_eq!(out[1], format!("|R X{}|", " ".repeat(COLUMNS - 3)));
+        assert_eq!(out[2], format!(":R *{}:", " ".repeat(COLUMNS - 3)));
+        assert_eq!(out[3], format!("|{}|", " ".repeat(COLUMNS)));
+        assert_eq!(out[ROWS + 2], rule);
+        assert_eq!(out[ROWS + 3], "cursor 0,3 (hidden)");
+        // Without attributes it's just the frame.
+        let mut plain = Vec::new();
+        screen.dump(&mut plain, false).unwrap();
+        assert_eq!(String::from_utf8(plain).unwrap().lines().count(), ROWS + 3);
+    }
+}
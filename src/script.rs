@@ -0,0 +1,334 @@
+// Drives the console from a file instead of a person, for unattended test runs.
+//
+// One command per line; blank lines and lines starting with # are ignored.
+//     expect REGEX    Wait until guest output matches, then forget everything up to the match.
+//     screen REGEX    Wait until the screen, as lines of text, matches.
+//     snapshot FILE   Write a picture of the screen to FILE.
+//     send TEXT       Type TEXT. \r, \n, \t, \e, \\ and \xHH are escapes; nothing else is trimmed.
+//     pace MILLIS     Pause before each character sent from here on.
+//     timeout SECS    How long later expects may wait before the run fails.
+//     sleep MILLIS    Do nothing for a while.
+//     quit            Stop the machine successfully.
+// An expect or screen that times out, or a snapshot that can't be written, stops the machine
+// with a nonzero exit status.
+
+use console::{ self, ConsoleBackend, ConsoleInput };
+use host::Host;
+
+use regex::bytes::Regex;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ self, ErrorKind, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::time::{ Duration, Instant };
+use std::{ cmp, thread };
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+// Output older than this can't take part in a match.
+const TRANSCRIPT_LIMIT: usize = 0x10000;
+// How much output to show when an expect fails.
+const FAILURE_CONTEXT: usize = 0x200;
+const FAILURE_STATUS: i32 = 1;
+
+enum Command {
+    Expect(Regex),
+    Screen(Regex),
+    Snapshot(PathBuf),
+    Send(Vec<u8>),
+    Pace(Duration),
+    Timeout(Duration),
+    Sleep(Duration),
+    Quit,
+}
+
+struct Step {
+    line: usize,
+    command: Command,
+}
+
+pub struct ScriptBackend {
+    steps: Vec<Step>,
+    host: Host,
+}
+
+impl ScriptBackend {
+    pub fn load<T: AsRef<Path>>(path: &T, host: &Host) -> io::Result<ScriptBackend> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        let mut steps = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            match parse_command(line) {
+                Ok(Some(command)) => steps.push(Step {
+                    line: i + 1,
+                    command: command,
+                }),
+                Ok(None) => (),
+                Err(err) => {
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", path.as_ref().display(), i + 1, err)));
+                },
+            }
+        }
+        Ok(ScriptBackend {
+            steps: steps,
+            host: host.clone(),
+        })
+    }
+}
+
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    if line.trim().is_empty() || line.trim_start().starts_with('#') {
+        return Ok(None);
+    }
+    let line = line.trim_start();
+    let (word, argument) = match line.find(' ') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+    };
+    let number = || argument.trim().parse::<u64>().map_err(|err| format!("{} ← {}", argument, err));
+    Ok(Some(match word {
+        "expect" => Command::Expect(try!(Regex::new(argument).map_err(|err| err.to_string()))),
+        "screen" => Command::Screen(try!(Regex::new(argument).map_err(|err| err.to_string()))),
+        "snapshot" if !argument.is_empty() => Command::Snapshot(PathBuf::from(argument)),
+        "send" => Command::Send(try!(unescape(argument))),
+        "pace" => Command::Pace(Duration::from_millis(try!(number()))),
+        "timeout" => Command::Timeout(Duration::from_secs(try!(number()))),
+        "sleep" => Command::Sleep(Duration::from_millis(try!(number()))),
+        "quit" => Command::Quit,
+        _ => return Err(format!("Unknown command: {}", word)),
+    }))
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('e') => bytes.push(0x1b),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) if digits.len() == 2 => bytes.push(byte),
+                    _ => return Err(format!("Bad escape: \\x{}", digits)),
+                }
+            },
+            Some(c) => return Err(format!("Bad escape: \\{}", c)),
+            None => return Err("Trailing backslash.".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+// Guest output not yet consumed by an expect.
+struct Transcript {
+    bytes: Mutex<Vec<u8>>,
+    changed: Condvar,
+}
+
+impl ConsoleBackend for ScriptBackend {
+    fn describe(&self) -> Option<String> {
+        Some(format!("script, {} steps", self.steps.len()))
+    }
+    fn split(self: Box<Self>) -> (Box<dyn ConsoleInput>, Box<dyn Write + Send>) {
+        let backend = *self;
+        let transcript = Arc::new(Transcript {
+            bytes: Mutex::new(Vec::new()),
+            changed: Condvar::new(),
+        });
+        let input = ScriptInput {
+            steps: backend.steps.into(),
+            host: backend.host,
+            transcript: transcript.clone(),
+            typing: VecDeque::new(),
+            pace: Duration::from_millis(0),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            deadline: None,
+        };
+        let output = ScriptOutput {
+            transcript: transcript,
+            echo: io::stdout(),
+        };
+        (Box::new(input), Box::new(output))
+    }
+}
+
+struct ScriptInput {
+    steps: VecDeque<Step>,
+    host: Host,
+    transcript: Arc<Transcript>,
+    // What's left of the current send.
+    typing: VecDeque<u8>,
+    pace: Duration,
+    timeout: Duration,
+    // When the current expect gives up or the current sleep ends.
+    deadline: Option<Instant>,
+}
+
+impl ScriptInput {
+    // Asks the host to stop the machine and drops the rest of the script; the machine winds down
+    //     in its own time, so there's nothing more to type meanwhile.
+    fn stop(&mut self, status: i32) -> io::Error {
+        self.host.exit(status);
+        self.steps.clear();
+        self.typing.clear();
+        console::would_block()
+    }
+    fn fail(&mut self, line: usize, message: String) -> io::Error {
+        let _ = writeln!(io::stderr(), "\nscript: Line {}: {}", line, message);
+        self.stop(FAILURE_STATUS)
+    }
+    fn fail_expect(&mut self, line: usize, pattern: &Regex) -> io::Error {
+        let message = {
+            let transcript = self.transcript.bytes.lock().unwrap();
+            let context = &transcript[transcript.len() - cmp::min(transcript.len(), FAILURE_CONTEXT)..];
+            format!("Timed out waiting for /{}/. Last output:\n{:?}", pattern, String::from_utf8_lossy(context))
+        };
+        self.fail(line, message)
+    }
+    fn fail_screen(&mut self, line: usize, pattern: &Regex) -> io::Error {
+        let mut picture = Vec::new();
+        let _ = self.host.screen().lock().unwrap().dump(&mut picture, false);
+        self.fail(line, format!("Timed out waiting for /{}/ on screen:\n{}", pattern, String::from_utf8_lossy(&picture)))
+    }
+    // Waits for more output, or until the current deadline passes.
+    fn wait_output(&self, remaining: Duration, timeout: Duration) {
+        let transcript = self.transcript.bytes.lock().unwrap();
+        let _ = self.transcript.changed.wait_timeout(transcript, cmp::min(remaining, timeout)).unwrap();
+    }
+    // Whether we're done waiting, or how much longer to wait.
+    fn remaining(&mut self, length: Duration) -> Option<Duration> {
+        let deadline = *self.deadline.get_or_insert_with(|| Instant::now() + length);
+        let now = Instant::now();
+        if now >= deadline {
+            self.deadline = None;
+            None
+        } else {
+            Some(deadline - now)
+        }
+    }
+}
+
+impl ConsoleInput for ScriptInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        loop {
+            if let Some(byte) = self.typing.pop_front() {
+                if self.pace > Duration::from_millis(0) {
+                    thread::sleep(self.pace);
+                    buf[0] = byte;
+                    return Ok(1);
+                }
+                let count = cmp::min(buf.len(), self.typing.len() + 1);
+                buf[0] = byte;
+                for (slot, byte) in buf[1..count].iter_mut().zip(self.typing.drain(..count - 1)) {
+                    *slot = byte;
+                }
+                return Ok(count);
+            }
+            let step = match self.steps.pop_front() {
+                Some(x) => x,
+                // Nothing more to type; the guest carries on until something else stops it.
+                None => return Ok(0),
+            };
+            match step.command {
+                Command::Expect(ref pattern) => {
+                    let mut transcript = self.transcript.bytes.lock().unwrap();
+                    if let Some(found) = pattern.find(&transcript) {
+                        let end = found.end();
+                        transcript.drain(..end);
+                        self.deadline = None;
+                        continue;
+                    }
+                    drop(transcript);
+                    let length = self.timeout;
+                    let remaining = match self.remaining(length) {
+                        Some(x) => x,
+                        None => return Err(self.fail_expect(step.line, pattern)),
+                    };
+                    self.wait_output(remaining, timeout);
+                },
+                Command::Screen(ref pattern) => {
+                    let text = self.host.screen().lock().unwrap().text();
+                    if pattern.is_match(text.as_bytes()) {
+                        self.deadline = None;
+                        continue;
+                    }
+                    let length = self.timeout;
+                    let remaining = match self.remaining(length) {
+                        Some(x) => x,
+                        None => return Err(self.fail_screen(step.line, pattern)),
+                    };
+                    self.wait_output(remaining, timeout);
+                },
+                Command::Snapshot(ref path) => {
+                    let result = File::create(path).and_then(|mut file| {
+                        self.host.screen().lock().unwrap().dump(&mut file, true)
+                    });
+                    if let Err(err) = result {
+                        return Err(self.fail(step.line, format!("Unable to write snapshot: {} → {}", path.display(), err)));
+                    }
+                    continue;
+                },
+                Command::Sleep(length) => {
+                    if let Some(remaining) = self.remaining(length) {
+                        thread::sleep(cmp::min(remaining, timeout));
+                    } else {
+                        continue;
+                    }
+                },
+                Command::Send(ref bytes) => {
+                    self.typing.extend(bytes.iter().cloned());
+                    continue;
+                },
+                Command::Pace(pace) => {
+                    self.pace = pace;
+                    continue;
+                },
+                Command::Timeout(timeout) => {
+                    self.timeout = timeout;
+                    continue;
+                },
+                Command::Quit => return Err(self.stop(0)),
+            }
+            // Still waiting; try this step again next time.
+            self.steps.push_front(step);
+            return Err(io::Error::new(ErrorKind::WouldBlock, "Script waiting."));
+        }
+    }
+}
+
+// Collects guest output for expects, echoing it so a run leaves a readable log.
+struct ScriptOutput {
+    transcript: Arc<Transcript>,
+    echo: io::Stdout,
+}
+
+impl Write for ScriptOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        {
+            let mut transcript = self.transcript.bytes.lock().unwrap();
+            transcript.extend_from_slice(buf);
+            if transcript.len() > TRANSCRIPT_LIMIT {
+                let excess = transcript.len() - TRANSCRIPT_LIMIT;
+                transcript.drain(..excess);
+            }
+        }
+        self.transcript.changed.notify_all();
+        let _ = self.echo.write_all(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let _ = self.echo.flush();
+        Ok(())
+    }
+}
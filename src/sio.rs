@@ -0,0 +1,481 @@
+// A Zilog Z80 SIO/2 in asynchronous mode: two channels, each in front of a serial line like the
+//     console's, at four consecutive ports: channel A control, A data, B control, B data, as on
+//     the RC2014's SIO/2 board.
+//
+// Characters move at the line's pace, so clock modes, character lengths and parity are kept but
+// not acted on, and receive errors never happen. CTS is always on; DCD follows the line's carrier.
+// The core gives devices no way to interrupt the CPU, so pending interrupts only show in RR0 and,
+// with status affecting the vector, in channel B's RR2, for guests that poll.
+
+use super::ConcurrentDevice;
+use console::BackendSpec;
+use stdio_dev::StdioDevice;
+
+use z80e_core_rust::IoDevice;
+
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::Duration;
+
+// How often the lines are looked at for changes the guest should hear about.
+const POLL_MILLIS: u64 = 1;
+
+pub const CHANNEL_A: usize = 0;
+pub const CHANNEL_B: usize = 1;
+
+// WR0
+const POINTER: u8 = 0x07;
+const COMMAND: u8 = 0x38;
+const CMD_RESET_EXT: u8 = 0x10;
+const CMD_CHANNEL_RESET: u8 = 0x18;
+const CMD_ENABLE_RX_FIRST: u8 = 0x20;
+const CMD_RESET_TX_PENDING: u8 = 0x28;
+// WR1
+const EXT_INT_ENABLE: u8 = 1 << 0;
+const TX_INT_ENABLE: u8 = 1 << 1;
+// Channel B only.
+const STATUS_AFFECTS_VECTOR: u8 = 1 << 2;
+const RX_INT_MODE: u8 = 0x18;
+const RX_INT_FIRST: u8 = 0x08;
+// WR3
+const RX_ENABLE: u8 = 1 << 0;
+const AUTO_ENABLES: u8 = 1 << 5;
+// WR5
+const TX_ENABLE: u8 = 1 << 3;
+// RR0
+const RX_AVAILABLE: u8 = 1 << 0;
+// Channel A only.
+const INT_PENDING: u8 = 1 << 1;
+const TX_EMPTY: u8 = 1 << 2;
+const DCD: u8 = 1 << 3;
+const CTS: u8 = 1 << 5;
+// RR1
+const ALL_SENT: u8 = 1 << 0;
+
+// Where a channel's line comes from.
+pub enum ChannelLine {
+    // Shared with the console's status and data ports.
+    Console,
+    Backend(BackendSpec),
+}
+
+pub struct SioConfig {
+    pub base_port: u8,
+    pub channel: usize,
+    pub line: ChannelLine,
+}
+
+impl FromStr for SioConfig {
+    type Err = String;
+    // base_port,a|b,backend or base_port,a|b,console
+    fn from_str(s: &str) -> Result<SioConfig, String> {
+        let fields: Vec<&str> = s.splitn(3, ',').collect();
+        if fields.len() < 3 {
+            return Err(format!("Expected base_port,a|b,backend: {}", s));
+        }
+        let base_port = match u8::from_str(fields[0]) {
+            Ok(x) if x <= 0xfc => x,
+            Ok(x) => return Err(format!("No room for four ports at {}", x)),
+            Err(err) => return Err(format!("{} ← {}", fields[0], err)),
+        };
+        let channel = match fields[1] {
+            "a" | "A" => CHANNEL_A,
+            "b" | "B" => CHANNEL_B,
+            x => return Err(format!("Expected a or b: {}", x)),
+        };
+        let line = match fields[2] {
+            "console" => ChannelLine::Console,
+            x => ChannelLine::Backend(try!(BackendSpec::from_str(x))),
+        };
+        Ok(SioConfig {
+            base_port: base_port,
+            channel: channel,
+            line: line,
+        })
+    }
+}
+
+// Why a channel would interrupt, in order of priority. Nothing reaches the CPU; the values only go
+//     in bits 1-3 of RR2 when status affects vector, plus 4 for channel A.
+#[derive(Clone, Copy)]
+enum Cause {
+    Rx = 2,
+    Tx = 0,
+    Ext = 1,
+}
+
+struct Channel {
+    wr: [u8; 8],
+    pointer: usize,
+    line: Option<StdioDevice>,
+    // Read again when there's nothing new.
+    last: u8,
+    // Written while the transmitter was off or the line busy.
+    held: Option<u8>,
+    // A character has gone out since the transmitter was last seen empty.
+    sending: bool,
+    tx_pending: bool,
+    ext_pending: bool,
+    // Interrupt on first character is waiting for one.
+    rx_armed: bool,
+    // DCD and CTS as they were last reported.
+    modem_seen: u8,
+}
+
+impl Channel {
+    fn new(line: Option<StdioDevice>) -> Channel {
+        let mut channel = Channel {
+            wr: [0; 8],
+            pointer: 0,
+            line: line,
+            last: 0,
+            held: None,
+            sending: false,
+            tx_pending: false,
+            ext_pending: false,
+            rx_armed: false,
+            modem_seen: 0,
+        };
+        channel.modem_seen = channel.modem_status();
+        channel
+    }
+    fn reset(&mut self) {
+        let vector = self.wr[2];
+        self.wr = [0; 8];
+        self.wr[2] = vector;
+        self.pointer = 0;
+        self.held = None;
+        self.sending = false;
+        self.tx_pending = false;
+        self.ext_pending = false;
+        self.rx_armed = false;
+        self.modem_seen = self.modem_status();
+    }
+    fn modem_status(&self) -> u8 {
+        let carrier = match self.line {
+            Some(ref line) => line.carrier_detected(),
+            None => false,
+        };
+        CTS | if carrier { DCD } else { 0 }
+    }
+    fn rx_enabled(&self) -> bool {
+        self.wr[3] & RX_ENABLE != 0 && (self.wr[3] & AUTO_ENABLES == 0 || self.modem_status() & DCD != 0)
+    }
+    fn rx_available(&self) -> bool {
+        match self.line {
+            Some(ref line) => self.rx_enabled() && line.ready_read(),
+            None => false,
+        }
+    }
+    fn tx_empty(&self) -> bool {
+        self.held.is_none() && self.line_ready()
+    }
+    // Lines take characters as fast as the writer thread sends them; no line takes them at once.
+    fn line_ready(&self) -> bool {
+        match self.line {
+            Some(ref line) => line.ready_write(),
+            None => true,
+        }
+    }
+    fn rr0(&self) -> u8 {
+        let mut rr0 = self.modem_status();
+        if self.rx_available() { rr0 |= RX_AVAILABLE; }
+        if self.tx_empty() { rr0 |= TX_EMPTY; }
+        rr0
+    }
+    fn cause(&self) -> Option<Cause> {
+        let rx_mode = self.wr[1] & RX_INT_MODE;
+        if self.rx_available() && (rx_mode > RX_INT_FIRST || (rx_mode == RX_INT_FIRST && self.rx_armed)) {
+            Some(Cause::Rx)
+        } else if self.tx_pending && self.wr[1] & TX_INT_ENABLE != 0 {
+            Some(Cause::Tx)
+        } else if self.ext_pending && self.wr[1] & EXT_INT_ENABLE != 0 {
+            Some(Cause::Ext)
+        } else {
+            None
+        }
+    }
+    fn send(&mut self, byte: u8) {
+        if let Some(ref line) = self.line {
+            line.get_data_port().write_out(byte);
+        }
+        self.sending = true;
+    }
+    fn read_data(&mut self) -> u8 {
+        if self.rx_available() {
+            self.last = self.line.as_ref().unwrap().get_data_port().read_in();
+            if self.wr[1] & RX_INT_MODE == RX_INT_FIRST {
+                self.rx_armed = false;
+            }
+        }
+        self.last
+    }
+    fn write_data(&mut self, byte: u8) {
+        self.tx_pending = false;
+        if self.wr[5] & TX_ENABLE != 0 && self.tx_empty() {
+            self.send(byte);
+        } else {
+            self.held = Some(byte);
+        }
+    }
+    fn write_control(&mut self, value: u8) {
+        let pointer = self.pointer;
+        self.pointer = 0;
+        if pointer != 0 {
+            if pointer == 1 && value & RX_INT_MODE == RX_INT_FIRST {
+                self.rx_armed = true;
+            }
+            self.wr[pointer] = value;
+            return;
+        }
+        self.wr[0] = value;
+        self.pointer = (value & POINTER) as usize;
+        match value & COMMAND {
+            CMD_RESET_EXT => {
+                self.ext_pending = false;
+                self.modem_seen = self.modem_status();
+            },
+            CMD_CHANNEL_RESET => self.reset(),
+            CMD_ENABLE_RX_FIRST => self.rx_armed = true,
+            CMD_RESET_TX_PENDING => self.tx_pending = false,
+            // Null, send abort, error reset and return from interrupt have nothing to do here.
+            _ => (),
+        }
+    }
+    // Moves a held character along and notices what has changed on the line.
+    fn poll(&mut self) {
+        if let Some(byte) = self.held {
+            if self.wr[5] & TX_ENABLE != 0 && self.line_ready() {
+                self.held = None;
+                self.send(byte);
+            }
+        }
+        // Only a character going out makes a transmitter interrupt pending, not it being idle.
+        if self.sending && self.tx_empty() {
+            self.sending = false;
+            if self.wr[1] & TX_INT_ENABLE != 0 {
+                self.tx_pending = true;
+            }
+        }
+        let modem = self.modem_status();
+        if modem != self.modem_seen {
+            if self.wr[1] & EXT_INT_ENABLE != 0 {
+                self.ext_pending = true;
+            }
+            self.modem_seen = modem;
+        }
+    }
+}
+
+// The vector from WR2, with the cause in bits 1-3 if channel B's WR1 asks for it.
+fn vector(channels: &[Channel; 2], cause: Option<(usize, Cause)>) -> u8 {
+    let base = channels[CHANNEL_B].wr[2];
+    if channels[CHANNEL_B].wr[1] & STATUS_AFFECTS_VECTOR == 0 {
+        return base;
+    }
+    let code = match cause {
+        Some((channel, cause)) => (if channel == CHANNEL_A { 4 } else { 0 }) + cause as u8,
+        // Nothing pending reads as a special receive condition on channel B.
+        None => 3,
+    };
+    (base & !0x0e) | (code << 1)
+}
+
+#[derive(Clone)]
+pub struct Sio {
+    channels: Arc<Mutex<[Channel; 2]>>,
+}
+
+impl Sio {
+    pub fn new(a: Option<StdioDevice>, b: Option<StdioDevice>) -> Sio {
+        Sio {
+            channels: Arc::new(Mutex::new([Channel::new(a), Channel::new(b)])),
+        }
+    }
+    pub fn control_port(&self, channel: usize) -> SioControl {
+        SioControl { sio: self.clone(), channel: channel }
+    }
+    pub fn data_port(&self, channel: usize) -> SioData {
+        SioData { sio: self.clone(), channel: channel }
+    }
+}
+
+impl ConcurrentDevice for Sio {
+    fn run(&mut self, die: Arc<AtomicBool>, _timeout: Duration) {
+        while !die.load(Ordering::Acquire) {
+            for channel in self.channels.lock().unwrap().iter_mut() {
+                channel.poll();
+            }
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+    }
+}
+
+pub struct SioControl {
+    sio: Sio,
+    channel: usize,
+}
+
+impl IoDevice for SioControl {
+    fn read_in(&self) -> u8 {
+        let mut channels = self.sio.channels.lock().unwrap();
+        let pointer = channels[self.channel].pointer;
+        channels[self.channel].pointer = 0;
+        match pointer {
+            0 => {
+                let mut rr0 = channels[self.channel].rr0();
+                if self.channel == CHANNEL_A && channels.iter().any(|channel| channel.cause().is_some()) {
+                    rr0 |= INT_PENDING;
+                }
+                rr0
+            },
+            1 if channels[self.channel].tx_empty() => ALL_SENT,
+            2 => {
+                let pending = channels.iter().enumerate()
+                    .filter_map(|(index, channel)| channel.cause().map(|cause| (index, cause)))
+                    .next();
+                vector(&channels, pending)
+            },
+            _ => 0,
+        }
+    }
+    fn write_out(&mut self, value: u8) {
+        self.sio.channels.lock().unwrap()[self.channel].write_control(value);
+    }
+}
+
+pub struct SioData {
+    sio: Sio,
+    channel: usize,
+}
+
+impl IoDevice for SioData {
+    fn read_in(&self) -> u8 {
+        self.sio.channels.lock().unwrap()[self.channel].read_data()
+    }
+    fn write_out(&mut self, value: u8) {
+        self.sio.channels.lock().unwrap()[self.channel].write_data(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use console::{ ConsoleBackend, MemoryBackend };
+
+    use std::time::Instant;
+
+    const B_VECTOR: u8 = 0x40;
+
+    fn write(sio: &Sio, channel: usize, register: u8, value: u8) {
+        let mut control = sio.control_port(channel);
+        if register != 0 {
+            control.write_out(register);
+        }
+        control.write_out(value);
+    }
+
+    fn read(sio: &Sio, channel: usize, register: u8) -> u8 {
+        let mut control = sio.control_port(channel);
+        if register != 0 {
+            control.write_out(register);
+        }
+        control.read_in()
+    }
+
+    // One pass of what the device thread does.
+    fn poll(sio: &Sio) {
+        for channel in sio.channels.lock().unwrap().iter_mut() {
+            channel.poll();
+        }
+    }
+
+    fn status_affects_vector(sio: &Sio) {
+        write(sio, CHANNEL_B, 2, B_VECTOR);
+        write(sio, CHANNEL_B, 1, STATUS_AFFECTS_VECTOR);
+    }
+
+    #[test]
+    fn vector_is_unchanged_unless_status_affects_it() {
+        let sio = Sio::new(None, None);
+        write(&sio, CHANNEL_B, 2, B_VECTOR | 0x0e);
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 0x0e);
+        write(&sio, CHANNEL_B, 1, STATUS_AFFECTS_VECTOR);
+        // Nothing pending: a special receive condition on channel B.
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 3 << 1);
+        assert_eq!(read(&sio, CHANNEL_A, 0) & INT_PENDING, 0);
+    }
+
+    #[test]
+    fn transmitter_empty() {
+        let sio = Sio::new(None, None);
+        status_affects_vector(&sio);
+        write(&sio, CHANNEL_A, 1, TX_INT_ENABLE);
+        write(&sio, CHANNEL_A, 5, TX_ENABLE);
+        // Idle isn't empty; only a character going out is.
+        poll(&sio);
+        assert_eq!(read(&sio, CHANNEL_A, 0) & INT_PENDING, 0);
+        sio.data_port(CHANNEL_A).write_out(b'x');
+        poll(&sio);
+        assert!(read(&sio, CHANNEL_A, 0) & INT_PENDING != 0);
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 4 << 1);
+        write(&sio, CHANNEL_A, 0, CMD_RESET_TX_PENDING);
+        assert_eq!(read(&sio, CHANNEL_A, 0) & INT_PENDING, 0);
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 3 << 1);
+    }
+
+    #[test]
+    fn carrier_change_is_external_status() {
+        let line = StdioDevice::new();
+        let carrier = Arc::new(AtomicBool::new(false));
+        line.set_carrier(carrier.clone());
+        let sio = Sio::new(None, Some(line));
+        status_affects_vector(&sio);
+        write(&sio, CHANNEL_B, 1, STATUS_AFFECTS_VECTOR | EXT_INT_ENABLE);
+        assert_eq!(read(&sio, CHANNEL_B, 0) & DCD, 0);
+        carrier.store(true, Ordering::SeqCst);
+        poll(&sio);
+        assert!(read(&sio, CHANNEL_B, 0) & DCD != 0);
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 1 << 1);
+        write(&sio, CHANNEL_B, 0, CMD_RESET_EXT);
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 3 << 1);
+    }
+
+    #[test]
+    fn received_character_and_priority() {
+        let line = StdioDevice::new();
+        let (backend, _) = MemoryBackend::new(b"x");
+        let (input, _) = Box::new(backend).split();
+        let mut reader = line.get_reader(input);
+        let die = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let die = die.clone();
+            thread::spawn(move || reader.run(die, Duration::from_millis(10)))
+        };
+        let sio = Sio::new(None, Some(line));
+        status_affects_vector(&sio);
+        // Interrupt on every character.
+        write(&sio, CHANNEL_B, 1, STATUS_AFFECTS_VECTOR | 0x10);
+        write(&sio, CHANNEL_B, 3, RX_ENABLE);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read(&sio, CHANNEL_B, 0) & RX_AVAILABLE == 0 {
+            assert!(Instant::now() < deadline, "Input never arrived.");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 2 << 1);
+        // Channel A comes first.
+        write(&sio, CHANNEL_A, 1, TX_INT_ENABLE);
+        write(&sio, CHANNEL_A, 5, TX_ENABLE);
+        sio.data_port(CHANNEL_A).write_out(b'y');
+        poll(&sio);
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 4 << 1);
+        write(&sio, CHANNEL_A, 0, CMD_RESET_TX_PENDING);
+        assert_eq!(sio.data_port(CHANNEL_B).read_in(), b'x');
+        assert_eq!(read(&sio, CHANNEL_B, 2), B_VECTOR | 3 << 1);
+        die.store(true, Ordering::Release);
+        thread.join().unwrap();
+    }
+}
@@ -1,17 +1,120 @@
 use super::ConcurrentDevice;
 
+use console::ConsoleInput;
 use z80e_core_rust::{ IoDevice };
 
+use std::collections::VecDeque;
+use std::str::FromStr;
 use std::sync::{ Arc, Condvar, Mutex };
 use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
-use std::time::Duration;
-use std::io::{ self, Read, Write };
+use std::thread;
+use std::time::{ Duration, Instant };
+use std::io::{ self, ErrorKind, Write };
 
 const STATUS_READY_READ: usize = 1 << 0;
 const STATUS_READY_WRITE: usize = 1 << 1;
+// Input has ended and everything before the end has been read.
+const STATUS_EOF: usize = 1 << 2;
+// Data carrier detect, on lines that have one.
+const STATUS_CARRIER: usize = 1 << 3;
 
 const BUF_LENGTH: usize = 0x100;
 
+const SUB: u8 = 0x1a;
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+// How soon a reader holding input back looks again for room in the FIFO or an XON.
+const RETRY_MILLIS: u64 = 10;
+// Input read from the host but held back from the guest. Past this, the rest stays with the host
+//     until the guest catches up.
+const PENDING_LIMIT: usize = 0x1000;
+
+// What happens when console input ends.
+#[derive(Clone, Copy, Default)]
+pub struct EofPolicy {
+    // Give the guest a ^Z to read, as at the end of a CP/M text file.
+    pub sub: bool,
+    // Stop the machine once the guest has read everything and is waiting for more.
+    pub halt: bool,
+}
+
+impl FromStr for EofPolicy {
+    type Err = String;
+    // Any of sub and halt, separated by commas, or none.
+    fn from_str(s: &str) -> Result<EofPolicy, String> {
+        let mut policy = EofPolicy::default();
+        for word in s.split(',') {
+            match word {
+                "sub" => policy.sub = true,
+                "halt" => policy.halt = true,
+                "none" => (),
+                _ => return Err(format!("Expected sub, halt or none: {}", word)),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+// How console input is fed to the guest, so a paste arrives at something like typing speed.
+#[derive(Clone, Copy)]
+pub struct Pacing {
+    // Bytes the guest can have waiting; the rest is held back until it reads some.
+    pub fifo: usize,
+    pub char_delay: Duration,
+    // Added to char_delay after a CR or LF, for programs that do work at the end of each line.
+    pub line_delay: Duration,
+    // The guest sends ^S to stop input and ^Q to start it again.
+    pub xonxoff: bool,
+}
+
+impl Default for Pacing {
+    fn default() -> Pacing {
+        Pacing {
+            fifo: BUF_LENGTH,
+            char_delay: Duration::from_millis(0),
+            line_delay: Duration::from_millis(0),
+            xonxoff: false,
+        }
+    }
+}
+
+impl FromStr for Pacing {
+    type Err = String;
+    // Any of char=millis, line=millis, fifo=bytes and xonxoff, separated by commas.
+    fn from_str(s: &str) -> Result<Pacing, String> {
+        let mut pacing = Pacing::default();
+        for field in s.split(',') {
+            let (name, value) = match field.find('=') {
+                Some(i) => (&field[..i], &field[i + 1..]),
+                None => (field, ""),
+            };
+            let number = || usize::from_str(value).map_err(|err| format!("{} ← {}", field, err));
+            match name {
+                "char" => pacing.char_delay = Duration::from_millis(try!(number()) as u64),
+                "line" => pacing.line_delay = Duration::from_millis(try!(number()) as u64),
+                "fifo" => {
+                    pacing.fifo = try!(number());
+                    if pacing.fifo == 0 {
+                        return Err(format!("The FIFO needs room for at least one byte: {}", field));
+                    }
+                },
+                "xonxoff" if value.is_empty() => pacing.xonxoff = true,
+                _ => return Err(format!("Expected char=millis, line=millis, fifo=bytes or xonxoff: {}", field)),
+            }
+        }
+        Ok(pacing)
+    }
+}
+
+struct EofState {
+    policy: EofPolicy,
+    ended: bool,
+    // Taken when it's called, so it's called once.
+    halt: Option<Box<dyn Fn() + Send>>,
+}
+
 pub struct StdioControl {
     device: StdioDevice,
 }
@@ -26,7 +129,27 @@ impl StdioControl {
 
 impl IoDevice for StdioControl {
     fn read_in(&self) -> u8 {
-        (self.device.status.load(Ordering::SeqCst) & 0xff) as u8
+        let mut status = self.device.status.load(Ordering::SeqCst);
+        if (status & STATUS_READY_READ) == 0 {
+            let mut eof = self.device.eof.lock().unwrap();
+            // The reader may have filled the FIFO since the status was loaded, so it's the FIFO
+            //     that says whether the guest has read everything.
+            if eof.ended && self.device.read_buffer.lock().unwrap().is_empty() {
+                status |= STATUS_EOF;
+                if eof.policy.halt {
+                    if let Some(halt) = eof.halt.take() {
+                        let _ = writeln!(io::stderr(), "Console input ended; halting.");
+                        halt();
+                    }
+                }
+            }
+        }
+        if let Some(ref carrier) = *self.device.carrier.lock().unwrap() {
+            if carrier.load(Ordering::SeqCst) {
+                status |= STATUS_CARRIER;
+            }
+        }
+        (status & 0xff) as u8
     }
     fn write_out(&mut self, value: u8) {
         let _ = value;
@@ -55,16 +178,29 @@ impl IoDevice for StdioData {
                     panic!("StdioDevice buffer mutex poisoned: {}", err);
                 }
             };
-            if buffer.len() == 1 {
+            let byte = buffer.pop_front().unwrap_or(0);
+            if buffer.is_empty() {
                 self.device.status.fetch_and(!STATUS_READY_READ, Ordering::SeqCst);
             }
-            buffer.remove(0)
+            byte
         } else {
             let _ = writeln!(io::stderr(), "Attempted to read StdioDevice when it wasn't ready.");
             0
         }
     }
     fn write_out(&mut self, value: u8) {
+        {
+            let mut diverted = self.device.diverted.0.lock().unwrap();
+            if let Some(ref mut output) = *diverted {
+                output.push_back(value);
+                self.device.diverted.1.notify_all();
+                return;
+            }
+        }
+        if self.device.pacing.lock().unwrap().xonxoff && (value == XON || value == XOFF) {
+            self.device.stopped.store(value == XOFF, Ordering::SeqCst);
+            return;
+        }
         let status = self.device.status.load(Ordering::SeqCst);
         if (status & STATUS_READY_WRITE) != 0 {
             let mut buffer = match self.device.writer_state.buffer.lock() {
@@ -86,41 +222,85 @@ impl IoDevice for StdioData {
 
 pub struct StdioReader {
     device: StdioDevice,
-    stdin: io::Stdin,
+    input: Box<dyn ConsoleInput>,
 }
 
 impl StdioReader {
-    fn new(device: StdioDevice) -> StdioReader {
+    fn new(device: StdioDevice, input: Box<dyn ConsoleInput>) -> StdioReader {
         StdioReader {
             device: device,
-            stdin: io::stdin(),
+            input: input,
+        }
+    }
+    // Hands the guest as much held-back input as the pacing allows, returning how long until it
+    //     can have more, or None once nothing is held back.
+    fn feed(&self, pending: &mut VecDeque<u8>, next: &mut Instant) -> Option<Duration> {
+        if pending.is_empty() { return None; }
+        let now = Instant::now();
+        if now < *next { return Some(*next - now); }
+        let retry = Duration::from_millis(RETRY_MILLIS);
+        let pacing = *self.device.pacing.lock().unwrap();
+        if pacing.xonxoff && self.device.stopped.load(Ordering::SeqCst) { return Some(retry); }
+        let zero = Duration::from_millis(0);
+        // Without a delay per byte, everything up to the next line delay goes at once.
+        let count = if pacing.char_delay > zero {
+            1
+        } else if pacing.line_delay > zero {
+            pending.iter().position(|&x| x == CR || x == LF).map_or(pending.len(), |i| i + 1)
+        } else {
+            pending.len()
+        };
+        let bytes: Vec<u8> = pending.iter().take(count).cloned().collect();
+        let taken = self.device.offer(&bytes);
+        pending.drain(..taken);
+        if taken < count {
+            return Some(retry);
         }
+        *next = now + pacing.char_delay;
+        if bytes.last() == Some(&CR) || bytes.last() == Some(&LF) {
+            *next += pacing.line_delay;
+        }
+        if pending.is_empty() { None } else { Some(*next - now) }
     }
 }
 
 impl ConcurrentDevice for StdioReader {
     fn run(&mut self, die: Arc<AtomicBool>, timeout: Duration) {
         let mut buf: [u8; BUF_LENGTH] = [0; BUF_LENGTH];
+        // Read from the host but not yet handed to the guest; reading carries on meanwhile, up to
+        //     PENDING_LIMIT, so the escape menu still answers during a paste of reasonable size.
+        let mut pending: VecDeque<u8> = VecDeque::new();
+        let mut next = Instant::now();
+        let mut ended = false;
         loop {
-            // I wish this weren't so, but there's no read() with a timeout.
-            let _ = timeout;
             if die.load(Ordering::Acquire) { break };
-            let count = match self.stdin.read(&mut buf) {
-                Ok(x) => x,
+            let wait = self.feed(&mut pending, &mut next);
+            if ended {
+                match wait {
+                    Some(wait) => thread::sleep(wait.min(timeout)),
+                    None => {
+                        self.device.end_input();
+                        break;
+                    },
+                }
+                continue;
+            }
+            if pending.len() >= PENDING_LIMIT {
+                thread::sleep(wait.unwrap_or(timeout).min(timeout));
+                continue;
+            }
+            match self.input.read(&mut buf, wait.map_or(timeout, |wait| wait.min(timeout))) {
+                Ok(0) => {
+                    ended = true;
+                    if self.device.eof.lock().unwrap().policy.sub {
+                        pending.push_back(SUB);
+                    }
+                },
+                Ok(count) => pending.extend(buf[..count].iter().cloned()),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => (),
                 Err(err) => {
-                    let _ = writeln!(io::stderr(), "Error reading from stdin: {}", err);
-                    0
+                    let _ = writeln!(io::stderr(), "Error reading console input: {}", err);
                 },
-            };
-            if count > 0 {
-                let mut buffer = match self.device.read_buffer.lock() {
-                    Ok(x) => x,
-                    Err(err) => panic!("StdioDevice read buffer mutex poisoned: {}", err),
-                };
-                for i in 0..count {
-                    buffer.push(buf[i]);
-                }
-                self.device.status.fetch_or(STATUS_READY_READ, Ordering::SeqCst);
             }
         }
     }
@@ -128,14 +308,14 @@ impl ConcurrentDevice for StdioReader {
 
 pub struct StdioWriter {
     device: StdioDevice,
-    stdout: io::Stdout,
+    output: Box<dyn Write + Send>,
 }
 
 impl StdioWriter {
-    fn new(device: StdioDevice) -> StdioWriter {
+    fn new(device: StdioDevice, output: Box<dyn Write + Send>) -> StdioWriter {
         StdioWriter {
             device: device,
-            stdout: io::stdout(),
+            output: output,
         }
     }
 }
@@ -168,7 +348,7 @@ impl ConcurrentDevice for StdioWriter {
                         }
                     };
                 }
-                match self.stdout.write_all(&buf[..count]) {
+                match self.output.write_all(&buf[..count]) {
                     Ok(()) => {
                         if buffer.len() >= count{
                             *buffer = buffer[count..].to_vec();
@@ -177,10 +357,12 @@ impl ConcurrentDevice for StdioWriter {
                         }
                     },
                     Err(err) => {
-                        let _ = writeln!(io::stderr(), "Error writing to stdout: {}", err);
+                        let _ = writeln!(io::stderr(), "Error writing console output: {}", err);
                     },
                 }
-                self.stdout.flush().unwrap();
+                if let Err(err) = self.output.flush() {
+                    let _ = writeln!(io::stderr(), "Error flushing console output: {}", err);
+                }
                 if count == 0 { break; }
             }
             *have_data = false;
@@ -208,16 +390,64 @@ impl StdioWriterState {
 #[derive(Clone)]
 pub struct StdioDevice {
     pub status: Arc<AtomicUsize>,
-    read_buffer: Arc<Mutex<Vec<u8>>>,
+    read_buffer: Arc<Mutex<VecDeque<u8>>>,
+    pacing: Arc<Mutex<Pacing>>,
+    // The guest has sent XOFF.
+    stopped: Arc<AtomicBool>,
+    // Guest output the host is reading instead of the console, while there's a Diversion.
+    diverted: Arc<(Mutex<Option<VecDeque<u8>>>, Condvar)>,
+    carrier: Arc<Mutex<Option<Arc<AtomicBool>>>>,
     writer_state: Arc<StdioWriterState>,
+    eof: Arc<Mutex<EofState>>,
 }
 
 impl StdioDevice {
     pub fn new() -> StdioDevice {
         StdioDevice {
             status: Arc::new(AtomicUsize::new(0)),
-            read_buffer: Arc::new(Mutex::new(Vec::new())),
+            read_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            pacing: Arc::new(Mutex::new(Pacing::default())),
+            stopped: Arc::new(AtomicBool::new(false)),
+            diverted: Arc::new((Mutex::new(None), Condvar::new())),
+            carrier: Arc::new(Mutex::new(None)),
             writer_state: Arc::new(StdioWriterState::new()),
+            eof: Arc::new(Mutex::new(EofState {
+                policy: EofPolicy::default(),
+                ended: false,
+                halt: None,
+            })),
+        }
+    }
+    // halt is how to stop the machine, should the policy call for it.
+    pub fn set_eof_policy(&self, policy: EofPolicy, halt: Box<dyn Fn() + Send>) {
+        let mut eof = self.eof.lock().unwrap();
+        eof.policy = policy;
+        eof.halt = Some(halt);
+    }
+    pub fn set_pacing(&self, pacing: Pacing) {
+        *self.pacing.lock().unwrap() = pacing;
+    }
+    // Called once everything before the end, ^Z included, is in the FIFO.
+    fn end_input(&self) {
+        self.eof.lock().unwrap().ended = true;
+    }
+    // Where the status port's carrier bit comes from.
+    pub fn set_carrier(&self, carrier: Arc<AtomicBool>) {
+        *self.carrier.lock().unwrap() = Some(carrier);
+    }
+    // For devices that put their own registers in front of the line, without the status port's
+    //     end-of-input bookkeeping.
+    pub fn ready_read(&self) -> bool {
+        self.status.load(Ordering::SeqCst) & STATUS_READY_READ != 0
+    }
+    pub fn ready_write(&self) -> bool {
+        self.status.load(Ordering::SeqCst) & STATUS_READY_WRITE != 0
+    }
+    // Lines that can't tell always have carrier.
+    pub fn carrier_detected(&self) -> bool {
+        match *self.carrier.lock().unwrap() {
+            Some(ref carrier) => carrier.load(Ordering::SeqCst),
+            None => true,
         }
     }
     pub fn get_control_port(&self) -> StdioControl {
@@ -226,11 +456,270 @@ impl StdioDevice {
     pub fn get_data_port(&self) -> StdioData {
         StdioData::new(self.clone())
     }
-    pub fn get_reader(&self) -> StdioReader {
-        StdioReader::new(self.clone())
+    pub fn get_reader(&self, input: Box<dyn ConsoleInput>) -> StdioReader {
+        StdioReader::new(self.clone(), input)
     }
-    pub fn get_writer(&self) -> StdioWriter {
-        StdioWriter::new(self.clone())
+    pub fn get_writer(&self, output: Box<dyn Write + Send>) -> StdioWriter {
+        StdioWriter::new(self.clone(), output)
+    }
+    // Lets the host talk to the guest itself, as for a file transfer; the console gets the guest
+    //     back when the Diversion is dropped.
+    pub fn divert(&self) -> Diversion {
+        *self.diverted.0.lock().unwrap() = Some(VecDeque::new());
+        Diversion { device: self.clone() }
+    }
+    // Puts as much input in the FIFO as fits, returning how much that was.
+    pub fn offer(&self, bytes: &[u8]) -> usize {
+        let mut buffer = match self.read_buffer.lock() {
+            Ok(x) => x,
+            Err(err) => panic!("StdioDevice read buffer mutex poisoned: {}", err),
+        };
+        let fifo = self.pacing.lock().unwrap().fifo;
+        let count = bytes.len().min(fifo.saturating_sub(buffer.len()));
+        if count == 0 { return 0; }
+        buffer.extend(bytes[..count].iter().cloned());
+        self.status.fetch_or(STATUS_READY_READ, Ordering::SeqCst);
+        count
     }
 }
 
+
+pub struct Diversion {
+    device: StdioDevice,
+}
+
+impl Diversion {
+    // Takes the guest's next byte of output, if it sends one in time.
+    pub fn read(&self, timeout: Duration) -> Option<u8> {
+        let deadline = Instant::now() + timeout;
+        let mut diverted = self.device.diverted.0.lock().unwrap();
+        loop {
+            if let Some(byte) = diverted.as_mut().and_then(|output| output.pop_front()) {
+                return Some(byte);
+            }
+            let now = Instant::now();
+            if now >= deadline { return None; }
+            diverted = self.device.diverted.1.wait_timeout(diverted, deadline - now).unwrap().0;
+        }
+    }
+    // Gives the guest input, waiting for it to make room; fails if it stops reading for timeout.
+    pub fn write(&self, bytes: &[u8], timeout: Duration) -> io::Result<()> {
+        let mut offset = 0;
+        let mut deadline = Instant::now() + timeout;
+        while offset < bytes.len() {
+            let count = self.device.offer(&bytes[offset..]);
+            if count > 0 {
+                offset += count;
+                deadline = Instant::now() + timeout;
+            } else if Instant::now() >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "The guest isn't reading its console."));
+            } else {
+                thread::sleep(Duration::from_millis(RETRY_MILLIS));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Diversion {
+    fn drop(&mut self) {
+        *self.device.diverted.0.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::{ ConsoleBackend, MemoryBackend };
+
+    use std::thread::JoinHandle;
+
+    const TIMEOUT_MILLIS: u64 = 10;
+    const DEADLINE_SECS: u64 = 5;
+
+    struct Running {
+        die: Arc<AtomicBool>,
+        threads: Vec<JoinHandle<()>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Running {
+        fn stop(self) -> Vec<u8> {
+            self.die.store(true, Ordering::Release);
+            for thread in self.threads {
+                thread.join().unwrap();
+            }
+            let output = self.output.lock().unwrap();
+            output.clone()
+        }
+    }
+
+    fn start(device: &StdioDevice, input: &[u8]) -> Running {
+        let (backend, output) = MemoryBackend::new(input);
+        let (input, output_sink) = Box::new(backend).split();
+        let die = Arc::new(AtomicBool::new(false));
+        let timeout = Duration::from_millis(TIMEOUT_MILLIS);
+        let mut threads = Vec::new();
+        {
+            let mut reader = device.get_reader(input);
+            let die = die.clone();
+            threads.push(thread::spawn(move || reader.run(die, timeout)));
+        }
+        {
+            let mut writer = device.get_writer(output_sink);
+            let die = die.clone();
+            threads.push(thread::spawn(move || writer.run(die, timeout)));
+        }
+        Running { die: die, threads: threads, output: output }
+    }
+
+    // Reads the way a BIOS would, until the status port reports the end of input.
+    fn read_all(device: &StdioDevice) -> Vec<u8> {
+        let control = device.get_control_port();
+        let data = device.get_data_port();
+        let deadline = Instant::now() + Duration::from_secs(DEADLINE_SECS);
+        let mut bytes = Vec::new();
+        loop {
+            assert!(Instant::now() < deadline, "Input never ended; read so far: {:?}", bytes);
+            let status = control.read_in() as usize;
+            if status & STATUS_READY_READ != 0 {
+                assert!(device.read_buffer.lock().unwrap().len() <= device.pacing.lock().unwrap().fifo);
+                bytes.push(data.read_in());
+            } else if status & STATUS_EOF != 0 {
+                return bytes;
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn write_all(device: &StdioDevice, bytes: &[u8]) {
+        let control = device.get_control_port();
+        let mut data = device.get_data_port();
+        let deadline = Instant::now() + Duration::from_secs(DEADLINE_SECS);
+        for &byte in bytes {
+            while control.read_in() as usize & STATUS_READY_WRITE == 0 {
+                assert!(Instant::now() < deadline, "The console never became ready for output.");
+                thread::sleep(Duration::from_millis(1));
+            }
+            data.write_out(byte);
+        }
+    }
+
+    fn wait_for_output(running: &Running, length: usize) {
+        let deadline = Instant::now() + Duration::from_secs(DEADLINE_SECS);
+        while running.output.lock().unwrap().len() < length {
+            assert!(Instant::now() < deadline, "Output never arrived.");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn input_reaches_the_data_port_in_order() {
+        let device = StdioDevice::new();
+        let running = start(&device, b"Hello, world.\r");
+        assert_eq!(read_all(&device), b"Hello, world.\r");
+        running.stop();
+    }
+
+    #[test]
+    fn data_port_reads_zero_when_not_ready() {
+        let device = StdioDevice::new();
+        assert_eq!(device.get_control_port().read_in() as usize & STATUS_READY_READ, 0);
+        assert_eq!(device.get_data_port().read_in(), 0);
+    }
+
+    #[test]
+    fn sub_policy_ends_input_with_sub() {
+        let device = StdioDevice::new();
+        device.set_eof_policy(EofPolicy { sub: true, halt: false }, Box::new(|| ()));
+        let running = start(&device, b"x");
+        assert_eq!(read_all(&device), &[b'x', SUB]);
+        running.stop();
+    }
+
+    #[test]
+    fn halt_policy_waits_for_the_fifo_to_empty() {
+        let device = StdioDevice::new();
+        let halts = Arc::new(AtomicUsize::new(0));
+        {
+            let halts = halts.clone();
+            device.set_eof_policy(EofPolicy { sub: false, halt: true }, Box::new(move || { halts.fetch_add(1, Ordering::SeqCst); }));
+        }
+        let running = start(&device, b"ab");
+        let deadline = Instant::now() + Duration::from_secs(DEADLINE_SECS);
+        while !device.eof.lock().unwrap().ended {
+            assert!(Instant::now() < deadline, "Input never ended.");
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The input has ended, but the guest hasn't read all of it.
+        assert_eq!(device.get_control_port().read_in() as usize & STATUS_EOF, 0);
+        assert_eq!(halts.load(Ordering::SeqCst), 0);
+        assert_eq!(read_all(&device), b"ab");
+        assert_eq!(halts.load(Ordering::SeqCst), 1);
+        // Only once, however long the guest goes on asking.
+        assert!(device.get_control_port().read_in() as usize & STATUS_EOF != 0);
+        assert_eq!(halts.load(Ordering::SeqCst), 1);
+        running.stop();
+    }
+
+    #[test]
+    fn fifo_holds_back_the_rest() {
+        let device = StdioDevice::new();
+        device.set_pacing(Pacing { fifo: 2, ..Pacing::default() });
+        let input: Vec<u8> = (0..100).collect();
+        let running = start(&device, &input);
+        assert_eq!(read_all(&device), input);
+        running.stop();
+    }
+
+    // Endless input, counting what's been taken.
+    struct Flood(Arc<AtomicUsize>);
+
+    impl ConsoleInput for Flood {
+        fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+            self.0.fetch_add(buf.len(), Ordering::SeqCst);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn input_stays_with_the_host_while_the_guest_isnt_reading() {
+        let device = StdioDevice::new();
+        device.set_pacing(Pacing { fifo: 1, ..Pacing::default() });
+        let taken = Arc::new(AtomicUsize::new(0));
+        let mut reader = device.get_reader(Box::new(Flood(taken.clone())));
+        let die = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let die = die.clone();
+            thread::spawn(move || reader.run(die, Duration::from_millis(TIMEOUT_MILLIS)))
+        };
+        thread::sleep(Duration::from_millis(100));
+        die.store(true, Ordering::Release);
+        thread.join().unwrap();
+        assert!(taken.load(Ordering::SeqCst) < PENDING_LIMIT + BUF_LENGTH + 1);
+        assert!(taken.load(Ordering::SeqCst) >= PENDING_LIMIT);
+    }
+
+    #[test]
+    fn output_reaches_the_backend() {
+        let device = StdioDevice::new();
+        let running = start(&device, b"");
+        write_all(&device, b"A>DIR\r\n");
+        wait_for_output(&running, 7);
+        assert_eq!(running.stop(), b"A>DIR\r\n");
+    }
+
+    #[test]
+    fn xon_and_xoff_are_taken_out_of_the_output() {
+        let device = StdioDevice::new();
+        device.set_pacing(Pacing { xonxoff: true, ..Pacing::default() });
+        let running = start(&device, b"");
+        write_all(&device, &[XOFF]);
+        assert!(device.stopped.load(Ordering::SeqCst));
+        write_all(&device, &[XON, b'a']);
+        assert!(!device.stopped.load(Ordering::SeqCst));
+        wait_for_output(&running, 1);
+        assert_eq!(running.stop(), b"a");
+    }
+}
@@ -0,0 +1,302 @@
+// Paper tape reader and punch, for getting files in and out through RDR: and PUN:.
+//     Tapes are host files or, written |command, pipes from or to a shell command.
+
+use tty;
+
+use z80e_core_rust::IoDevice;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ self, Read, Write };
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::process::{ Child, Command, Stdio };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+// Reader status port bits.
+const READER_READY: u8 = 1 << 0;
+const READER_EOF: u8 = 1 << 1;
+// Punch status port bits.
+const PUNCH_READY: u8 = 1 << 0;
+const PUNCH_ERROR: u8 = 1 << 7;
+
+// What the reader gives once the tape has run out, as CP/M expects of text.
+const SUB: u8 = 0x1a;
+
+const BUF_LENGTH: usize = 0x100;
+
+fn command(spec: &str) -> Option<&str> {
+    if spec.starts_with('|') && spec.len() > 1 { Some(&spec[1..]) } else { None }
+}
+
+struct Source {
+    input: Box<dyn Read + Send>,
+    fd: RawFd,
+    child: Option<Child>,
+}
+
+impl Source {
+    fn open(spec: &str) -> io::Result<Source> {
+        match command(spec) {
+            Some(command) => {
+                let mut child = try!(Command::new("sh").arg("-c").arg(command).stdout(Stdio::piped()).spawn());
+                let output = child.stdout.take().unwrap();
+                Ok(Source {
+                    fd: output.as_raw_fd(),
+                    input: Box::new(output),
+                    child: Some(child),
+                })
+            },
+            None => {
+                let file = try!(File::open(spec));
+                Ok(Source {
+                    fd: file.as_raw_fd(),
+                    input: Box::new(file),
+                    child: None,
+                })
+            },
+        }
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+struct Sink {
+    output: Box<dyn Write + Send>,
+    child: Option<Child>,
+}
+
+impl Sink {
+    fn open(spec: &str) -> io::Result<Sink> {
+        match command(spec) {
+            Some(command) => {
+                let mut child = try!(Command::new("sh").arg("-c").arg(command).stdin(Stdio::piped()).spawn());
+                Ok(Sink {
+                    output: Box::new(child.stdin.take().unwrap()),
+                    child: Some(child),
+                })
+            },
+            None => Ok(Sink {
+                output: Box::new(try!(File::create(spec))),
+                child: None,
+            }),
+        }
+    }
+}
+
+impl Drop for Sink {
+    // Lets a command see the end of its input and finish up.
+    fn drop(&mut self) {
+        let _ = self.output.flush();
+        self.output = Box::new(io::sink());
+        if let Some(ref mut child) = self.child {
+            let _ = child.wait();
+        }
+    }
+}
+
+struct ReaderState {
+    spec: Option<String>,
+    source: Option<Source>,
+    buffer: VecDeque<u8>,
+}
+
+impl ReaderState {
+    // Reads ahead without ever waiting on a pipe.
+    fn fill(&mut self) {
+        if !self.buffer.is_empty() { return; }
+        let mut failed = false;
+        if let Some(ref mut source) = self.source {
+            match tty::wait_readable(source.fd, Duration::from_millis(0)) {
+                Ok(false) => return,
+                Ok(true) => {
+                    let mut buf = [0u8; BUF_LENGTH];
+                    match source.input.read(&mut buf) {
+                        Ok(0) => failed = true,
+                        Ok(count) => self.buffer.extend(buf[..count].iter().cloned()),
+                        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                        Err(err) => {
+                            let _ = writeln!(io::stderr(), "reader: {}", err);
+                            failed = true;
+                        },
+                    }
+                },
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "reader: {}", err);
+                    failed = true;
+                },
+            }
+        }
+        if failed {
+            self.source = None;
+        }
+    }
+}
+
+struct PunchState {
+    spec: Option<String>,
+    sink: Option<Sink>,
+    error: bool,
+}
+
+#[derive(Clone)]
+pub struct Tape {
+    reader: Arc<Mutex<ReaderState>>,
+    punch: Arc<Mutex<PunchState>>,
+}
+
+impl Tape {
+    pub fn new() -> Tape {
+        Tape {
+            reader: Arc::new(Mutex::new(ReaderState {
+                spec: None,
+                source: None,
+                buffer: VecDeque::new(),
+            })),
+            punch: Arc::new(Mutex::new(PunchState {
+                spec: None,
+                sink: None,
+                error: false,
+            })),
+        }
+    }
+    // Mounts a new tape in the reader, from the beginning.
+    pub fn load_reader(&self, spec: &str) -> io::Result<()> {
+        let source = try!(Source::open(spec));
+        let mut reader = self.reader.lock().unwrap();
+        reader.spec = Some(spec.to_string());
+        reader.source = Some(source);
+        reader.buffer.clear();
+        Ok(())
+    }
+    pub fn rewind_reader(&self) -> io::Result<()> {
+        let spec = self.reader.lock().unwrap().spec.clone();
+        match spec {
+            Some(spec) => self.load_reader(&spec),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No tape in the reader.")),
+        }
+    }
+    pub fn eject_reader(&self) {
+        let mut reader = self.reader.lock().unwrap();
+        reader.spec = None;
+        reader.source = None;
+        reader.buffer.clear();
+    }
+    // Starts a fresh tape in the punch, finishing off the old one.
+    pub fn load_punch(&self, spec: &str) -> io::Result<()> {
+        let mut punch = self.punch.lock().unwrap();
+        punch.sink = None;
+        punch.sink = Some(try!(Sink::open(spec)));
+        punch.spec = Some(spec.to_string());
+        punch.error = false;
+        Ok(())
+    }
+    pub fn eject_punch(&self) {
+        let mut punch = self.punch.lock().unwrap();
+        punch.spec = None;
+        punch.sink = None;
+    }
+    // What's in each unit, for the operator.
+    pub fn describe(&self) -> (Option<String>, Option<String>) {
+        (self.reader.lock().unwrap().spec.clone(), self.punch.lock().unwrap().spec.clone())
+    }
+    pub fn reader_status_port(&self) -> ReaderStatus {
+        ReaderStatus { tape: self.clone() }
+    }
+    pub fn reader_data_port(&self) -> ReaderData {
+        ReaderData { tape: self.clone() }
+    }
+    pub fn punch_status_port(&self) -> PunchStatus {
+        PunchStatus { tape: self.clone() }
+    }
+    pub fn punch_data_port(&self) -> PunchData {
+        PunchData { tape: self.clone() }
+    }
+}
+
+pub struct ReaderStatus {
+    tape: Tape,
+}
+
+impl IoDevice for ReaderStatus {
+    fn read_in(&self) -> u8 {
+        let mut reader = self.tape.reader.lock().unwrap();
+        reader.fill();
+        if !reader.buffer.is_empty() {
+            READER_READY
+        } else if reader.source.is_none() {
+            READER_EOF
+        } else {
+            0
+        }
+    }
+    fn write_out(&mut self, value: u8) {
+        let _ = value;
+    }
+}
+
+pub struct ReaderData {
+    tape: Tape,
+}
+
+impl IoDevice for ReaderData {
+    fn read_in(&self) -> u8 {
+        let mut reader = self.tape.reader.lock().unwrap();
+        reader.fill();
+        match reader.buffer.pop_front() {
+            Some(byte) => byte,
+            None if reader.source.is_none() => SUB,
+            None => {
+                let _ = writeln!(io::stderr(), "Attempted to read the tape reader when it wasn't ready.");
+                0
+            },
+        }
+    }
+    fn write_out(&mut self, value: u8) {
+        let _ = value;
+    }
+}
+
+pub struct PunchStatus {
+    tape: Tape,
+}
+
+impl IoDevice for PunchStatus {
+    fn read_in(&self) -> u8 {
+        if self.tape.punch.lock().unwrap().error { PUNCH_READY | PUNCH_ERROR } else { PUNCH_READY }
+    }
+    fn write_out(&mut self, value: u8) {
+        let _ = value;
+    }
+}
+
+pub struct PunchData {
+    tape: Tape,
+}
+
+impl IoDevice for PunchData {
+    fn read_in(&self) -> u8 {
+        0
+    }
+    // With no tape in the punch, output goes nowhere.
+    fn write_out(&mut self, value: u8) {
+        let mut punch = self.tape.punch.lock().unwrap();
+        let result = match punch.sink {
+            Some(ref mut sink) => sink.output.write_all(&[value]),
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            if !punch.error {
+                let _ = writeln!(io::stderr(), "punch: {}", err);
+            }
+            punch.error = true;
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Just enough of the telnet protocol for a character-mode terminal.
+
+// Telnet commands.
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+// Telnet options.
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+const OPT_LINEMODE: u8 = 34;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+// We echo and don't do go-ahead, which puts clients into character mode.
+const NEGOTIATION: [u8; 12] = [
+    IAC, WILL, OPT_ECHO,
+    IAC, WILL, OPT_SGA,
+    IAC, DO, OPT_SGA,
+    IAC, DONT, OPT_LINEMODE,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    Cr,
+    Iac,
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+// Strips protocol from what a client sends.
+pub struct TelnetDecoder {
+    state: State,
+}
+
+impl TelnetDecoder {
+    pub fn new() -> TelnetDecoder {
+        TelnetDecoder {
+            state: State::Data,
+        }
+    }
+    // What to send a client as soon as it connects.
+    pub fn negotiation() -> &'static [u8] {
+        &NEGOTIATION
+    }
+    // Guest input goes to input, answers to the client's requests to replies.
+    pub fn decode(&mut self, bytes: &[u8], input: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &byte in bytes {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) | (State::Cr, IAC) => State::Iac,
+                (State::Data, CR) | (State::Cr, CR) => {
+                    input.push(CR);
+                    State::Cr
+                },
+                // CR LF and CR NUL both mean a bare CR, which is what CP/M wants for Enter.
+                (State::Cr, LF) | (State::Cr, NUL) => State::Data,
+                (State::Data, byte) | (State::Cr, byte) => {
+                    input.push(byte);
+                    State::Data
+                },
+                (State::Iac, IAC) => {
+                    input.push(IAC);
+                    State::Data
+                },
+                (State::Iac, SB) => State::Subnegotiation,
+                (State::Iac, WILL) | (State::Iac, WONT) | (State::Iac, DO) | (State::Iac, DONT) => State::Option(byte),
+                (State::Iac, _) => State::Data,
+                (State::Option(verb), option) => {
+                    match (verb, option) {
+                        (DO, OPT_ECHO) | (DO, OPT_SGA) | (WILL, OPT_SGA) | (WONT, _) | (DONT, _) => (),
+                        (DO, option) => replies.extend_from_slice(&[IAC, WONT, option]),
+                        (_, option) => replies.extend_from_slice(&[IAC, DONT, option]),
+                    }
+                    State::Data
+                },
+                (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                (State::Subnegotiation, _) => State::Subnegotiation,
+                (State::SubnegotiationIac, SE) => State::Data,
+                (State::SubnegotiationIac, _) => State::Subnegotiation,
+            };
+        }
+    }
+}
+
+// Escapes what we send a client.
+pub struct TelnetEncoder {
+    pending_cr: bool,
+}
+
+impl TelnetEncoder {
+    pub fn new() -> TelnetEncoder {
+        TelnetEncoder {
+            pending_cr: false,
+        }
+    }
+    pub fn encode(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        for &byte in bytes {
+            // A CR not followed by LF must be sent as CR NUL.
+            if self.pending_cr && byte != LF {
+                out.push(NUL);
+            }
+            out.push(byte);
+            if byte == IAC {
+                out.push(IAC);
+            }
+            self.pending_cr = byte == CR;
+        }
+    }
+}
@@ -0,0 +1,621 @@
+// Makes period terminals' control codes work on a modern ANSI terminal, in both directions.
+
+use console::{ self, ConsoleInput };
+
+use std::collections::VecDeque;
+use std::io::{ self, ErrorKind, Write };
+use std::str::FromStr;
+use std::time::Duration;
+use std::cmp;
+
+const BUF_LENGTH: usize = 0x100;
+// How long a lone ESC from the host waits to see whether it starts a key sequence.
+const ESCAPE_WAIT_MILLIS: u64 = 50;
+// Longer than any key sequence we know; anything longer is passed along untouched.
+const MAX_KEY_SEQUENCE: usize = 16;
+
+const BEL: u8 = 0x07;
+const BS: u8 = 0x08;
+const HT: u8 = 0x09;
+const LF: u8 = 0x0a;
+const VT: u8 = 0x0b;
+const FF: u8 = 0x0c;
+const CR: u8 = 0x0d;
+const ETB: u8 = 0x17;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+const ESC: u8 = 0x1b;
+const RS: u8 = 0x1e;
+
+// The terminal the guest thinks it's talking to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Terminal {
+    // What the host already speaks, so there's nothing to translate.
+    Ansi,
+    Adm3a,
+    Vt52,
+    // ADM-3A addressing plus line editing and video attributes, as on the later Kaypros.
+    Kaypro,
+}
+
+impl FromStr for Terminal {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Terminal, String> {
+        match s {
+            "ansi" => Ok(Terminal::Ansi),
+            "adm3a" | "adm-3a" => Ok(Terminal::Adm3a),
+            "vt52" => Ok(Terminal::Vt52),
+            "kaypro" => Ok(Terminal::Kaypro),
+            _ => Err(format!("Expected ansi, adm3a, vt52 or kaypro: {}", s)),
+        }
+    }
+}
+
+// Keys on a host keyboard that send escape sequences.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    Function(u8),
+}
+
+impl Terminal {
+    // What this terminal's keyboard sends for key, if it has one.
+    pub fn key(&self, key: Key) -> Option<&'static [u8]> {
+        match (*self, key) {
+            (Terminal::Ansi, Key::Up) => Some(b"\x1b[A"),
+            (Terminal::Ansi, Key::Down) => Some(b"\x1b[B"),
+            (Terminal::Ansi, Key::Right) => Some(b"\x1b[C"),
+            (Terminal::Ansi, Key::Left) => Some(b"\x1b[D"),
+            (Terminal::Ansi, Key::Home) => Some(b"\x1b[H"),
+            (Terminal::Ansi, _) => None,
+            (Terminal::Vt52, Key::Up) => Some(b"\x1bA"),
+            (Terminal::Vt52, Key::Down) => Some(b"\x1bB"),
+            (Terminal::Vt52, Key::Right) => Some(b"\x1bC"),
+            (Terminal::Vt52, Key::Left) => Some(b"\x1bD"),
+            (Terminal::Vt52, Key::Function(1)) => Some(b"\x1bP"),
+            (Terminal::Vt52, Key::Function(2)) => Some(b"\x1bQ"),
+            (Terminal::Vt52, Key::Function(3)) => Some(b"\x1bR"),
+            (Terminal::Vt52, _) => None,
+            (_, Key::Up) => Some(b"\x0b"),
+            (_, Key::Down) => Some(b"\x0a"),
+            (_, Key::Left) => Some(b"\x08"),
+            (_, Key::Right) => Some(b"\x0c"),
+            (Terminal::Adm3a, Key::Home) => Some(b"\x1e"),
+            (_, _) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Attribute {
+    Reverse,
+    Dim,
+    Blink,
+    Underline,
+}
+
+// What a terminal was asked to do, independent of how it was asked.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Op {
+    Print(u8),
+    CarriageReturn,
+    // Moves down, scrolling at the bottom.
+    LineFeed,
+    // Moves up, scrolling at the top.
+    ReverseLineFeed,
+    Backspace,
+    Tab,
+    Bell,
+    // Zero-based.
+    Goto(u8, u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    ClearScreen,
+    ClearToEndOfLine,
+    ClearToEndOfScreen,
+    InsertLine,
+    DeleteLine,
+    Attribute(Attribute, bool),
+    ShowCursor(bool),
+    SaveCursor,
+    RestoreCursor,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    Address,
+    AddressRow(u8),
+    AttributeOn,
+    AttributeOff,
+    // ANSI control sequence; whether it's a private (ESC [ ?) one.
+    Csi(bool),
+}
+
+// Turns a guest's output into Ops.
+pub struct Decoder {
+    terminal: Terminal,
+    state: State,
+    // Control sequence parameters seen so far.
+    parameters: Vec<u16>,
+}
+
+impl Decoder {
+    pub fn new(terminal: Terminal) -> Decoder {
+        Decoder {
+            terminal: terminal,
+            state: State::Ground,
+            parameters: Vec::new(),
+        }
+    }
+    pub fn decode(&mut self, bytes: &[u8], ops: &mut Vec<Op>) {
+        for &byte in bytes {
+            self.state = self.step(byte, ops);
+        }
+    }
+    fn step(&mut self, byte: u8, ops: &mut Vec<Op>) -> State {
+        let adm3a = self.terminal == Terminal::Adm3a || self.terminal == Terminal::Kaypro;
+        let kaypro = self.terminal == Terminal::Kaypro;
+        match self.state {
+            State::Ground => {
+                match byte {
+                    ESC => return State::Escape,
+                    CR => ops.push(Op::CarriageReturn),
+                    LF => ops.push(Op::LineFeed),
+                    BS => ops.push(Op::Backspace),
+                    HT => ops.push(Op::Tab),
+                    BEL => ops.push(Op::Bell),
+                    VT if adm3a => ops.push(Op::Up),
+                    FF if adm3a => ops.push(Op::Right),
+                    SUB if adm3a => ops.push(Op::ClearScreen),
+                    RS if adm3a => ops.push(Op::Home),
+                    CAN if kaypro => ops.push(Op::ClearToEndOfLine),
+                    ETB if kaypro => ops.push(Op::ClearToEndOfScreen),
+                    // Padding and codes the terminal would have ignored.
+                    0x00..=0x1f | 0x7f => (),
+                    _ => ops.push(Op::Print(byte)),
+                }
+                State::Ground
+            },
+            State::Escape => {
+                match (self.terminal, byte) {
+                    (Terminal::Ansi, b'[') => {
+                        self.parameters.clear();
+                        return State::Csi(false);
+                    },
+                    (Terminal::Ansi, b'7') => ops.push(Op::SaveCursor),
+                    (Terminal::Ansi, b'8') => ops.push(Op::RestoreCursor),
+                    (Terminal::Ansi, b'D') => ops.push(Op::LineFeed),
+                    (Terminal::Ansi, b'M') => ops.push(Op::ReverseLineFeed),
+                    (Terminal::Vt52, b'Y') | (Terminal::Adm3a, b'=') | (Terminal::Kaypro, b'=') => return State::Address,
+                    (Terminal::Vt52, b'A') => ops.push(Op::Up),
+                    (Terminal::Vt52, b'B') => ops.push(Op::Down),
+                    (Terminal::Vt52, b'C') => ops.push(Op::Right),
+                    (Terminal::Vt52, b'D') => ops.push(Op::Left),
+                    (Terminal::Vt52, b'H') => ops.push(Op::Home),
+                    (Terminal::Vt52, b'I') => ops.push(Op::ReverseLineFeed),
+                    (Terminal::Vt52, b'J') => ops.push(Op::ClearToEndOfScreen),
+                    (Terminal::Vt52, b'K') => ops.push(Op::ClearToEndOfLine),
+                    (Terminal::Kaypro, b'E') => ops.push(Op::InsertLine),
+                    (Terminal::Kaypro, b'R') => ops.push(Op::DeleteLine),
+                    (Terminal::Kaypro, b'B') => return State::AttributeOn,
+                    (Terminal::Kaypro, b'C') => return State::AttributeOff,
+                    // Keypad modes, graphics and the like, which don't matter on the host.
+                    _ => (),
+                }
+                State::Ground
+            },
+            State::Address => State::AddressRow(byte.wrapping_sub(0x20)),
+            State::AddressRow(row) => {
+                ops.push(Op::Goto(row, byte.wrapping_sub(0x20)));
+                State::Ground
+            },
+            State::AttributeOn | State::AttributeOff => {
+                let on = self.state == State::AttributeOn;
+                match byte {
+                    b'0' => ops.push(Op::Attribute(Attribute::Reverse, on)),
+                    b'1' => ops.push(Op::Attribute(Attribute::Dim, on)),
+                    b'2' => ops.push(Op::Attribute(Attribute::Blink, on)),
+                    b'3' => ops.push(Op::Attribute(Attribute::Underline, on)),
+                    b'4' => ops.push(Op::ShowCursor(on)),
+                    b'6' if on => ops.push(Op::SaveCursor),
+                    b'7' if on => ops.push(Op::RestoreCursor),
+                    _ => (),
+                }
+                State::Ground
+            },
+            State::Csi(private) => {
+                match byte {
+                    b'0'..=b'9' => {
+                        if self.parameters.is_empty() {
+                            self.parameters.push(0);
+                        }
+                        let last = self.parameters.last_mut().unwrap();
+                        *last = last.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    },
+                    b';' => {
+                        if self.parameters.is_empty() {
+                            self.parameters.push(0);
+                        }
+                        self.parameters.push(0);
+                    },
+                    b'?' => return State::Csi(true),
+                    0x40..=0x7e => {
+                        self.control_sequence(private, byte, ops);
+                        return State::Ground;
+                    },
+                    // Malformed; drop it.
+                    _ => return State::Ground,
+                }
+                State::Csi(private)
+            },
+        }
+    }
+    fn parameter(&self, i: usize, default: u16) -> u16 {
+        match self.parameters.get(i) {
+            Some(&0) | None => default,
+            Some(&x) => x,
+        }
+    }
+    // The part of ECMA-48 that CP/M programs written for ANSI terminals actually use.
+    fn control_sequence(&self, private: bool, final_byte: u8, ops: &mut Vec<Op>) {
+        let count = cmp::min(self.parameter(0, 1), 0xff) as usize;
+        let repeat = |op: Op, ops: &mut Vec<Op>| ops.extend((0..count).map(|_| op));
+        match (private, final_byte) {
+            (false, b'A') => repeat(Op::Up, ops),
+            (false, b'B') => repeat(Op::Down, ops),
+            (false, b'C') => repeat(Op::Right, ops),
+            (false, b'D') => repeat(Op::Left, ops),
+            (false, b'L') => repeat(Op::InsertLine, ops),
+            (false, b'M') => repeat(Op::DeleteLine, ops),
+            (false, b'H') | (false, b'f') => {
+                let row = cmp::min(self.parameter(0, 1), 0x100) - 1;
+                let column = cmp::min(self.parameter(1, 1), 0x100) - 1;
+                ops.push(Op::Goto(row as u8, column as u8));
+            },
+            (false, b'J') => match self.parameters.first().cloned().unwrap_or(0) {
+                0 => ops.push(Op::ClearToEndOfScreen),
+                // ClearScreen homes the cursor, which this doesn't.
+                2 => ops.extend_from_slice(&[Op::SaveCursor, Op::ClearScreen, Op::RestoreCursor]),
+                _ => (),
+            },
+            (false, b'K') if self.parameters.first().cloned().unwrap_or(0) == 0 => ops.push(Op::ClearToEndOfLine),
+            (false, b'm') => {
+                let parameters = if self.parameters.is_empty() { vec![0] } else { self.parameters.clone() };
+                for parameter in parameters {
+                    match parameter {
+                        0 => {
+                            for &attribute in [Attribute::Reverse, Attribute::Dim, Attribute::Blink, Attribute::Underline].iter() {
+                                ops.push(Op::Attribute(attribute, false));
+                            }
+                        },
+                        2 => ops.push(Op::Attribute(Attribute::Dim, true)),
+                        4 => ops.push(Op::Attribute(Attribute::Underline, true)),
+                        5 => ops.push(Op::Attribute(Attribute::Blink, true)),
+                        7 => ops.push(Op::Attribute(Attribute::Reverse, true)),
+                        22 => ops.push(Op::Attribute(Attribute::Dim, false)),
+                        24 => ops.push(Op::Attribute(Attribute::Underline, false)),
+                        25 => ops.push(Op::Attribute(Attribute::Blink, false)),
+                        27 => ops.push(Op::Attribute(Attribute::Reverse, false)),
+                        _ => (),
+                    }
+                }
+            },
+            (true, b'h') if self.parameters == [25] => ops.push(Op::ShowCursor(true)),
+            (true, b'l') if self.parameters == [25] => ops.push(Op::ShowCursor(false)),
+            _ => (),
+        }
+    }
+}
+
+// The ANSI rendering of op.
+pub fn ansi(op: Op, out: &mut Vec<u8>) {
+    match op {
+        Op::Print(byte) => out.push(byte),
+        Op::CarriageReturn => out.push(CR),
+        Op::LineFeed => out.push(LF),
+        Op::ReverseLineFeed => out.extend_from_slice(b"\x1bM"),
+        Op::Backspace => out.push(BS),
+        Op::Tab => out.push(HT),
+        Op::Bell => out.push(BEL),
+        Op::Goto(row, column) => {
+            let _ = write!(out, "\x1b[{};{}H", row as u16 + 1, column as u16 + 1);
+        },
+        Op::Up => out.extend_from_slice(b"\x1b[A"),
+        Op::Down => out.extend_from_slice(b"\x1b[B"),
+        Op::Right => out.extend_from_slice(b"\x1b[C"),
+        Op::Left => out.extend_from_slice(b"\x1b[D"),
+        Op::Home => out.extend_from_slice(b"\x1b[H"),
+        Op::ClearScreen => out.extend_from_slice(b"\x1b[H\x1b[2J"),
+        Op::ClearToEndOfLine => out.extend_from_slice(b"\x1b[K"),
+        Op::ClearToEndOfScreen => out.extend_from_slice(b"\x1b[J"),
+        Op::InsertLine => out.extend_from_slice(b"\x1b[L"),
+        Op::DeleteLine => out.extend_from_slice(b"\x1b[M"),
+        Op::Attribute(attribute, on) => {
+            let code = match (attribute, on) {
+                (Attribute::Reverse, true) => 7,
+                (Attribute::Reverse, false) => 27,
+                (Attribute::Dim, true) => 2,
+                (Attribute::Dim, false) => 22,
+                (Attribute::Blink, true) => 5,
+                (Attribute::Blink, false) => 25,
+                (Attribute::Underline, true) => 4,
+                (Attribute::Underline, false) => 24,
+            };
+            let _ = write!(out, "\x1b[{}m", code);
+        },
+        Op::ShowCursor(true) => out.extend_from_slice(b"\x1b[?25h"),
+        Op::ShowCursor(false) => out.extend_from_slice(b"\x1b[?25l"),
+        Op::SaveCursor => out.extend_from_slice(b"\x1b7"),
+        Op::RestoreCursor => out.extend_from_slice(b"\x1b8"),
+    }
+}
+
+// Translates guest output for an ANSI host terminal.
+pub struct TerminalOutput {
+    inner: Box<dyn Write + Send>,
+    decoder: Decoder,
+}
+
+impl TerminalOutput {
+    pub fn new(terminal: Terminal, inner: Box<dyn Write + Send>) -> TerminalOutput {
+        TerminalOutput {
+            inner: inner,
+            decoder: Decoder::new(terminal),
+        }
+    }
+}
+
+impl Write for TerminalOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut ops = Vec::with_capacity(buf.len());
+        self.decoder.decode(buf, &mut ops);
+        let mut translated = Vec::with_capacity(buf.len());
+        for op in ops {
+            ansi(op, &mut translated);
+        }
+        try!(self.inner.write_all(&translated));
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Sequence {
+    // Length, and the key if we know it.
+    Complete(usize, Option<Key>),
+    Incomplete,
+    NotASequence,
+}
+
+// Recognizes the xterm-style sequences host keyboards send.
+fn parse_key(bytes: &[u8]) -> Sequence {
+    if bytes.len() < 2 {
+        return Sequence::Incomplete;
+    }
+    let introducer = bytes[1];
+    if introducer != b'[' && introducer != b'O' {
+        return Sequence::NotASequence;
+    }
+    let mut end = 2;
+    while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b';') {
+        end += 1;
+    }
+    if end >= MAX_KEY_SEQUENCE {
+        return Sequence::NotASequence;
+    }
+    if end == bytes.len() {
+        return Sequence::Incomplete;
+    }
+    // Modifiers come after a semicolon; we don't distinguish them.
+    let parameter = bytes[2..end].split(|byte| *byte == b';').next().and_then(|x| {
+        String::from_utf8_lossy(x).parse::<u8>().ok()
+    });
+    let key = match (introducer, parameter, bytes[end]) {
+        (_, _, b'A') => Some(Key::Up),
+        (_, _, b'B') => Some(Key::Down),
+        (_, _, b'C') => Some(Key::Right),
+        (_, _, b'D') => Some(Key::Left),
+        (_, _, b'H') => Some(Key::Home),
+        (b'O', _, final_byte @ b'P'..=b'S') => Some(Key::Function(final_byte - b'P' + 1)),
+        (b'[', Some(1), b'~') | (b'[', Some(7), b'~') => Some(Key::Home),
+        (b'[', Some(n @ 11..=15), b'~') => Some(Key::Function(n - 10)),
+        (b'[', Some(n @ 17..=21), b'~') => Some(Key::Function(n - 11)),
+        (b'[', Some(n @ 23..=24), b'~') => Some(Key::Function(n - 12)),
+        _ => None,
+    };
+    Sequence::Complete(end + 1, key)
+}
+
+// Translates host keys into what the guest's terminal would have sent.
+pub struct TerminalInput {
+    inner: Box<dyn ConsoleInput>,
+    terminal: Terminal,
+    // Raw input that might be the start of a key sequence.
+    pending: Vec<u8>,
+    ready: VecDeque<u8>,
+}
+
+impl TerminalInput {
+    pub fn new(terminal: Terminal, inner: Box<dyn ConsoleInput>) -> TerminalInput {
+        TerminalInput {
+            inner: inner,
+            terminal: terminal,
+            pending: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+    // flush gives up on sequences that haven't finished arriving.
+    fn translate(&mut self, flush: bool) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i] != ESC {
+                self.ready.push_back(self.pending[i]);
+                i += 1;
+                continue;
+            }
+            match parse_key(&self.pending[i..]) {
+                Sequence::Complete(length, Some(key)) => {
+                    if let Some(code) = self.terminal.key(key) {
+                        self.ready.extend(code.iter().cloned());
+                    }
+                    i += length;
+                },
+                Sequence::Complete(length, None) => {
+                    self.ready.extend(self.pending[i..i + length].iter().cloned());
+                    i += length;
+                },
+                Sequence::Incomplete if !flush => break,
+                Sequence::Incomplete | Sequence::NotASequence => {
+                    self.ready.push_back(ESC);
+                    i += 1;
+                },
+            }
+        }
+        self.pending.drain(..i);
+    }
+}
+
+impl ConsoleInput for TerminalInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if self.ready.is_empty() {
+            let wait = if self.pending.is_empty() {
+                timeout
+            } else {
+                cmp::min(timeout, Duration::from_millis(ESCAPE_WAIT_MILLIS))
+            };
+            let mut raw: [u8; BUF_LENGTH] = [0; BUF_LENGTH];
+            match self.inner.read(&mut raw, wait) {
+                Ok(0) if self.pending.is_empty() => return Ok(0),
+                Ok(0) => self.translate(true),
+                Ok(count) => {
+                    self.pending.extend_from_slice(&raw[..count]);
+                    self.translate(false);
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+                    self.translate(true);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        if self.ready.is_empty() {
+            return Err(console::would_block());
+        }
+        let count = cmp::min(buf.len(), self.ready.len());
+        for (slot, byte) in buf[..count].iter_mut().zip(self.ready.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::{ ConsoleBackend, MemoryBackend };
+
+    fn decode(terminal: Terminal, bytes: &[u8]) -> Vec<Op> {
+        let mut ops = Vec::new();
+        Decoder::new(terminal).decode(bytes, &mut ops);
+        ops
+    }
+
+    fn translate_output(terminal: Terminal, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in decode(terminal, bytes) {
+            ansi(op, &mut out);
+        }
+        out
+    }
+
+    // Everything the host keyboard sends, as the guest's terminal would have sent it.
+    fn translate_input(terminal: Terminal, bytes: &[u8]) -> Vec<u8> {
+        let (backend, _) = MemoryBackend::new(bytes);
+        let (inner, _) = Box::new(backend).split();
+        let mut input = TerminalInput::new(terminal, inner);
+        let mut translated = Vec::new();
+        let mut buf = [0; BUF_LENGTH];
+        loop {
+            match input.read(&mut buf, Duration::from_millis(1)) {
+                Ok(0) => return translated,
+                Ok(count) => translated.extend_from_slice(&buf[..count]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => panic!("{}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn adm3a_cursor_addressing_and_controls() {
+        assert!(decode(Terminal::Adm3a, b"\x1b=%*") == [Op::Goto(5, 10)]);
+        assert!(decode(Terminal::Adm3a, b"\x0b\x0c\x1a\x1e") == [Op::Up, Op::Right, Op::ClearScreen, Op::Home]);
+        // ^X and ^W only mean something on a Kaypro.
+        assert!(decode(Terminal::Adm3a, b"\x18\x17").is_empty());
+    }
+
+    #[test]
+    fn sequences_can_be_split_between_writes() {
+        let mut decoder = Decoder::new(Terminal::Adm3a);
+        let mut ops = Vec::new();
+        decoder.decode(b"A\x1b", &mut ops);
+        decoder.decode(b"=", &mut ops);
+        decoder.decode(b"  B", &mut ops);
+        assert!(ops == [Op::Print(b'A'), Op::Goto(0, 0), Op::Print(b'B')]);
+    }
+
+    #[test]
+    fn vt52_escapes() {
+        assert!(decode(Terminal::Vt52, b"\x1bY#$") == [Op::Goto(3, 4)]);
+        assert!(decode(Terminal::Vt52, b"\x1bA\x1bD\x1bH\x1bI\x1bJ\x1bK") ==
+                [Op::Up, Op::Left, Op::Home, Op::ReverseLineFeed, Op::ClearToEndOfScreen, Op::ClearToEndOfLine]);
+        // VT52 has no addressing by ESC =, and ^Z is just padding.
+        assert!(decode(Terminal::Vt52, b"\x1b=\x1a").is_empty());
+    }
+
+    #[test]
+    fn kaypro_line_editing_and_attributes() {
+        assert!(decode(Terminal::Kaypro, b"\x18\x17\x1bE\x1bR") ==
+                [Op::ClearToEndOfLine, Op::ClearToEndOfScreen, Op::InsertLine, Op::DeleteLine]);
+        assert!(decode(Terminal::Kaypro, b"\x1bB0x\x1bC0") ==
+                [Op::Attribute(Attribute::Reverse, true), Op::Print(b'x'), Op::Attribute(Attribute::Reverse, false)]);
+        assert!(decode(Terminal::Kaypro, b"\x1bC4\x1bB6\x1bC6") == [Op::ShowCursor(false), Op::SaveCursor]);
+    }
+
+    #[test]
+    fn ansi_control_sequences() {
+        assert!(decode(Terminal::Ansi, b"\x1b[5;10H\x1b[H") == [Op::Goto(4, 9), Op::Goto(0, 0)]);
+        assert!(decode(Terminal::Ansi, b"\x1b[2A\x1b[C") == [Op::Up, Op::Up, Op::Right]);
+        assert!(decode(Terminal::Ansi, b"\x1b[?25l\x1b[7;4m") ==
+                [Op::ShowCursor(false), Op::Attribute(Attribute::Reverse, true), Op::Attribute(Attribute::Underline, true)]);
+        assert!(decode(Terminal::Ansi, b"\x1b[2J") == [Op::SaveCursor, Op::ClearScreen, Op::RestoreCursor]);
+        // Malformed sequences are dropped, and what follows is printed.
+        assert!(decode(Terminal::Ansi, b"\x1b[1\x01x") == [Op::Print(b'x')]);
+    }
+
+    #[test]
+    fn guest_output_comes_out_as_ansi() {
+        assert_eq!(translate_output(Terminal::Adm3a, b"\x1a\x1b=!!hi"), b"\x1b[H\x1b[2J\x1b[2;2Hhi");
+        assert_eq!(translate_output(Terminal::Kaypro, b"\x1bB1\x18"), b"\x1b[2m\x1b[K");
+        assert_eq!(translate_output(Terminal::Ansi, b"\x1b[3;4H"), b"\x1b[3;4H");
+    }
+
+    #[test]
+    fn host_keys_come_in_as_the_guest_terminal_would_send_them() {
+        assert_eq!(translate_input(Terminal::Adm3a, b"\x1b[A\x1b[D\x1b[H"), b"\x0b\x08\x1e");
+        assert_eq!(translate_input(Terminal::Vt52, b"\x1bOP\x1b[B"), b"\x1bP\x1bB");
+        assert_eq!(translate_input(Terminal::Kaypro, b"x\x1b[C"), b"x\x0c");
+        // Sequences we don't know pass through, and a lone ESC is an ESC.
+        assert_eq!(translate_input(Terminal::Adm3a, b"\x1b[99~\x1b"), b"\x1b[99~\x1b");
+        // Keys the guest's terminal hasn't got are dropped.
+        assert_eq!(translate_input(Terminal::Adm3a, b"\x1bOP"), b"");
+    }
+}
@@ -0,0 +1,407 @@
+// Rewrites console traffic between host conventions and what CP/M expects: line endings, the
+//     eighth bit, UTF-8 and which key rubs out.
+
+use console::{ self, ConsoleInput };
+
+use std::collections::VecDeque;
+use std::io::{ self, ErrorKind, Write };
+use std::str::{ self, FromStr };
+use std::time::Duration;
+use std::cmp;
+
+const BUF_LENGTH: usize = 0x100;
+
+const BS: u8 = 0x08;
+const LF: u8 = 0x0a;
+const CR: u8 = 0x0d;
+const DEL: u8 = 0x7f;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Rubout {
+    Unchanged,
+    DelToBs,
+    BsToDel,
+}
+
+// What to do to one direction of console traffic.
+#[derive(Clone, Copy)]
+pub struct Rules {
+    // Input: LF and CR LF become CR. Output: CR LF becomes LF.
+    newline: bool,
+    strip: bool,
+    // Input only: set bit 7, for guests expecting mark parity.
+    mark: bool,
+    // Input only: UTF-8 becomes the nearest ASCII, or ? when there's nothing close.
+    ascii: bool,
+    rubout: Rubout,
+}
+
+impl Rules {
+    fn none() -> Rules {
+        Rules {
+            newline: false,
+            strip: false,
+            mark: false,
+            ascii: false,
+            rubout: Rubout::Unchanged,
+        }
+    }
+    fn describe(&self) -> Vec<&'static str> {
+        let mut words = Vec::new();
+        if self.ascii { words.push("ascii"); }
+        if self.strip { words.push("strip"); }
+        if self.newline { words.push("lf"); }
+        match self.rubout {
+            Rubout::Unchanged => (),
+            Rubout::DelToBs => words.push("del"),
+            Rubout::BsToDel => words.push("bs"),
+        }
+        if self.mark { words.push("mark"); }
+        words
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Translation {
+    pub input: Rules,
+    pub output: Rules,
+}
+
+impl Translation {
+    pub fn describe(&self) -> String {
+        let mut fields = Vec::new();
+        for &(direction, rules) in [("in", self.input), ("out", self.output)].iter() {
+            let words = rules.describe();
+            if !words.is_empty() {
+                fields.push(format!("{}={}", direction, words.join("+")));
+            }
+        }
+        fields.join(",")
+    }
+}
+
+impl FromStr for Translation {
+    type Err = String;
+    // in=rules and/or out=rules, separated by commas, where rules are some of lf, strip, del
+    //     (DEL becomes BS) and bs (BS becomes DEL) joined with +, and input can also have ascii
+    //     and mark.
+    fn from_str(s: &str) -> Result<Translation, String> {
+        let mut translation = Translation {
+            input: Rules::none(),
+            output: Rules::none(),
+        };
+        for field in s.split(',') {
+            let (input, words) = match field.find('=') {
+                Some(i) if &field[..i] == "in" => (true, &field[i + 1..]),
+                Some(i) if &field[..i] == "out" => (false, &field[i + 1..]),
+                _ => return Err(format!("Expected in=rules or out=rules: {}", field)),
+            };
+            let rules = if input { &mut translation.input } else { &mut translation.output };
+            for word in words.split('+') {
+                match word {
+                    "lf" => rules.newline = true,
+                    "strip" => rules.strip = true,
+                    "del" => rules.rubout = Rubout::DelToBs,
+                    "bs" => rules.rubout = Rubout::BsToDel,
+                    "mark" if input => rules.mark = true,
+                    "ascii" if input => rules.ascii = true,
+                    "mark" | "ascii" => return Err(format!("Only for input: {}", word)),
+                    _ => return Err(format!("Expected lf, strip, del, bs, mark or ascii: {}", word)),
+                }
+            }
+        }
+        Ok(translation)
+    }
+}
+
+// How long a UTF-8 sequence starting with this byte is, or 0 if it can't start one.
+fn sequence_length(lead: u8) -> usize {
+    match lead {
+        0x00..=0x7f => 1,
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => 0,
+    }
+}
+
+fn transliterate(c: char) -> &'static str {
+    match c {
+        '\u{a0}' | '\u{2002}'..='\u{200a}' => " ",
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{2032}' | '\u{b4}' => "'",
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{2033}' => "\"",
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => "-",
+        '\u{2026}' => "...",
+        '\u{2022}' => "*",
+        '\u{b7}' => ".",
+        '\u{ab}' => "<<",
+        '\u{bb}' => ">>",
+        '\u{d7}' => "x",
+        '\u{f7}' => "/",
+        '\u{a1}' => "!",
+        '\u{bf}' => "?",
+        '\u{a2}' => "c",
+        '\u{a3}' => "L",
+        '\u{a5}' => "Y",
+        '\u{20ac}' => "EUR",
+        '\u{a9}' => "(C)",
+        '\u{ae}' => "(R)",
+        '\u{2122}' => "(TM)",
+        '\u{bc}' => "1/4",
+        '\u{bd}' => "1/2",
+        '\u{be}' => "3/4",
+        '\u{c0}'..='\u{c5}' => "A",
+        '\u{c6}' => "AE",
+        '\u{c7}' => "C",
+        '\u{c8}'..='\u{cb}' => "E",
+        '\u{cc}'..='\u{cf}' => "I",
+        '\u{d0}' => "D",
+        '\u{d1}' => "N",
+        '\u{d2}'..='\u{d6}' | '\u{d8}' => "O",
+        '\u{d9}'..='\u{dc}' => "U",
+        '\u{dd}' => "Y",
+        '\u{de}' => "TH",
+        '\u{df}' => "ss",
+        '\u{e0}'..='\u{e5}' => "a",
+        '\u{e6}' => "ae",
+        '\u{e7}' => "c",
+        '\u{e8}'..='\u{eb}' => "e",
+        '\u{ec}'..='\u{ef}' => "i",
+        '\u{f0}' => "d",
+        '\u{f1}' => "n",
+        '\u{f2}'..='\u{f6}' | '\u{f8}' => "o",
+        '\u{f9}'..='\u{fc}' => "u",
+        '\u{fd}' | '\u{ff}' => "y",
+        '\u{fe}' => "th",
+        '\u{152}' => "OE",
+        '\u{153}' => "oe",
+        _ => "?",
+    }
+}
+
+// Applies one direction's rules a byte at a time, remembering what straddles a read or write.
+struct Translator {
+    rules: Rules,
+    input: bool,
+    // Input: the last byte was CR, so an LF now is the rest of the same line end.
+    //     Output: a CR held back to see whether an LF follows.
+    after_cr: bool,
+    utf8: Vec<u8>,
+}
+
+impl Translator {
+    fn new(rules: Rules, input: bool) -> Translator {
+        Translator {
+            rules: rules,
+            input: input,
+            after_cr: false,
+            utf8: Vec::with_capacity(4),
+        }
+    }
+    fn translate(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        for &byte in bytes {
+            if self.rules.ascii {
+                self.decode(byte, out);
+            } else {
+                self.rewrite(byte, out);
+            }
+        }
+    }
+    fn decode(&mut self, byte: u8, out: &mut Vec<u8>) {
+        if !self.utf8.is_empty() {
+            if byte & 0xc0 == 0x80 {
+                self.utf8.push(byte);
+                if self.utf8.len() < sequence_length(self.utf8[0]) { return; }
+                let text = match str::from_utf8(&self.utf8) {
+                    Ok(x) => x.chars().next().map_or("?", transliterate),
+                    Err(_) => "?",
+                };
+                self.utf8.clear();
+                for &byte in text.as_bytes() {
+                    self.rewrite(byte, out);
+                }
+                return;
+            }
+            // Cut short.
+            self.utf8.clear();
+            self.rewrite(b'?', out);
+        }
+        match sequence_length(byte) {
+            1 => self.rewrite(byte, out),
+            0 => self.rewrite(b'?', out),
+            _ => self.utf8.push(byte),
+        }
+    }
+    fn rewrite(&mut self, byte: u8, out: &mut Vec<u8>) {
+        let byte = if self.rules.strip { byte & 0x7f } else { byte };
+        if self.rules.newline {
+            let after_cr = self.after_cr;
+            self.after_cr = byte == CR;
+            if self.input {
+                match byte {
+                    LF if after_cr => return,
+                    LF => return self.emit(CR, out),
+                    _ => (),
+                }
+            } else {
+                if after_cr && byte != LF {
+                    self.emit(CR, out);
+                }
+                if byte == CR { return; }
+            }
+        }
+        let byte = match (self.rules.rubout, byte) {
+            (Rubout::DelToBs, DEL) => BS,
+            (Rubout::BsToDel, BS) => DEL,
+            _ => byte,
+        };
+        self.emit(byte, out);
+    }
+    fn emit(&self, byte: u8, out: &mut Vec<u8>) {
+        out.push(if self.rules.mark { byte | 0x80 } else { byte });
+    }
+}
+
+pub struct TranslateInput {
+    inner: Box<dyn ConsoleInput>,
+    translator: Translator,
+    ready: VecDeque<u8>,
+}
+
+impl TranslateInput {
+    pub fn new(rules: Rules, inner: Box<dyn ConsoleInput>) -> TranslateInput {
+        TranslateInput {
+            inner: inner,
+            translator: Translator::new(rules, true),
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+impl ConsoleInput for TranslateInput {
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if self.ready.is_empty() {
+            let mut raw: [u8; BUF_LENGTH] = [0; BUF_LENGTH];
+            match self.inner.read(&mut raw, timeout) {
+                Ok(0) => return Ok(0),
+                Ok(count) => {
+                    let mut translated = Vec::with_capacity(count);
+                    self.translator.translate(&raw[..count], &mut translated);
+                    self.ready.extend(translated);
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => (),
+                Err(err) => return Err(err),
+            }
+        }
+        // Everything read may have gone, like the LF of a CR LF.
+        if self.ready.is_empty() {
+            return Err(console::would_block());
+        }
+        let count = cmp::min(buf.len(), self.ready.len());
+        for (slot, byte) in buf[..count].iter_mut().zip(self.ready.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+// A CR going out waits for the next byte, since it might be the start of a CR LF.
+pub struct TranslateOutput {
+    inner: Box<dyn Write + Send>,
+    translator: Translator,
+}
+
+impl TranslateOutput {
+    pub fn new(rules: Rules, inner: Box<dyn Write + Send>) -> TranslateOutput {
+        TranslateOutput {
+            inner: inner,
+            translator: Translator::new(rules, false),
+        }
+    }
+}
+
+impl Write for TranslateOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut translated = Vec::with_capacity(buf.len());
+        self.translator.translate(buf, &mut translated);
+        try!(self.inner.write_all(&translated));
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each chunk goes through separately, as if read or written on its own.
+    fn translate(rules: &str, input: bool, chunks: &[&[u8]]) -> Vec<u8> {
+        let translation: Translation = rules.parse().unwrap();
+        let rules = if input { translation.input } else { translation.output };
+        let mut translator = Translator::new(rules, input);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            translator.translate(chunk, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn parses_and_describes() {
+        let translation: Translation = "in=lf+del+ascii,out=lf+strip".parse().unwrap();
+        assert_eq!(translation.describe(), "in=ascii+lf+del,out=strip+lf");
+        assert!("in=mark,out=bs".parse::<Translation>().is_ok());
+        assert!("out=mark".parse::<Translation>().is_err());
+        assert!("out=ascii".parse::<Translation>().is_err());
+        assert!("in=cr".parse::<Translation>().is_err());
+        assert!("both=lf".parse::<Translation>().is_err());
+    }
+
+    #[test]
+    fn input_line_ends_become_cr() {
+        assert_eq!(translate("in=lf", true, &[b"a\nb\r\nc\rd"]), b"a\rb\rc\rd");
+        // The LF of a CR LF arriving in a later read.
+        assert_eq!(translate("in=lf", true, &[b"a\r", b"\nb"]), b"a\rb");
+        assert_eq!(translate("in=lf", true, &[b"\n\n"]), b"\r\r");
+    }
+
+    #[test]
+    fn output_cr_lf_becomes_lf() {
+        assert_eq!(translate("out=lf", false, &[b"a\r\nb"]), b"a\nb");
+        // The CR waits for the next write to see what follows.
+        assert_eq!(translate("out=lf", false, &[b"a\r"]), b"a");
+        assert_eq!(translate("out=lf", false, &[b"a\r", b"\nb"]), b"a\nb");
+        assert_eq!(translate("out=lf", false, &[b"a\r", b"b"]), b"a\rb");
+        assert_eq!(translate("out=lf", false, &[b"\r\r\n"]), b"\r\n");
+    }
+
+    #[test]
+    fn strip_mark_and_rubout() {
+        assert_eq!(translate("in=strip", true, &[&[0xc1, 0x41]]), b"AA");
+        assert_eq!(translate("in=mark", true, &[b"A"]), &[0xc1]);
+        assert_eq!(translate("in=del", true, &[&[DEL, BS]]), &[BS, BS]);
+        assert_eq!(translate("out=bs", false, &[&[DEL, BS]]), &[DEL, DEL]);
+        // Mark applies after everything else.
+        assert_eq!(translate("in=lf+del+mark", true, &[&[LF, DEL]]), &[CR | 0x80, BS | 0x80]);
+    }
+
+    #[test]
+    fn ascii_transliterates_utf8() {
+        assert_eq!(translate("in=ascii", true, &["caf\u{e9} \u{201c}x\u{201d}".as_bytes()]), b"cafe \"x\"");
+        assert_eq!(translate("in=ascii", true, &["\u{2026}\u{20ac}".as_bytes()]), b"...EUR");
+        assert_eq!(translate("in=ascii", true, &["\u{4e2d}".as_bytes()]), b"?");
+        // A sequence split across reads.
+        let euro = "\u{20ac}".as_bytes();
+        assert_eq!(translate("in=ascii", true, &[&euro[..1], &euro[1..2], &euro[2..]]), b"EUR");
+    }
+
+    #[test]
+    fn ascii_replaces_bad_utf8() {
+        // A stray continuation byte, one that can't start anything, and a sequence cut short.
+        assert_eq!(translate("in=ascii", true, &[&[0x80, b'a', 0xff, b'b', 0xe2, 0x82, b'c']]), b"?a?b?c");
+        // An overlong encoding is as good as nothing.
+        assert_eq!(translate("in=ascii", true, &[&[0xe0, 0x80, 0x80]]), b"?");
+    }
+}
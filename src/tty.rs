@@ -0,0 +1,88 @@
+use libc;
+
+use std::io::{ self, ErrorKind };
+use std::mem;
+use std::panic;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Once;
+use std::time::Duration;
+
+// The settings to put back, recorded once before any handler can look at them.
+static mut SAVED: Option<(libc::c_int, libc::termios)> = None;
+static RAW: AtomicBool = AtomicBool::new(false);
+static HOOKS: Once = Once::new();
+
+pub fn is_tty(fd: libc::c_int) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+// Whether fd has something to read (or has hung up) within timeout.
+pub fn wait_readable(fd: libc::c_int, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+    let millis = if millis > libc::c_int::max_value() as u64 { libc::c_int::max_value() } else { millis as libc::c_int };
+    match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted { Ok(false) } else { Err(err) }
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// Takes the terminal out of line-buffered mode so the guest sees every key, ^C included.
+//     The original settings come back on exit, on panic, and on fatal signals.
+pub fn enter_raw(fd: libc::c_int) -> io::Result<()> {
+    if RAW.load(Ordering::SeqCst) { return Ok(()); }
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { SAVED = Some((fd, termios)); }
+    HOOKS.call_once(install_hooks);
+    let mut raw = termios;
+    unsafe { libc::cfmakeraw(&mut raw); }
+    // Only input needs to be raw; with output processing left on, the host's own messages and
+    //     panics still start at the left margin.
+    raw.c_oflag |= libc::OPOST | libc::ONLCR;
+    if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &raw) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    RAW.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// Safe to call from anywhere, any number of times, including signal handlers.
+pub fn restore() {
+    if RAW.swap(false, Ordering::SeqCst) {
+        unsafe {
+            if let Some((fd, ref termios)) = SAVED {
+                libc::tcsetattr(fd, libc::TCSAFLUSH, termios);
+            }
+        }
+    }
+}
+
+extern "C" fn on_fatal_signal(signal: libc::c_int) {
+    restore();
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+fn install_hooks() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        previous(info);
+    }));
+    for &signal in [libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM].iter() {
+        unsafe { libc::signal(signal, on_fatal_signal as extern "C" fn(libc::c_int) as libc::sighandler_t); }
+    }
+}
@@ -0,0 +1,332 @@
+// XMODEM and XMODEM-1K file transfers with a guest over its console, for the host menu.
+//
+// Files go across whole blocks at a time, so what the guest sends arrives padded with ^Z to a
+// multiple of 128 bytes, the same as a CP/M file.
+
+use stdio_dev::Diversion;
+
+use std::io;
+use std::time::Duration;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+// Asks the sender for CRC-16 instead of an arithmetic checksum.
+const CRC: u8 = b'C';
+
+const SHORT_BLOCK: usize = 128;
+const LONG_BLOCK: usize = 1024;
+
+// How long to wait for the other end to get going, so there's time to type the guest's command.
+const START_SECS: u64 = 60;
+// How long a receiver waits before asking again to start; the first half of the tries ask for CRC.
+const START_RETRY_SECS: u64 = 3;
+const REPLY_SECS: u64 = 10;
+const BYTE_SECS: u64 = 1;
+const MAX_RETRIES: usize = 10;
+
+// The guest's end of the wire.
+pub trait Line {
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()>;
+    // None if nothing arrives in time.
+    fn get(&mut self, timeout: Duration) -> Option<u8>;
+}
+
+impl Line for Diversion {
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write(bytes, Duration::from_secs(REPLY_SECS))
+    }
+    fn get(&mut self, timeout: Duration) -> Option<u8> {
+        self.read(timeout)
+    }
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn put(line: &mut dyn Line, bytes: &[u8]) -> Result<(), String> {
+    line.put(bytes).map_err(|err| format!("Guest stopped reading: {}", err))
+}
+
+fn cancel(line: &mut dyn Line) {
+    let _ = line.put(&[CAN, CAN]);
+}
+
+// Waits for the receiver to ask for the first block, returning whether it wants CRCs.
+fn wait_start(line: &mut dyn Line) -> Result<bool, String> {
+    for _ in 0..START_SECS {
+        match line.get(Duration::from_secs(1)) {
+            Some(CRC) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) => return Err("Cancelled by the guest.".to_string()),
+            // Whatever the guest printed before its transfer started.
+            _ => (),
+        }
+    }
+    Err("The guest never started receiving.".to_string())
+}
+
+// Sends a packet until the receiver takes it.
+fn exchange(line: &mut dyn Line, packet: &[u8]) -> Result<(), String> {
+    for _ in 0..MAX_RETRIES {
+        try!(put(line, packet));
+        for _ in 0..REPLY_SECS {
+            match line.get(Duration::from_secs(1)) {
+                Some(ACK) => return Ok(()),
+                Some(CAN) => return Err("Cancelled by the guest.".to_string()),
+                Some(NAK) => break,
+                _ => (),
+            }
+        }
+    }
+    cancel(line);
+    Err("Too many retries.".to_string())
+}
+
+// Sends data to a guest that's receiving, returning how many blocks it took. one_k uses 1024-byte
+//     blocks where the receiver can check them with a CRC.
+pub fn send(line: &mut dyn Line, data: &[u8], one_k: bool) -> Result<usize, String> {
+    let crc = try!(wait_start(line));
+    let mut offset = 0;
+    let mut number: u8 = 1;
+    let mut blocks = 0;
+    while offset < data.len() {
+        let size = if one_k && crc && data.len() - offset > SHORT_BLOCK { LONG_BLOCK } else { SHORT_BLOCK };
+        let end = (offset + size).min(data.len());
+        let mut packet = Vec::with_capacity(size + 5);
+        packet.push(if size == LONG_BLOCK { STX } else { SOH });
+        packet.push(number);
+        packet.push(!number);
+        packet.extend_from_slice(&data[offset..end]);
+        packet.resize(3 + size, SUB);
+        if crc {
+            let crc = crc16(&packet[3..]);
+            packet.push((crc >> 8) as u8);
+            packet.push(crc as u8);
+        } else {
+            let sum = checksum(&packet[3..]);
+            packet.push(sum);
+        }
+        try!(exchange(line, &packet));
+        offset = end;
+        number = number.wrapping_add(1);
+        blocks += 1;
+    }
+    try!(exchange(line, &[EOT]));
+    Ok(blocks)
+}
+
+// Throws away the rest of a bad packet so the sender hears the NAK.
+fn purge(line: &mut dyn Line) {
+    while line.get(Duration::from_secs(BYTE_SECS)).is_some() {}
+}
+
+// Reads the rest of a packet after its header byte: the block number and its data if it's intact.
+fn read_packet(line: &mut dyn Line, size: usize, crc: bool) -> Option<(u8, Vec<u8>)> {
+    let length = 2 + size + if crc { 2 } else { 1 };
+    let mut packet = Vec::with_capacity(length);
+    while packet.len() < length {
+        match line.get(Duration::from_secs(BYTE_SECS)) {
+            Some(byte) => packet.push(byte),
+            None => return None,
+        }
+    }
+    if packet[0] != !packet[1] {
+        return None;
+    }
+    let data = &packet[2..2 + size];
+    let intact = if crc {
+        crc16(data) == ((packet[2 + size] as u16) << 8 | packet[3 + size] as u16)
+    } else {
+        checksum(data) == packet[2 + size]
+    };
+    if intact { Some((packet[0], data.to_vec())) } else { None }
+}
+
+// Takes a file from a guest that's sending.
+pub fn receive(line: &mut dyn Line) -> Result<Vec<u8>, String> {
+    let start_tries = (START_SECS / START_RETRY_SECS) as usize;
+    let mut data = Vec::new();
+    let mut expected: u8 = 1;
+    let mut crc = true;
+    let mut started = false;
+    let mut tries = 0;
+    let mut errors = 0;
+    try!(put(line, &[CRC]));
+    loop {
+        let timeout = if started { REPLY_SECS } else { START_RETRY_SECS };
+        let size = match line.get(Duration::from_secs(timeout)) {
+            Some(SOH) => SHORT_BLOCK,
+            Some(STX) => LONG_BLOCK,
+            Some(EOT) => {
+                try!(put(line, &[ACK]));
+                return Ok(data);
+            },
+            Some(CAN) => return Err("Cancelled by the guest.".to_string()),
+            // Whatever the guest printed before its transfer started.
+            Some(_) => continue,
+            None if !started => {
+                tries += 1;
+                if tries >= start_tries {
+                    cancel(line);
+                    return Err("The guest never started sending.".to_string());
+                }
+                if tries == start_tries / 2 {
+                    crc = false;
+                }
+                try!(put(line, &[if crc { CRC } else { NAK }]));
+                continue;
+            },
+            None => {
+                errors += 1;
+                if errors >= MAX_RETRIES {
+                    cancel(line);
+                    return Err("Too many errors.".to_string());
+                }
+                try!(put(line, &[NAK]));
+                continue;
+            },
+        };
+        started = true;
+        match read_packet(line, size, crc) {
+            Some((number, _)) if number == expected.wrapping_sub(1) => {
+                // Our ACK got lost and the sender tried again.
+                try!(put(line, &[ACK]));
+            },
+            Some((number, _)) if number != expected => {
+                cancel(line);
+                return Err(format!("Expected block {}, got {}.", expected, number));
+            },
+            Some((_, block)) => {
+                data.extend_from_slice(&block);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                try!(put(line, &[ACK]));
+            },
+            None => {
+                errors += 1;
+                if errors >= MAX_RETRIES {
+                    cancel(line);
+                    return Err("Too many errors.".to_string());
+                }
+                purge(line);
+                try!(put(line, &[NAK]));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::{ self, Receiver, Sender };
+    use std::thread;
+
+    // One end of a wire between two threads.
+    struct Wire {
+        tx: Sender<u8>,
+        rx: Receiver<u8>,
+        // Flips the bits of the byte put at this position, counting from the first put.
+        corrupt: Option<usize>,
+        position: usize,
+    }
+
+    impl Line for Wire {
+        fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+            for &byte in bytes {
+                let byte = if self.corrupt == Some(self.position) { !byte } else { byte };
+                self.position += 1;
+                let _ = self.tx.send(byte);
+            }
+            Ok(())
+        }
+        fn get(&mut self, timeout: Duration) -> Option<u8> {
+            self.rx.recv_timeout(timeout).ok()
+        }
+    }
+
+    fn wires() -> (Wire, Wire) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+        let a = Wire { tx: a_tx, rx: b_rx, corrupt: None, position: 0 };
+        let b = Wire { tx: b_tx, rx: a_rx, corrupt: None, position: 0 };
+        (a, b)
+    }
+
+    fn transfer(data: &[u8], one_k: bool, corrupt: Option<usize>) -> (usize, Vec<u8>) {
+        let (mut sender, mut receiver) = wires();
+        sender.corrupt = corrupt;
+        let data = data.to_vec();
+        let sending = thread::spawn(move || send(&mut sender, &data, one_k));
+        let received = receive(&mut receiver).unwrap();
+        (sending.join().unwrap().unwrap(), received)
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    fn padded(data: &[u8], size: usize) -> Vec<u8> {
+        let mut padded = data.to_vec();
+        padded.resize(data.len() + (size - data.len() % size) % size, SUB);
+        padded
+    }
+
+    #[test]
+    fn short_blocks() {
+        let data = data(1000);
+        let (blocks, received) = transfer(&data, false, None);
+        assert_eq!(blocks, 8);
+        assert_eq!(received, padded(&data, SHORT_BLOCK));
+    }
+
+    #[test]
+    fn long_blocks_end_with_a_short_one() {
+        let data = data(2100);
+        let (blocks, received) = transfer(&data, true, None);
+        // 1024 and 1024, then 52 in a short block.
+        assert_eq!(blocks, 3);
+        assert_eq!(received, padded(&data, SHORT_BLOCK));
+    }
+
+    #[test]
+    fn block_numbers_wrap() {
+        let data = data(300 * SHORT_BLOCK);
+        let (blocks, received) = transfer(&data, false, None);
+        assert_eq!(blocks, 300);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn corrupted_block_is_sent_again() {
+        let data = data(3 * SHORT_BLOCK);
+        // Part of the second block's data.
+        let (blocks, received) = transfer(&data, false, Some(SHORT_BLOCK + 5 + 3 + 20));
+        assert_eq!(blocks, 3);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn checksums() {
+        // The XMODEM CRC-16 check value.
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(checksum(&[0xff, 0x02, 0x10]), 0x11);
+    }
+}